@@ -0,0 +1,149 @@
+use crate::error::PDFConError;
+use log::{debug, warn};
+use lopdf::{Dictionary, Document, Object, decode_text_string};
+use std::io::Write;
+use std::path::Path;
+
+// Name trees can nest. Stop following kids past this depth in case one refers to itself
+const MAX_TREE_DEPTH: usize = 16;
+
+// File attachments live in the EmbeddedFiles name tree of the catalog, not on any page
+pub struct Attachment {
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
+fn resolved_dict<'a>(
+    doc: &'a Document,
+    dict: &'a Dictionary,
+    key: &[u8],
+) -> Option<&'a Dictionary> {
+    let (_, object) = doc.dereference(dict.get(key).ok()?).ok()?;
+    object.as_dict().ok()
+}
+
+// Only the last component of the stored name is used so an attachment can't write outside the
+// output directory. A drive on windows like C:evil.exe would leave it too so names with a colon
+// aren't used at all. UF is the unicode name and preferred over F
+fn file_name(doc: &Document, spec: &Dictionary) -> Option<String> {
+    let name = [b"UF".as_slice(), b"F"].iter().find_map(|key| {
+        let (_, value) = doc.dereference(spec.get(key).ok()?).ok()?;
+        decode_text_string(value).ok()
+    })?;
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    if name.contains(':') {
+        return None;
+    }
+    let file_name = Path::new(name).file_name()?.to_str()?;
+    (file_name == name).then(|| name.to_string())
+}
+
+fn read_spec(doc: &Document, spec: &Object) -> Result<Option<Attachment>, PDFConError> {
+    let spec = doc.dereference(spec)?.1.as_dict()?;
+    let Some(files) = resolved_dict(doc, spec, b"EF") else {
+        // A reference to a file outside the pdf. Nothing to extract
+        debug!(
+            "Skipping attachment {} since it isn't embedded",
+            file_name(doc, spec).unwrap_or_default()
+        );
+        return Ok(None);
+    };
+    let Ok(file) = files.get(b"UF").or_else(|_| files.get(b"F")) else {
+        return Ok(None);
+    };
+    // Named after the embedded stream when the stored name can't be used
+    let name = match (file_name(doc, spec), file.as_reference()) {
+        (Some(name), _) => name,
+        (None, Ok(id)) => {
+            let name = format!("attachment_{}_{}", id.0, id.1);
+            warn!(
+                "Attachment {} {} R has no usable file name. Writing it as {}",
+                id.0, id.1, name
+            );
+            name
+        }
+        (None, Err(_)) => {
+            warn!("Skipping an attachment without a usable file name");
+            return Ok(None);
+        }
+    };
+    let stream = doc.dereference(file)?.1.as_stream()?;
+    let content = if stream.dict.has(b"Filter") {
+        stream.decompressed_content()?
+    } else {
+        stream.content.clone()
+    };
+    Ok(Some(Attachment { name, content }))
+}
+
+// Names holds key and file spec pairs. Kids holds more nodes of the same shape
+fn walk_tree(
+    doc: &Document,
+    node: &Dictionary,
+    depth: usize,
+    attachments: &mut Vec<Attachment>,
+) -> Result<(), PDFConError> {
+    if depth > MAX_TREE_DEPTH {
+        warn!(
+            "Not following the attachment tree past {} levels",
+            MAX_TREE_DEPTH
+        );
+        return Ok(());
+    }
+    if let Ok(names) = node.get(b"Names") {
+        for pair in doc.dereference(names)?.1.as_array()?.chunks(2) {
+            if let [_, spec] = pair
+                && let Some(attachment) = read_spec(doc, spec)?
+            {
+                attachments.push(attachment);
+            }
+        }
+    }
+    if let Ok(kids) = node.get(b"Kids") {
+        for kid in doc.dereference(kids)?.1.as_array()? {
+            walk_tree(
+                doc,
+                doc.dereference(kid)?.1.as_dict()?,
+                depth + 1,
+                attachments,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Every embedded file in tree order. Empty when the document has none
+pub fn embedded_files(doc: &Document) -> Result<Vec<Attachment>, PDFConError> {
+    let mut attachments = Vec::new();
+    let root = doc
+        .catalog()
+        .ok()
+        .and_then(|c| resolved_dict(doc, c, b"Names"))
+        .and_then(|n| resolved_dict(doc, n, b"EmbeddedFiles"));
+    if let Some(root) = root {
+        walk_tree(doc, root, 0, &mut attachments)?;
+    }
+    Ok(attachments)
+}
+
+// Never replaces a file that's already there, whether it's an extracted image or an earlier
+// attachment with the same name
+pub fn write(directory: &Path, attachment: &Attachment) -> Result<(), PDFConError> {
+    let path = directory.join(&attachment.name);
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path);
+    match file {
+        Ok(mut file) => Ok(file.write_all(&attachment.content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            warn!(
+                "Not writing attachment {} since {} already exists",
+                attachment.name,
+                path.to_string_lossy()
+            );
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,162 @@
+use crate::Run;
+use crate::constants::{bc_yellow, bold, c_gray};
+use crate::error::PDFConError;
+use crate::pack::build_document;
+use crate::pdf_image::{PDFConColorSpace, compress_zlib, optimize::ImageData};
+use crate::unpack::{Unpack, filter_func};
+use console::Term;
+use image::{ImageEncoder, codecs::jpeg};
+use log::error;
+use lopdf::{Document, Reader};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BenchFormat {
+    PNG,
+    JPG,
+    MIXED,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bench {
+    pub pages: usize,
+    pub width: u32,
+    pub height: u32,
+    pub format: BenchFormat,
+    pub threads: Vec<usize>,
+}
+
+impl Bench {
+    // A gradient with some noise mixed in so neither encoder gets an unrealistically easy time
+    fn synthetic_pixels(&self) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity((self.width * self.height * 3) as usize);
+        let mut seed: u32 = 0x9E37_79B9;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let noise = (seed & 0x0F) as u8;
+                pixels.push(((x * 255) / self.width.max(1)) as u8 ^ noise);
+                pixels.push(((y * 255) / self.height.max(1)) as u8 ^ noise);
+                pixels.push(((x + y) & 0xFF) as u8);
+            }
+        }
+        pixels
+    }
+
+    fn synthetic_document(&self) -> Result<Vec<u8>, PDFConError> {
+        let pixels = self.synthetic_pixels();
+
+        let png = compress_zlib(pixels.clone(), flate2::Compression::default())?;
+        let mut jpg = Vec::new();
+        jpeg::JpegEncoder::new_with_quality(&mut jpg, 90).write_image(
+            &pixels,
+            self.width,
+            self.height,
+            image::ExtendedColorType::Rgb8,
+        )?;
+
+        let images = (0..self.pages)
+            .map(|page| {
+                let as_jpeg = match self.format {
+                    BenchFormat::PNG => false,
+                    BenchFormat::JPG => true,
+                    BenchFormat::MIXED => page % 2 == 1,
+                };
+                if as_jpeg {
+                    ImageData::JPEG(
+                        jpg.clone(),
+                        self.width,
+                        self.height,
+                        PDFConColorSpace::RGB8,
+                    )
+                } else {
+                    ImageData::PNG(
+                        png.clone(),
+                        self.width,
+                        self.height,
+                        PDFConColorSpace::RGB8,
+                    )
+                }
+            })
+            .collect();
+
+        let mut doc = build_document(images)?;
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn report(&self, line: String) {
+        if Term::stdout().write_line(line.as_str()).is_err() {
+            error!("Failed to print to console");
+        }
+    }
+}
+
+impl Run for Bench {
+    fn run(&self) -> Result<(), PDFConError> {
+        let bytes = self.synthetic_document()?;
+
+        // Parse the same way unpack does so the filter is part of the measurement
+        let start = Instant::now();
+        let document = Reader {
+            buffer: &bytes,
+            document: Document::new(),
+        }
+        .read(Some(filter_func))?;
+        let parse_time = start.elapsed().as_secs_f64();
+
+        self.report(format!(
+            "{} {} pages of {}x{} {:?}, {:.1} MB pdf parsed in {:.3}s",
+            bc_yellow().apply_to("Bench:"),
+            self.pages,
+            self.width,
+            self.height,
+            self.format,
+            bytes.len() as f64 / 1_000_000.0,
+            parse_time
+        ));
+
+        let out_directory =
+            std::env::temp_dir().join(format!("pdfcon-bench-{}", std::process::id()));
+        let decoded_mb =
+            (self.width as f64 * self.height as f64 * 3.0 * self.pages as f64) / 1_000_000.0;
+
+        let mut results = Vec::new();
+        for &threads in &self.threads {
+            std::fs::create_dir_all(&out_directory)?;
+
+            let unpack = Unpack {
+                threads,
+                out_directory: out_directory.clone(),
+                ..Default::default()
+            };
+
+            // Each run gets a pool of its own the same way a real unpack does
+            let start = Instant::now();
+            let result = unpack.install(|| unpack.extract_images(std::slice::from_ref(&document)));
+            let elapsed = start.elapsed().as_secs_f64();
+
+            std::fs::remove_dir_all(&out_directory)?;
+            result?;
+
+            results.push((threads, elapsed));
+        }
+
+        for (threads, elapsed) in results {
+            self.report(format!(
+                " {} {:>3} {} {:>8.3}s {:>10.1} images/s {:>10.1} MB/s",
+                bold().apply_to("threads"),
+                threads,
+                c_gray().apply_to("|"),
+                elapsed,
+                self.pages as f64 / elapsed,
+                decoded_mb / elapsed
+            ));
+        }
+
+        Ok(())
+    }
+}
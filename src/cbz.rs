@@ -0,0 +1,125 @@
+use crate::error::PDFConError;
+use flate2::Crc;
+use std::io::Write;
+
+// 1980-01-01 in DOS format, the earliest a zip can say. Every entry gets it so the same pdf
+// always makes the same archive
+const DOS_DATE: u16 = (1 << 5) | 1;
+// Names are utf-8
+const UTF8_NAMES: u16 = 1 << 11;
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+// Just enough zip to hold a comic book archive. Entries are stored rather than deflated since
+// pngs and jpegs are compressed already. Sizes and offsets are 32 bit so an archive tops out at
+// 4 GB and 65535 entries
+pub struct Cbz<W: Write> {
+    out: W,
+    written: u64,
+    entries: Vec<Entry>,
+}
+
+fn too_big() -> PDFConError {
+    PDFConError::OutputFormatError(String::from("the images don't fit in a 4 GB cbz"))
+}
+
+impl<W: Write> Cbz<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            written: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn position(&self) -> Result<u32, PDFConError> {
+        u32::try_from(self.written).map_err(|_| too_big())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), PDFConError> {
+        self.out.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    // Readers show entries in name order so names are what keeps the pages in order
+    pub fn add_file(&mut self, name: &str, data: &[u8]) -> Result<(), PDFConError> {
+        let offset = self.position()?;
+        let size = u32::try_from(data.len()).map_err(|_| too_big())?;
+        let mut crc = Crc::new();
+        crc.update(data);
+        let crc = crc.sum();
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(0x04034b50u32.to_le_bytes());
+        // Version 1.0 is enough to extract stored entries
+        header.extend(10u16.to_le_bytes());
+        header.extend(UTF8_NAMES.to_le_bytes());
+        // Stored, no compression
+        header.extend(0u16.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(DOS_DATE.to_le_bytes());
+        header.extend(crc.to_le_bytes());
+        header.extend(size.to_le_bytes());
+        header.extend(size.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.write(&header)?;
+        self.write(data)?;
+
+        self.entries.push(Entry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+        Ok(())
+    }
+
+    // The central directory goes at the end and is what readers actually go by
+    pub fn finish(mut self) -> Result<W, PDFConError> {
+        let count = u16::try_from(self.entries.len()).map_err(|_| {
+            PDFConError::OutputFormatError(String::from("a cbz holds at most 65535 images"))
+        })?;
+        let directory_at = self.position()?;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend(0x02014b50u32.to_le_bytes());
+            directory.extend(20u16.to_le_bytes());
+            directory.extend(10u16.to_le_bytes());
+            directory.extend(UTF8_NAMES.to_le_bytes());
+            directory.extend(0u16.to_le_bytes());
+            directory.extend(0u16.to_le_bytes());
+            directory.extend(DOS_DATE.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            // Extra field, comment, disk number and internal and external attributes
+            directory.extend([0; 12]);
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let directory_len = u32::try_from(directory.len()).map_err(|_| too_big())?;
+        self.write(&directory)?;
+
+        let mut end = Vec::with_capacity(22);
+        end.extend(0x06054b50u32.to_le_bytes());
+        // Everything is on the one disk
+        end.extend([0; 4]);
+        end.extend(count.to_le_bytes());
+        end.extend(count.to_le_bytes());
+        end.extend(directory_len.to_le_bytes());
+        end.extend(directory_at.to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+        self.write(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
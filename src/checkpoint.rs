@@ -0,0 +1,109 @@
+use crate::error::PDFConError;
+use log::warn;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Kept in the output directory so a run pointed at the same directory picks it up again
+pub const FILE_NAME: &str = ".pdfcon-checkpoint";
+
+// Pages that finished, written out every interval images so an interrupted run can skip them.
+// Pages are numbered across every input like the output names before --reverse
+pub struct Checkpoint {
+    path: PathBuf,
+    interval: usize,
+    done: Mutex<BTreeSet<u32>>,
+    since_write: AtomicUsize,
+    // Held while the file is written. Workers that find it taken leave the write to whoever has it
+    writing: Mutex<()>,
+}
+
+impl Checkpoint {
+    // Starts from the pages an earlier run recorded in directory, if it left a checkpoint
+    pub fn load(directory: &Path, interval: usize) -> Result<Self, PDFConError> {
+        let path = directory.join(FILE_NAME);
+        let done = match std::fs::read_to_string(&path) {
+            Ok(text) => text.lines().filter_map(|l| l.trim().parse().ok()).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            interval: interval.max(1),
+            done: Mutex::new(done),
+            since_write: AtomicUsize::new(0),
+            writing: Mutex::new(()),
+        })
+    }
+
+    pub fn exists(directory: &Path) -> bool {
+        directory.join(FILE_NAME).is_file()
+    }
+
+    pub fn resumed_pages(&self) -> usize {
+        self.done.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_done(&self, page_num: u32) -> bool {
+        self.done
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&page_num)
+    }
+
+    // Record a page once everything from it is on disk. Only the worker that crosses the interval
+    // writes the file and nobody waits on it
+    pub fn finish_page(&self, page_num: u32, images: usize) {
+        self.done
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(page_num);
+        let since_write = self.since_write.fetch_add(images, Ordering::Relaxed) + images;
+        if since_write < self.interval {
+            return;
+        }
+        if let Ok(_writing) = self.writing.try_lock() {
+            // Only reset the count if it's still over the interval so what other workers added
+            // since the check isn't lost. It's under when someone else saved in between
+            let reset =
+                self.since_write
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                        (count >= self.interval).then_some(0)
+                    });
+            if reset.is_ok() {
+                self.save();
+            }
+        }
+    }
+
+    // A failed write only costs the ability to resume so it's a warning. The file is replaced
+    // through a rename so an interruption never leaves half a checkpoint behind
+    pub fn save(&self) {
+        let pages: String = self
+            .done
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|p| format!("{}\n", p))
+            .collect();
+        let temporary = self.path.with_extension("tmp");
+        let result =
+            std::fs::write(&temporary, pages).and_then(|_| std::fs::rename(&temporary, &self.path));
+        if let Err(e) = result {
+            warn!(
+                "Failed to write the checkpoint {}: {}",
+                self.path.to_string_lossy(),
+                e
+            );
+        }
+    }
+
+    // Nothing is left to resume once every page is done
+    pub fn remove(&self) -> Result<(), PDFConError> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
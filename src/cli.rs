@@ -1,6 +1,54 @@
 use clap::{ArgAction, Command, arg, command, value_parser};
 use std::path::PathBuf;
 
+fn parse_hex_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(String::from("expected six hex digits like ffffff"));
+    }
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("{} isn't a hex color", value))
+    };
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    let digits = upper.trim_end_matches(['B', 'K', 'M', 'G']);
+    let scale: usize = match &upper[digits.len()..] {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("{} isn't a size like 512M or 2G", value)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("{} isn't a size like 512M or 2G", value))
+}
+
+fn parse_aspect(value: &str) -> Result<(f64, f64), String> {
+    let invalid = || format!("{} isn't a width/height range like 0.6-0.8", value);
+    let (min, max) = value.split_once('-').ok_or_else(invalid)?;
+    let min = min.trim().parse::<f64>().map_err(|_| invalid())?;
+    let max = max.trim().parse::<f64>().map_err(|_| invalid())?;
+    if !(min > 0.0 && min <= max && max.is_finite()) {
+        return Err(invalid());
+    }
+    Ok((min, max))
+}
+
+// Goes in front of file names so it can't reach into another directory
+fn parse_prefix(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains(['/', '\\']) {
+        return Err(format!("{} can't be used as part of a file name", value));
+    }
+    Ok(value.to_string())
+}
+
 pub fn build_command() -> clap::Command {
     let command: clap::Command = command!()
         .propagate_version(true)
@@ -29,6 +77,76 @@ pub fn build_command() -> clap::Command {
                         .value_parser(value_parser!(PathBuf))
                         .required(false),
                 )
+                .arg(
+                    arg!([COMPRESSION])
+                        .long("compression")
+                        .visible_alias("pack-compression")
+                        .help("Deflate level from 0 to 9 for images that aren't jpegs. Defaults to 9")
+                        .value_parser(value_parser!(u32).range(0..=9))
+                        .required(false),
+                )
+                .arg(
+                    arg!([PDFA])
+                        .long("pdfa")
+                        .help("Write a PDF/A-2b document for archiving")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([GLOB])
+                        .short('g')
+                        .long("glob")
+                        .help("Only include files whose name matches the pattern. Can be given more than once")
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!([FILE_LIST])
+                        .long("file-list")
+                        .help("File with one image path per line. Pages are added in its order without sorting")
+                        .value_parser(value_parser!(PathBuf))
+                        .conflicts_with_all(["GLOB", "IN_DIRECTORY"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([RECURSIVE])
+                        .long("recursive")
+                        .help("Include images in every directory under the input directory. Pages go directory by directory, with numbers in names compared by value")
+                        .conflicts_with("FILE_LIST")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([ROTATIONS])
+                        .short('r')
+                        .long("rotations")
+                        .help("File with an image path under the input directory and an angle of 0, 90, 180 or 270 on each line. Pages are rotated to match")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([INTENT])
+                        .long("intent")
+                        .help("Rendering intent to write on every image. Viewers pick their own when it's left out")
+                        .value_parser([
+                            "Perceptual",
+                            "Saturation",
+                            "RelativeColorimetric",
+                            "AbsoluteColorimetric",
+                        ])
+                        .required(false),
+                )
+                .arg(
+                    arg!([NICE])
+                        .long("nice")
+                        .help("Run at this nice level from 0 to 19 so other work stays responsive")
+                        .value_parser(value_parser!(i32).range(0..=19))
+                        .required(false),
+                )
+                .arg(
+                    arg!([VALIDATE_ONLY])
+                        .long("validate-only")
+                        .help("Check that every input image decodes and report the ones that don't without writing the pdf")
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     arg!([IN_DIRECTORY])
                         .value_parser(value_parser!(PathBuf))
@@ -45,16 +163,25 @@ pub fn build_command() -> clap::Command {
                         .value_parser(value_parser!(usize))
                         .required(false),
                 )
+                .arg(
+                    arg!([IO_THREADS])
+                        .long("io-threads")
+                        .help("Most files to write at once. Lower it on slow or network storage. Defaults to --threads")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
                 .arg(
                     arg!([OUT_DIRECTORY])
                         .short('d')
                         .long("directory")
+                        .help("Directory to write images to. A .png or .jpg path writes a single image file, - writes it to stdout and a .tiff path writes every image as a page of one tiff and a .cbz path writes them into a comic book archive")
                         .value_parser(value_parser!(PathBuf))
                         .required(false),
                 )
                 .arg(
                     arg!([IN_FILE])
                         .value_parser(value_parser!(PathBuf))
+                        .num_args(1..)
                         .required(true),
                 )
                 .arg(
@@ -62,8 +189,508 @@ pub fn build_command() -> clap::Command {
                         .short('o')
                         .long("optimize")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([CONTIGUOUS])
+                        .long("contiguous")
+                        .help("Number images 1..n in page order, skipping pages without images. File names no longer match pdf page numbers")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([PAD])
+                        .long("pad")
+                        .help("Always zero pad file names to this many digits")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    arg!([PREFIX])
+                        .long("prefix")
+                        .visible_alias("output-prefix")
+                        .help("Start file names with NAME_ so several pdfs can share a directory, like NAME_0001.png")
+                        .value_name("NAME")
+                        .value_parser(parse_prefix)
+                        .required(false),
+                )
+                .arg(
+                    arg!([PAGE_OFFSET])
+                        .long("page-offset")
+                        .help("Add this to every file name's number, so 100 starts a continuation at 101")
+                        .value_parser(value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    arg!([SALVAGE])
+                        .long("salvage")
+                        .help("Write out damaged images with a warning instead of failing the page")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([STRIP_THUMBNAILS])
+                        .long("strip-thumbnails")
+                        .help("Drop the thumbnails jpegs carry in their JFIF and Exif headers. The image itself is copied over untouched")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([PNG_COMPRESSION])
+                        .long("png-compression")
+                        .help("Deflate level from 0 to 9 used when encoding pngs")
+                        .value_parser(value_parser!(u32).range(0..=9))
+                        .required(false),
+                )
+                .arg(
+                    arg!([QUANTIZE])
+                        .long("quantize")
+                        .help("Reduce pngs to a palette of at most N colors and write them indexed. Suits scans with only a few colors")
+                        .value_parser(value_parser!(u32).range(2..=256))
+                        .required(false),
+                )
+                .arg(
+                    arg!([MAX_IMAGES])
+                        .long("max-images")
+                        .help("Stop once this many images have been written")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    arg!([SKIP_SIMILAR])
+                        .long("skip-similar")
+                        .help("Drop images whose perceptual hash is at most this many bits from the last image kept, like runs of blank scanned pages. 0 to 64")
+                        .value_name("BITS")
+                        .value_parser(value_parser!(u32).range(0..=64))
+                        .required(false),
+                )
+                .arg(
+                    arg!([TOP])
+                        .long("top")
+                        .help("Only extract the N images with the most pixels. Sizes are read before anything is decoded")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with("OBJECT")
+                        .required(false),
+                )
+                .arg(
+                    arg!([ASPECT])
+                        .long("aspect")
+                        .help("Only write images whose width/height ratio is in this range, like 0.6-0.8")
+                        .value_parser(parse_aspect)
+                        .required(false),
+                )
+                .arg(
+                    arg!([MIN_MEGAPIXELS])
+                        .long("min-megapixels")
+                        .help("Only write images with at least this many megapixels")
+                        .value_parser(value_parser!(f64))
+                        .required(false),
+                )
+                .arg(
+                    arg!([EMBED_PROVENANCE])
+                        .long("embed-provenance")
+                        .help("Record the source pdf, page and object in each image's metadata")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([VERIFY_OUTPUT])
+                        .long("verify-output")
+                        .help("Decode every written image again and fail if any can't be read")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([SVG])
+                        .long("svg")
+                        .help("Also write the vector drawings of each page to an svg file")
+                        .conflicts_with("CONTIGUOUS")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([APPLY_PAGE_ROTATION])
+                        .long("apply-page-rotation")
+                        .help("Turn images by the rotation of their page so they read the way the page is shown. Jpegs get an Exif orientation instead of being re-encoded")
+                        .conflicts_with("SVG")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([AUTO_ORIENT])
+                        .long("auto-deskew-rotate")
+                        .visible_alias("normalize-orientation")
+                        .help("Turn scanned pages of text upright by where their ink is. Pages it isn't sure about are left alone")
+                        .conflicts_with("SVG")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([COMPOSITE])
+                        .long("composite")
+                        .help("Put the images of each page back together where the page draws them and write them as one png, for scans stored in strips or tiles")
+                        .conflicts_with_all(["SVG", "OBJECT", "DUMP_DICT"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([TRIM])
+                        .long("trim")
+                        .help("Crop borders of one color off scanned images. Jpegs are left alone")
+                        .conflicts_with("SVG")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([TRIM_FUZZ])
+                        .long("trim-fuzz")
+                        .help("How far in percent a border pixel can be from the border color with --trim")
+                        .value_parser(value_parser!(u8).range(0..=100))
+                        .default_value("10")
+                        .requires("TRIM"),
+                )
+                .arg(
+                    arg!([FORCE])
+                        .long("force")
+                        .help("Write into the output directory even if it already has files in it")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([REVERSE])
+                        .long("reverse")
+                        .help("Number from the last page to the first for right to left books. The last page becomes 1 and names no longer match pdf page numbers")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([COLLATE])
+                        .long("collate")
+                        .help("Interleave two pdfs, the first holding the odd pages and the second the even pages, like a duplex document scanned one side at a time")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!([REVERSE_EVEN])
+                        .long("reverse-even")
+                        .help("The even pages for --collate run from the last page to the first")
+                        .action(ArgAction::SetTrue)
+                        .requires("COLLATE")
+                        .required(false),
+                )
+                .arg(
+                    arg!([BG_COLOR])
+                        .long("bg-color")
+                        .help("RRGGBB color shown through transparent parts of masked images with --alpha flatten. Defaults to white")
+                        .value_parser(parse_hex_color)
+                        .required(false),
+                )
+                .arg(
+                    arg!([ALPHA])
+                        .long("alpha")
+                        .help("keep writes soft masks as an alpha channel. flatten blends them onto --bg-color for tools that can't handle transparency")
+                        .value_parser(["keep", "flatten"])
+                        .default_value("keep")
+                        .required(false),
+                )
+                .arg(
+                    arg!([BIT_DEPTH])
+                        .long("bit-depth")
+                        .visible_alias("output-depth")
+                        .help("8 scales 16 bit images down to 8 bit pngs for tools that can't read 16 bits. 16 keeps images at the depth they're stored in")
+                        .value_parser(["8", "16"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([DITHER])
+                        .long("dither")
+                        .help("Dither 16 bit images scaled down by --bit-depth 8 so gradients don't band")
+                        .requires("BIT_DEPTH")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([GROUP_BY_TYPE])
+                        .long("group-by-type")
+                        .help("Sort images into gray and color subdirectories of the output")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!([EXTRACT_TEXT])
+                        .long("extract-text")
+                        .help("Also write each page's text to a .txt named like its image")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["CONTIGUOUS", "SPRITE_SHEET"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([ATTACHMENTS])
+                        .long("attachments")
+                        .help("Also write the files embedded in the pdf to the output directory under their own names")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("SPRITE_SHEET")
+                        .required(false),
+                )
+                .arg(
+                    arg!([PREFER_HIGHRES])
+                        .long("prefer-highres")
+                        .help("Extract the largest of an image's alternate versions, like the print resolution one behind a screen proxy")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!([ICC_PROFILES])
+                        .long("icc-profiles")
+                        .help("Also write every ICC profile the pdf's color spaces use to the output directory as .icc files")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("SPRITE_SHEET")
+                        .required(false),
+                )
+                .arg(
+                    arg!([EXEC])
+                        .long("exec")
+                        .help("Run this command on every written image. {path} is replaced with the image's path. Quote arguments that have spaces in them")
+                        .required(false),
+                )
+                .arg(
+                    arg!([STRICT])
+                        .long("strict")
+                        .help("Fail the run when an --exec command fails or no images were extracted")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!([STRICT_COLORSPACE])
+                        .long("strict-colorspace")
+                        .help("Fail on images whose colors png can only approximate, like Lab, instead of converting them")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!([FORMAT])
+                        .long("format")
+                        .help("native writes every image as it's embedded and refuses anything that would re-encode it")
+                        .value_parser(["auto", "native"])
+                        .default_value("auto")
+                        .required(false),
+                )
+                .arg(
+                    arg!([SPRITE_SHEET])
+                        .long("sprite-sheet")
+                        .help("Pack every image into this png instead of a directory. Where each image went is written to a .json next to it")
+                        .value_parser(value_parser!(PathBuf))
+                        .conflicts_with_all(["OUT_DIRECTORY", "SVG"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([COLUMNS])
+                        .long("cols")
+                        .help("Images per row on the sprite sheet")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .requires("SPRITE_SHEET")
+                        .required(false),
+                )
+                .arg(
+                    arg!([CBZ_CHUNK])
+                        .long("cbz-chunk")
+                        .help("When writing to a .cbz, start a new part every N images. The parts are named like book.part001.cbz and keep the page numbers running in their file names")
+                        .value_name("N")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .required(false),
+                )
+                .arg(
+                    arg!([OBJECT])
+                        .long("object")
+                        .help("Only extract the image with this object id and generation, like --object 42 0. Takes a single pdf")
+                        .value_names(["ID", "GENERATION"])
+                        .num_args(2)
+                        .value_parser(value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    arg!([CHECKPOINT])
+                        .long("checkpoint")
+                        .help("Record finished pages every this many images. Rerunning into the same directory skips them")
+                        .value_parser(value_parser!(usize))
+                        .conflicts_with_all(["CONTIGUOUS", "MAX_IMAGES"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([DUMP_RAW])
+                        .long("dump-raw")
+                        .help("Also write each image's undecoded stream to <object id>_<generation>.bin")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("SPRITE_SHEET")
+                        .required(false),
+                )
+                .arg(
+                    arg!([DUMP_DICT])
+                        .long("dump-dict")
+                        .help("Also write each image's stream dictionary, with references resolved, to a .json named like the image")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["CONTIGUOUS", "SPRITE_SHEET"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([REPAIR])
+                        .long("repair")
+                        .help("Rebuild the cross-reference table of pdfs that fail to load and try again")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    arg!([TOLERANT])
+                        .long("tolerant")
+                        .help("Keep going on damaged pdfs. Pages cut off from the page tree are put back and objects that can't be read are reported")
+                        .action(ArgAction::SetTrue)
+                        .requires("REPAIR")
+                        .required(false),
+                )
+                .arg(
+                    arg!([PROFILE])
+                        .long("profile")
+                        .help("Print how long parsing, decoding, encoding and writing took, summed over every thread")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([ONLY_TYPES])
+                        .long("only-types")
+                        .help("Comma separated object types to keep while loading, like XObject,Font. Everything else with a type is dropped. Replaces PDFCON_IGNORE_TYPES")
+                        .value_delimiter(',')
+                        .num_args(1..)
+                        .required(false),
+                )
+                .arg(
+                    arg!([LIMIT_MEMORY])
+                        .long("limit-memory")
+                        .help("Fail an image when its decoded size would take what all threads are decoding past this, like 512M or 2G. The size is estimated from the image dimensions, so this isn't a hard ceiling on memory use")
+                        .value_parser(parse_size)
+                        .required(false),
+                )
+                .arg(
+                    arg!([NICE])
+                        .long("nice")
+                        .help("Run at this nice level from 0 to 19 so other work stays responsive")
+                        .value_parser(value_parser!(i32).range(0..=19))
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("split")
+                .about("Turn each page of a pdf into a pdf of its own")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([OUT_DIRECTORY])
+                        .short('d')
+                        .long("directory")
+                        .help("Directory to write 0001.pdf, 0002.pdf and so on to")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([FORCE])
+                        .long("force")
+                        .help("Write into the output directory even if it already has files in it")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("merge")
+                .about("Join the pages of several pdfs into one pdf")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .num_args(2..)
+                        .required(true),
+                )
+                .arg(
+                    arg!([OUT_FILE])
+                        .short('o')
+                        .long("output")
+                        .help("Pdf to write the merged pages to")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Print facts about a pdf without extracting anything")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("prescan")
+                .about("List the filters, color spaces and features a pdf uses and whether unpack supports them")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Benchmark unpack against a synthetic pdf")
+                .hide(true)
+                .arg(
+                    arg!([PAGES])
+                        .short('p')
+                        .long("pages")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    arg!([WIDTH])
+                        .long("width")
+                        .value_parser(value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    arg!([HEIGHT])
+                        .long("height")
+                        .value_parser(value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    arg!([FORMAT])
+                        .short('f')
+                        .long("format")
+                        .value_parser(["png", "jpg", "mixed"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([THREADS])
+                        .short('t')
+                        .long("threads")
+                        .value_parser(value_parser!(usize))
+                        .value_delimiter(',')
+                        .num_args(1..)
+                        .required(false),
                 ),
         );
 
     command
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unpack(args: &[&str]) -> Result<clap::ArgMatches, clap::Error> {
+        build_command().try_get_matches_from(["pdfcon", "unpack", "doc.pdf"].iter().chain(args))
+    }
+
+    #[test]
+    fn sprite_sheets_reject_sidecar_files() {
+        assert!(unpack(&["--sprite-sheet", "sheet.png"]).is_ok());
+        for flag in [
+            "--extract-text",
+            "--dump-raw",
+            "--dump-dict",
+            "--attachments",
+            "--icc-profiles",
+            "--svg",
+        ] {
+            let error = unpack(&["--sprite-sheet", "sheet.png", flag]).unwrap_err();
+            assert_eq!(
+                error.kind(),
+                clap::error::ErrorKind::ArgumentConflict,
+                "{}",
+                flag
+            );
+        }
+    }
+}
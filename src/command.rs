@@ -1,14 +1,26 @@
+use crate::bench::{Bench, BenchFormat};
 use crate::cli::build_command;
-use crate::constants::physical_cores;
+use crate::constants::{physical_cores, thread_count};
+use crate::info::Info;
+use crate::merge::Merge;
 use crate::pack::Pack;
+use crate::prescan::Prescan;
+use crate::split::Split;
 use crate::unpack::Unpack;
+use clap::error::ErrorKind;
 use std::ffi::OsStr;
 use std::path::PathBuf;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PDFCon {
-    UNPACK(Unpack),
+    // Boxed since it has far more options than the other commands
+    UNPACK(Box<Unpack>),
     PACK(Pack),
+    SPLIT(Split),
+    MERGE(Merge),
+    INFO(Info),
+    PRESCAN(Prescan),
+    BENCH(Bench),
 }
 
 pub fn get_command() -> PDFCon {
@@ -29,30 +41,204 @@ pub fn get_command() -> PDFCon {
                 .get_one::<PathBuf>("OUT_FILE")
                 .unwrap_or(&default_name)
                 .to_owned(),
-            threads: sub_matches
-                .get_one::<usize>("THREADS")
-                .copied()
-                .unwrap_or(total_physical / 2)
-                .clamp(1usize, total_physical * 2),
+            threads: thread_count(
+                sub_matches.get_one::<usize>("THREADS").copied(),
+                total_physical,
+            ),
+            pdfa: sub_matches.get_flag("PDFA"),
+            globs: sub_matches
+                .get_many::<String>("GLOB")
+                .map(|g| g.cloned().collect())
+                .unwrap_or_default(),
+            rotations: sub_matches.get_one::<PathBuf>("ROTATIONS").cloned(),
+            nice: sub_matches.get_one::<i32>("NICE").copied(),
+            validate_only: sub_matches.get_flag("VALIDATE_ONLY"),
+            intent: sub_matches.get_one::<String>("INTENT").cloned(),
+            file_list: sub_matches.get_one::<PathBuf>("FILE_LIST").cloned(),
+            compression: sub_matches.get_one::<u32>("COMPRESSION").copied(),
+            recursive: sub_matches.get_flag("RECURSIVE"),
         }),
-        Some(("unpack", sub_matches)) => PDFCon::UNPACK(Unpack {
-            threads: sub_matches
-                .get_one::<usize>("THREADS")
-                .copied()
-                .unwrap_or(total_physical / 2)
-                .clamp(1usize, total_physical * 2),
+        Some(("unpack", sub_matches)) => PDFCon::UNPACK(Box::new(Unpack {
+            threads: thread_count(
+                sub_matches.get_one::<usize>("THREADS").copied(),
+                total_physical,
+            ),
+            io_threads: sub_matches
+                .get_one::<usize>("IO_THREADS")
+                .map(|t| (*t).max(1)),
             out_directory: sub_matches
                 .get_one::<PathBuf>("OUT_DIRECTORY")
                 .unwrap_or(&PathBuf::from("output/"))
                 .to_owned(),
             in_file: sub_matches
-                .get_one::<PathBuf>("IN_FILE")
+                .get_many::<PathBuf>("IN_FILE")
                 .unwrap()
-                .to_owned(),
+                .cloned()
+                .collect(),
             optimize: sub_matches
                 .get_one::<bool>("OPTIMIZE")
                 .copied()
                 .unwrap_or(false),
+            contiguous: sub_matches.get_flag("CONTIGUOUS"),
+            pad: sub_matches.get_one::<usize>("PAD").copied(),
+            prefix: sub_matches.get_one::<String>("PREFIX").cloned(),
+            salvage: sub_matches.get_flag("SALVAGE"),
+            strip_thumbnails: sub_matches.get_flag("STRIP_THUMBNAILS"),
+            png_compression: sub_matches.get_one::<u32>("PNG_COMPRESSION").copied(),
+            max_images: sub_matches.get_one::<usize>("MAX_IMAGES").copied(),
+            top: sub_matches.get_one::<usize>("TOP").copied(),
+            aspect: sub_matches.get_one::<(f64, f64)>("ASPECT").copied(),
+            min_pixels: sub_matches
+                .get_one::<f64>("MIN_MEGAPIXELS")
+                .map(|mp| (mp.max(0.0) * 1_000_000.0) as u64),
+            embed_provenance: sub_matches.get_flag("EMBED_PROVENANCE"),
+            verify_output: sub_matches.get_flag("VERIFY_OUTPUT"),
+            svg: sub_matches.get_flag("SVG"),
+            force: sub_matches.get_flag("FORCE"),
+            reverse: sub_matches.get_flag("REVERSE"),
+            bg_color: sub_matches
+                .get_one::<[u8; 3]>("BG_COLOR")
+                .copied()
+                .unwrap_or([255, 255, 255]),
+            nice: sub_matches.get_one::<i32>("NICE").copied(),
+            limit_memory: sub_matches.get_one::<usize>("LIMIT_MEMORY").copied(),
+            repair: sub_matches.get_flag("REPAIR"),
+            tolerant: sub_matches.get_flag("TOLERANT"),
+            group_by_type: sub_matches.get_flag("GROUP_BY_TYPE"),
+            dump_raw: sub_matches.get_flag("DUMP_RAW"),
+            dump_dict: sub_matches.get_flag("DUMP_DICT"),
+            sprite_sheet: sub_matches.get_one::<PathBuf>("SPRITE_SHEET").cloned(),
+            checkpoint: sub_matches.get_one::<usize>("CHECKPOINT").copied(),
+            quantize: sub_matches.get_one::<u32>("QUANTIZE").map(|q| *q as usize),
+            apply_page_rotation: sub_matches.get_flag("APPLY_PAGE_ROTATION"),
+            auto_orient: sub_matches.get_flag("AUTO_ORIENT"),
+            strict_colorspace: sub_matches.get_flag("STRICT_COLORSPACE"),
+            profile: sub_matches.get_flag("PROFILE"),
+            only_types: sub_matches
+                .get_many::<String>("ONLY_TYPES")
+                .map(|types| types.cloned().collect()),
+            trim: sub_matches.get_flag("TRIM").then(|| {
+                // --trim-fuzz is a percent of the sample range. Samples are compared as bytes
+                let fuzz = *sub_matches.get_one::<u8>("TRIM_FUZZ").unwrap() as u32;
+                (fuzz * 255 / 100) as u8
+            }),
+            composite: sub_matches.get_flag("COMPOSITE"),
+            eight_bit: sub_matches
+                .get_one::<String>("BIT_DEPTH")
+                .is_some_and(|d| d == "8"),
+            dither: sub_matches.get_flag("DITHER"),
+            keep_alpha: sub_matches
+                .get_one::<String>("ALPHA")
+                .is_none_or(|a| a == "keep"),
+            native: sub_matches
+                .get_one::<String>("FORMAT")
+                .is_some_and(|f| f == "native"),
+            exec: sub_matches.get_one::<String>("EXEC").cloned(),
+            strict: sub_matches.get_flag("STRICT"),
+            extract_text: sub_matches.get_flag("EXTRACT_TEXT"),
+            attachments: sub_matches.get_flag("ATTACHMENTS"),
+            icc_profiles: sub_matches.get_flag("ICC_PROFILES"),
+            prefer_highres: sub_matches.get_flag("PREFER_HIGHRES"),
+            skip_similar: sub_matches.get_one::<u32>("SKIP_SIMILAR").copied(),
+            collate: sub_matches.get_flag("COLLATE"),
+            reverse_even: sub_matches.get_flag("REVERSE_EVEN"),
+            object: sub_matches
+                .get_many::<u32>("OBJECT")
+                .map(|o| o.copied().collect::<Vec<_>>())
+                .map(|o| {
+                    // Object ids only mean something within the pdf they're from
+                    if sub_matches.get_many::<PathBuf>("IN_FILE").unwrap().len() > 1 {
+                        build_command()
+                            .error(
+                                ErrorKind::ArgumentConflict,
+                                "--object picks an image out of one pdf but several were given",
+                            )
+                            .exit()
+                    }
+                    match u16::try_from(o[1]) {
+                        Ok(generation) => (o[0], generation),
+                        // Generations are 16 bits. Cutting a bigger one down would name another
+                        // object
+                        Err(_) => build_command()
+                            .error(
+                                ErrorKind::ValueValidation,
+                                format!("{} is too big for a generation of --object", o[1]),
+                            )
+                            .exit(),
+                    }
+                }),
+            page_offset: sub_matches
+                .get_one::<u32>("PAGE_OFFSET")
+                .copied()
+                .unwrap_or(0),
+            sprite_columns: sub_matches.get_one::<u32>("COLUMNS").map(|c| *c as usize),
+            cbz_chunk: sub_matches.get_one::<u32>("CBZ_CHUNK").map(|c| *c as usize),
+        })),
+        Some(("split", sub_matches)) => PDFCon::SPLIT(Split {
+            in_file: sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned(),
+            out_directory: sub_matches
+                .get_one::<PathBuf>("OUT_DIRECTORY")
+                .unwrap_or(&PathBuf::from("output/"))
+                .to_owned(),
+            force: sub_matches.get_flag("FORCE"),
+        }),
+        Some(("merge", sub_matches)) => PDFCon::MERGE(Merge {
+            in_file: sub_matches
+                .get_many::<PathBuf>("IN_FILE")
+                .unwrap()
+                .cloned()
+                .collect(),
+            out_file: sub_matches
+                .get_one::<PathBuf>("OUT_FILE")
+                .unwrap()
+                .to_owned(),
+        }),
+        Some(("info", sub_matches)) => PDFCon::INFO(Info {
+            in_file: sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned(),
+        }),
+        Some(("prescan", sub_matches)) => PDFCon::PRESCAN(Prescan {
+            in_file: sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned(),
+        }),
+        Some(("bench", sub_matches)) => PDFCon::BENCH(Bench {
+            pages: sub_matches
+                .get_one::<usize>("PAGES")
+                .copied()
+                .unwrap_or(100)
+                .max(1),
+            width: sub_matches
+                .get_one::<u32>("WIDTH")
+                .copied()
+                .unwrap_or(1240)
+                .max(1),
+            height: sub_matches
+                .get_one::<u32>("HEIGHT")
+                .copied()
+                .unwrap_or(1754)
+                .max(1),
+            format: match sub_matches.get_one::<String>("FORMAT").map(|f| f.as_str()) {
+                Some("png") => BenchFormat::PNG,
+                Some("jpg") => BenchFormat::JPG,
+                _ => BenchFormat::MIXED,
+            },
+            threads: match sub_matches.get_many::<usize>("THREADS") {
+                Some(threads) => threads
+                    .copied()
+                    .map(|t| thread_count(Some(t), total_physical))
+                    .collect(),
+                // Powers of two up to the physical core count
+                None => std::iter::successors(Some(1usize), |t| Some(t * 2))
+                    .take_while(|t| *t <= total_physical.max(1))
+                    .collect(),
+            },
         }),
         _ => unreachable!(
             "Subcommands are mandatory. It should not be possible to reach this branch"
@@ -0,0 +1,168 @@
+use crate::error::PDFConError;
+use crate::memory;
+use crate::svg::{IDENTITY, Matrix, concat, transform};
+use image::{DynamicImage, Rgba, RgbaImage};
+use lopdf::content::Content;
+use lopdf::{Document, ObjectId};
+
+// Puts the images of a page back together where its content stream draws them, for scans that
+// were stored as strips or tiles. Only images drawn by the page itself are placed, not ones
+// inside forms or patterns
+
+// Longest side of a composite. A stray placement far off the page shouldn't ask for a canvas
+// the size of a building
+const MAX_SIDE: f64 = 30000.0;
+
+// Every Do on the page along with the matrix it draws through. An image fills the unit square
+// of its matrix with its first row at the top
+pub fn image_placements(
+    doc: &Document,
+    page_id: ObjectId,
+) -> Result<Vec<(Vec<u8>, Matrix)>, PDFConError> {
+    let content = Content::decode(&doc.get_page_content(page_id)?)?;
+
+    let mut stack = Vec::new();
+    let mut ctm = IDENTITY;
+    let mut placements = Vec::new();
+    for operation in content.operations {
+        match operation.operator.as_str() {
+            "q" => stack.push(ctm),
+            "Q" => {
+                if let Some(saved) = stack.pop() {
+                    ctm = saved;
+                }
+            }
+            "cm" => {
+                let numbers: Vec<f64> = operation
+                    .operands
+                    .iter()
+                    .filter_map(|o| o.as_float().ok().map(f64::from))
+                    .collect();
+                if let Ok(matrix) = <Matrix>::try_from(numbers) {
+                    ctm = concat(&matrix, &ctm);
+                }
+            }
+            "Do" => {
+                if let Some(Ok(name)) = operation.operands.first().map(|o| o.as_name()) {
+                    placements.push((name.to_vec(), ctm));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(placements)
+}
+
+fn inverse(m: &Matrix) -> Option<Matrix> {
+    let det = m[0] * m[3] - m[1] * m[2];
+    if det.abs() < f64::EPSILON {
+        return None;
+    }
+    Some([
+        m[3] / det,
+        -m[1] / det,
+        -m[2] / det,
+        m[0] / det,
+        (m[2] * m[5] - m[3] * m[4]) / det,
+        (m[1] * m[4] - m[0] * m[5]) / det,
+    ])
+}
+
+fn corners(m: &Matrix) -> [(f64, f64); 4] {
+    [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)].map(|(x, y)| transform(m, x, y))
+}
+
+// Source over
+fn blend(under: &mut Rgba<u8>, over: &Rgba<u8>) {
+    let alpha = over[3] as f32 / 255.0;
+    let below = under[3] as f32 / 255.0 * (1.0 - alpha);
+    let total = alpha + below;
+    if total <= 0.0 {
+        return;
+    }
+    for channel in 0..3 {
+        under[channel] =
+            ((over[channel] as f32 * alpha + under[channel] as f32 * below) / total).round() as u8;
+    }
+    under[3] = (total * 255.0).round() as u8;
+}
+
+// The area every layer covers, drawn in order. The densest image decides how many pixels a point
+// gets so none of them lose detail. Samples are picked, not interpolated, so strips that sit edge
+// to edge come back as the original scan. Without keep_alpha the canvas starts out white
+pub fn composite(
+    layers: &[(Matrix, &DynamicImage)],
+    keep_alpha: bool,
+    limit_memory: Option<usize>,
+) -> Result<Option<RgbaImage>, PDFConError> {
+    let mut bounds: Option<[f64; 4]> = None;
+    let mut scale: f64 = 0.0;
+    for (matrix, image) in layers {
+        for (x, y) in corners(matrix) {
+            bounds = Some(match bounds {
+                Some([min_x, min_y, max_x, max_y]) => {
+                    [min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)]
+                }
+                None => [x, y, x, y],
+            });
+        }
+        let across = matrix[0].hypot(matrix[1]);
+        let down = matrix[2].hypot(matrix[3]);
+        if across > 0.0 && down > 0.0 {
+            scale = scale
+                .max(image.width() as f64 / across)
+                .max(image.height() as f64 / down);
+        }
+    }
+    let Some([min_x, min_y, max_x, max_y]) = bounds else {
+        return Ok(None);
+    };
+    if scale <= 0.0 || !scale.is_finite() {
+        return Ok(None);
+    }
+    let scale = scale.min(MAX_SIDE / (max_x - min_x).max(max_y - min_y));
+    let width = ((max_x - min_x) * scale).round().max(1.0) as u32;
+    let height = ((max_y - min_y) * scale).round().max(1.0) as u32;
+    let _reserved = memory::reserve(width as usize * height as usize * 4, limit_memory)?;
+
+    let background = if keep_alpha {
+        Rgba([0, 0, 0, 0])
+    } else {
+        Rgba([255, 255, 255, 255])
+    };
+    let mut canvas = RgbaImage::from_pixel(width, height, background);
+    // Page space to canvas pixels. The canvas has y going down
+    let device = [scale, 0.0, 0.0, -scale, -min_x * scale, max_y * scale];
+    for (matrix, image) in layers {
+        let placed = concat(matrix, &device);
+        let Some(back) = inverse(&placed) else {
+            continue;
+        };
+        let source = image.to_rgba8();
+        let (source_width, source_height) = source.dimensions();
+
+        let points = corners(&placed);
+        let span = |axis: fn(&(f64, f64)) -> f64, limit: u32| {
+            let low = points.iter().map(axis).fold(f64::INFINITY, f64::min);
+            let high = points.iter().map(axis).fold(f64::NEG_INFINITY, f64::max);
+            (
+                low.floor().max(0.0) as u32,
+                (high.ceil().max(0.0) as u32).min(limit),
+            )
+        };
+        let (left, right) = span(|p| p.0, width);
+        let (top, bottom) = span(|p| p.1, height);
+        for y in top..bottom {
+            for x in left..right {
+                let (u, v) = transform(&back, x as f64 + 0.5, y as f64 + 0.5);
+                if !(0.0..1.0).contains(&u) || !(0.0..1.0).contains(&v) {
+                    continue;
+                }
+                let column = ((u * source_width as f64) as u32).min(source_width - 1);
+                let row = (((1.0 - v) * source_height as f64) as u32).min(source_height - 1);
+                blend(canvas.get_pixel_mut(x, y), source.get_pixel(column, row));
+            }
+        }
+    }
+    Ok(Some(canvas))
+}
@@ -20,8 +20,13 @@ pub static IGNORE_LIST: &[&[u8]] = &[
     b"Annot",
 ];
 
-const THREADS: OnceLock<usize> = OnceLock::new();
-const TICK_SPEED: OnceLock<u64> = OnceLock::new();
+// Kept with --only-types whatever it lists. The page tree is how images are found and object
+// streams hold other objects that haven't been filtered yet
+pub static REQUIRED_TYPES: &[&[u8]] = &[b"Catalog", b"Pages", b"Page", b"ObjStm"];
+
+static IGNORE_TYPES: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+static THREADS: OnceLock<usize> = OnceLock::new();
+static TICK_SPEED: OnceLock<u64> = OnceLock::new();
 static CURRENT_DIR: OnceLock<PathBuf> = OnceLock::new();
 static BOLD: OnceLock<Style> = OnceLock::new();
 static C_GRAY: OnceLock<Style> = OnceLock::new();
@@ -30,8 +35,47 @@ static BC_LGT_GREEN: OnceLock<Style> = OnceLock::new();
 static BC_GREEN: OnceLock<Style> = OnceLock::new();
 static BC_DRK_GREEN: OnceLock<Style> = OnceLock::new();
 
+// Core detection can come back with 0 in containers and on odd platforms. Never report fewer than one
 pub fn physical_cores() -> usize {
-    *THREADS.get_or_init(|| num_cpus::get_physical())
+    *THREADS.get_or_init(|| num_cpus::get_physical().max(1))
+}
+
+// Worker threads for a command. Half the cores by default and never more than twice as many as
+// there are. A core count of 0 still gives one thread
+pub fn thread_count(requested: Option<usize>, cores: usize) -> usize {
+    let cores = cores.max(1);
+    requested.unwrap_or(cores / 2).clamp(1, cores * 2)
+}
+
+// Object types dropped on load. PDFCON_IGNORE_TYPES="Font,Metadata" adds to IGNORE_LIST and a
+// leading - takes a type back out of it, like "-Annot". Read once since filter_func can't take state
+pub fn ignore_types() -> &'static [Vec<u8>] {
+    IGNORE_TYPES.get_or_init(|| {
+        let mut types: Vec<Vec<u8>> = IGNORE_LIST.iter().map(|t| t.to_vec()).collect();
+        let overrides = std::env::var("PDFCON_IGNORE_TYPES").unwrap_or_default();
+        for name in overrides.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name.strip_prefix('-') {
+                Some(removed) => types.retain(|t| t != removed.as_bytes()),
+                None if !types.iter().any(|t| t == name.as_bytes()) => types.push(name.into()),
+                None => {}
+            }
+        }
+        types
+    })
+}
+
+// Whether an object with this Type is kept. Objects without one are always kept since they're
+// things like resource dictionaries and decode parameters that images need. --only-types replaces
+// ignore_types when it's given
+pub fn keeps_type(type_name: &[u8], only_types: Option<&[String]>) -> bool {
+    match only_types {
+        Some(types) => {
+            type_name.is_empty()
+                || REQUIRED_TYPES.contains(&type_name)
+                || types.iter().any(|t| t.as_bytes() == type_name)
+        }
+        None => !ignore_types().iter().any(|t| t == type_name),
+    }
 }
 
 pub fn tick_speed() -> u64 {
@@ -60,3 +104,39 @@ pub fn bc_green() -> &'static Style {
 pub fn bc_drk_green() -> &'static Style {
     BC_DRK_GREEN.get_or_init(|| Style::new().color256(65).bold())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_cores_or_one_core_gives_one_thread() {
+        assert_eq!(thread_count(None, 0), 1);
+        assert_eq!(thread_count(None, 1), 1);
+        assert_eq!(thread_count(Some(0), 0), 1);
+        assert_eq!(thread_count(Some(0), 1), 1);
+    }
+
+    #[test]
+    fn default_is_half_the_cores() {
+        assert_eq!(thread_count(None, 8), 4);
+        assert_eq!(thread_count(None, 3), 1);
+    }
+
+    #[test]
+    fn requests_are_capped_at_twice_the_cores() {
+        assert_eq!(thread_count(Some(64), 4), 8);
+        assert_eq!(thread_count(Some(64), 0), 2);
+        assert_eq!(thread_count(Some(5), 4), 5);
+    }
+
+    #[test]
+    fn only_types_replaces_the_ignore_list() {
+        let only = ["Annot".to_string()];
+        assert!(!keeps_type(b"Annot", None));
+        assert!(keeps_type(b"Annot", Some(&only)));
+        assert!(!keeps_type(b"XObject", Some(&only)));
+        assert!(keeps_type(b"Page", Some(&only)));
+        assert!(keeps_type(b"", Some(&only)));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::sprite::json_string;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::HashSet;
+
+// Deep enough for a color space, its profile and the profile's alternate. Anything further is
+// left as a bare reference so a dump can't pull in half the document
+const MAX_DEPTH: usize = 8;
+
+fn dictionary_json(
+    doc: &Document,
+    dict: &Dictionary,
+    depth: usize,
+    seen: &mut HashSet<ObjectId>,
+) -> String {
+    let entries: Vec<String> = dict
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{}: {}",
+                json_string(&String::from_utf8_lossy(key)),
+                object_json(doc, value, depth, seen)
+            )
+        })
+        .collect();
+    format!("{{{}}}", entries.join(", "))
+}
+
+// Names keep their slash so they can be told apart from strings
+fn object_json(
+    doc: &Document,
+    object: &Object,
+    depth: usize,
+    seen: &mut HashSet<ObjectId>,
+) -> String {
+    match object {
+        Object::Null => String::from("null"),
+        Object::Boolean(b) => b.to_string(),
+        Object::Integer(i) => i.to_string(),
+        Object::Real(r) if r.is_finite() => r.to_string(),
+        // Json has no way to write these
+        Object::Real(_) => String::from("null"),
+        Object::Name(name) => json_string(&format!("/{}", String::from_utf8_lossy(name))),
+        Object::String(text, _) => json_string(&String::from_utf8_lossy(text)),
+        Object::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(|i| object_json(doc, i, depth, seen))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Object::Dictionary(dict) => dictionary_json(doc, dict, depth, seen),
+        // Only the dictionary. The data of a profile or a mask doesn't help anyone reading this
+        Object::Stream(stream) => format!(
+            "{{\"stream\": {}, \"bytes\": {}}}",
+            dictionary_json(doc, &stream.dict, depth, seen),
+            stream.content.len()
+        ),
+        // The value is filled in where the reference was, next to the id it came from. One that
+        // loops back to an object being dumped only gets its id
+        Object::Reference(id) => {
+            let reference = json_string(&format!("{} {} R", id.0, id.1));
+            if depth >= MAX_DEPTH || !seen.insert(*id) {
+                return format!("{{\"reference\": {}}}", reference);
+            }
+            let value = match doc.get_object(*id) {
+                Ok(value) => object_json(doc, value, depth + 1, seen),
+                Err(_) => String::from("null"),
+            };
+            seen.remove(id);
+            format!("{{\"reference\": {}, \"value\": {}}}", reference, value)
+        }
+    }
+}
+
+// One image's stream dictionary with every reference in it resolved, along with its filter names
+// in the order the dictionary lists them
+pub fn image_json(doc: &Document, id: ObjectId, page: u32, filters: &[&[u8]]) -> Option<String> {
+    let stream = doc.get_object(id).ok()?.as_stream().ok()?;
+    let mut seen = HashSet::from([id]);
+    let filters: Vec<String> = filters
+        .iter()
+        .map(|f| json_string(&String::from_utf8_lossy(f)))
+        .collect();
+    Some(format!(
+        "{{\n  \"object\": {},\n  \"page\": {},\n  \"bytes\": {},\n  \"filters\": [{}],\n  \"dictionary\": {}\n}}\n",
+        json_string(&format!("{} {} R", id.0, id.1)),
+        page,
+        stream.content.len(),
+        filters.join(", "),
+        dictionary_json(doc, &stream.dict, 0, &mut seen)
+    ))
+}
@@ -27,4 +27,40 @@ pub enum PDFConError {
     LopdfError(#[from] lopdf::Error),
     #[error("Error encountered when unpacking pdf")]
     UnpackError,
+    #[error("Error encountered when splitting pdf")]
+    SplitError,
+    #[error("Corrupt jpeg stream, {0}")]
+    CorruptJpegError(String),
+    #[error("Pixel data doesn't match the image size, {0}")]
+    PixelDataError(String),
+    #[error("Invalid glob pattern {0}")]
+    GlobPatternError(#[from] glob::PatternError),
+    #[error("Unsupported color space {0}")]
+    UnsupportedColorSpace(String),
+    #[error("Can't write the output, {0}")]
+    OutputFormatError(String),
+    #[error("Output verification failed, {0}")]
+    VerifyError(String),
+    #[error("Input validation failed, {0}")]
+    ValidateError(String),
+    #[error("{0} isn't empty. Use --force to write into it anyway")]
+    OutputNotEmptyError(String),
+    #[error("Output path {0} exists and isn't a directory")]
+    NotADirectoryError(String),
+    #[error("Invalid rotations file, {0}")]
+    RotationsError(String),
+    #[error("Invalid file list, {0}")]
+    FileListError(String),
+    #[error("Memory limit reached, {0}")]
+    MemoryLimitError(String),
+    #[error("Can't undo the predictor, {0}")]
+    PredictorError(String),
+    #[error("External command failed, {0}")]
+    ExecError(String),
+    #[error("Can't collate, {0}")]
+    CollateError(String),
+    #[error("No images were extracted, {0}")]
+    NoImagesError(String),
+    #[error("Suspicious filter chain, {0}")]
+    FilterChainError(String),
 }
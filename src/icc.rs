@@ -0,0 +1,76 @@
+use crate::attachments::Attachment;
+use log::warn;
+use lopdf::{Document, Object, ObjectId};
+use std::collections::{BTreeSet, HashSet};
+
+// FNV-1a. Unlike the std hasher it's the same from one build to the next so a profile keeps its
+// file name between runs
+fn fingerprint(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// An ICCBased color space is [/ICCBased stream]. They show up in resources, image dictionaries
+// and inside other color spaces like Indexed or DeviceN so every object is searched
+fn icc_streams(object: &Object, found: &mut BTreeSet<ObjectId>) {
+    let dict = match object {
+        Object::Array(items) => {
+            if let [Object::Name(name), Object::Reference(id), ..] = items.as_slice()
+                && name == b"ICCBased"
+            {
+                found.insert(*id);
+            }
+            return items.iter().for_each(|i| icc_streams(i, found));
+        }
+        Object::Dictionary(d) => d,
+        Object::Stream(s) => &s.dict,
+        _ => return,
+    };
+    for (_, value) in dict.iter() {
+        icc_streams(value, found);
+    }
+}
+
+// Every distinct profile the color spaces of the document use, named after a hash of its
+// content. Color spaces that share a profile only give one file
+pub fn color_profiles(doc: &Document) -> Vec<Attachment> {
+    let mut ids = BTreeSet::new();
+    for object in doc.objects.values() {
+        icc_streams(object, &mut ids);
+    }
+
+    let mut seen = HashSet::new();
+    let mut profiles = Vec::new();
+    for id in ids {
+        let Ok(stream) = doc.get_object(id).and_then(Object::as_stream) else {
+            warn!(
+                "Skipping ICC profile {} {} R since it isn't a stream",
+                id.0, id.1
+            );
+            continue;
+        };
+        let content = if stream.dict.has(b"Filter") {
+            match stream.decompressed_content() {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!(
+                        "Skipping ICC profile {} {} R that failed to decode: {{{}}}",
+                        id.0, id.1, e
+                    );
+                    continue;
+                }
+            }
+        } else {
+            stream.content.clone()
+        };
+        let hash = fingerprint(&content);
+        if seen.insert(hash) {
+            profiles.push(Attachment {
+                name: format!("{:016x}.icc", hash),
+                content,
+            });
+        }
+    }
+    profiles
+}
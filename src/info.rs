@@ -0,0 +1,131 @@
+use crate::Run;
+use crate::constants::{bc_yellow, bold, tick_speed};
+use crate::error::PDFConError;
+use crate::progress::{close_bar, spinner};
+use console::Term;
+use log::error;
+use lopdf::{Dictionary, Document, ObjectId, decode_text_string};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Info {
+    pub in_file: PathBuf,
+}
+
+impl Info {
+    fn metadata(&self, doc: &Document, key: &[u8]) -> Option<String> {
+        let info = resolved_dict(doc, &doc.trailer, b"Info")?;
+        let (_, value) = doc.dereference(info.get(key).ok()?).ok()?;
+        decode_text_string(value).ok()
+    }
+}
+
+// One fact per line, the name highlighted
+pub(crate) fn report(name: &str, value: String) {
+    let line = format!(
+        "{} {}",
+        bc_yellow().apply_to(format!("{name}:")),
+        bold().apply_to(value)
+    );
+    if Term::stdout().write_line(line.as_str()).is_err() {
+        error!("Failed to print to console");
+    }
+}
+
+// Every image placed on a page, directly or through a tiling pattern, which is where unpack looks
+// for them. Images shared between pages are only listed once
+pub fn page_images(doc: &Document) -> BTreeSet<ObjectId> {
+    let mut images = BTreeSet::new();
+    for page_id in doc.page_iter() {
+        let Ok((direct, resource_ids)) = doc.get_page_resources(page_id) else {
+            continue;
+        };
+        let resources = direct.into_iter().chain(
+            resource_ids
+                .iter()
+                .filter_map(|id| doc.get_dictionary(*id).ok()),
+        );
+        for resource in resources {
+            xobject_images(doc, resource, &mut images);
+            let Some(patterns) = resolved_dict(doc, resource, b"Pattern") else {
+                continue;
+            };
+            for (_, pattern) in patterns.iter() {
+                if let Ok((_, pattern)) = doc.dereference(pattern)
+                    && let Ok(pattern) = pattern.as_stream()
+                    && let Some(pattern_resources) = resolved_dict(doc, &pattern.dict, b"Resources")
+                {
+                    xobject_images(doc, pattern_resources, &mut images);
+                }
+            }
+        }
+    }
+    images
+}
+
+fn xobject_images(doc: &Document, resources: &Dictionary, images: &mut BTreeSet<ObjectId>) {
+    let Some(xobjects) = resolved_dict(doc, resources, b"XObject") else {
+        return;
+    };
+    for (_, xobject) in xobjects.iter() {
+        let Ok(id) = xobject.as_reference() else {
+            continue;
+        };
+        let is_image = doc
+            .get_object(id)
+            .and_then(|o| o.as_stream())
+            .and_then(|s| s.dict.get(b"Subtype"))
+            .and_then(|s| s.as_name())
+            .is_ok_and(|s| s == b"Image");
+        if is_image {
+            images.insert(id);
+        }
+    }
+}
+
+fn resolved_dict<'a>(
+    doc: &'a Document,
+    dict: &'a Dictionary,
+    key: &[u8],
+) -> Option<&'a Dictionary> {
+    let (_, object) = doc.dereference(dict.get(key).ok()?).ok()?;
+    object.as_dict().ok()
+}
+
+impl Run for Info {
+    fn run(&self) -> Result<(), PDFConError> {
+        let spnr = spinner("Parsing PDF", tick_speed());
+        spnr.set_message(self.in_file.to_string_lossy().to_string());
+
+        // Load without the unpack filter so the Info dictionary is still around
+        let doc = Document::load(&self.in_file)?;
+
+        close_bar(spnr, " ● Parsing Complete! ");
+
+        // lopdf decrypts with the empty password on load. Either way the source was encrypted
+        let encrypted = doc.is_encrypted() || doc.encryption_state.is_some();
+        let linearized = doc
+            .objects
+            .values()
+            .any(|o| o.as_dict().is_ok_and(|d| d.has(b"Linearized")));
+        let unknown = || String::from("-");
+
+        report("File", self.in_file.to_string_lossy().to_string());
+        report("Version", doc.version.clone());
+        report("Pages", doc.get_pages().len().to_string());
+        report("Images", page_images(&doc).len().to_string());
+        report("Encrypted", encrypted.to_string());
+        report("Linearized", linearized.to_string());
+        report(
+            "Producer",
+            self.metadata(&doc, b"Producer").unwrap_or_else(unknown),
+        );
+        report(
+            "Creator",
+            self.metadata(&doc, b"Creator").unwrap_or_else(unknown),
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use crate::error::PDFConError;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+
+// Zero means no limit
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+// Writers currently holding a slot
+static ACTIVE: Mutex<usize> = Mutex::new(0);
+static FREED: Condvar = Condvar::new();
+
+// Held for the length of one write. Dropping it hands the slot to the next waiting writer
+struct Slot;
+
+impl Slot {
+    fn acquire() -> Option<Self> {
+        let limit = LIMIT.load(Ordering::Relaxed);
+        if limit == 0 {
+            return None;
+        }
+        let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+        while *active >= limit {
+            active = FREED.wait(active).unwrap_or_else(|e| e.into_inner());
+        }
+        *active += 1;
+        Some(Self)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+        *active -= 1;
+        FREED.notify_one();
+    }
+}
+
+pub fn set_limit(writers: usize) {
+    LIMIT.store(writers, Ordering::Relaxed);
+}
+
+// Encoding happens before this so only the time spent on the disk counts against the limit.
+// Slow storage thrashes with too many files being written at once even when there are cores
+// free to decode
+pub fn write(path: &Path, contents: &[u8]) -> Result<(), PDFConError> {
+    let _slot = Slot::acquire();
+    std::fs::write(path, contents)?;
+    Ok(())
+}
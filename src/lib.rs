@@ -1,10 +1,31 @@
+pub mod attachments;
+pub mod bench;
+pub mod cbz;
+pub mod checkpoint;
 pub mod cli;
 pub mod command;
+pub mod composite;
 pub mod constants;
+pub mod dump;
 pub mod error;
+pub mod icc;
+pub mod info;
+pub mod io_limit;
+pub mod long_path;
+pub mod memory;
+pub mod merge;
 pub mod pack;
 pub mod pdf_image;
+pub mod pdfa;
+pub mod prescan;
+pub mod priority;
+pub mod profile;
 pub mod progress;
+pub mod repair;
+pub mod split;
+pub mod sprite;
+pub mod svg;
+pub mod tiff;
 pub mod unpack;
 
 pub trait Run {
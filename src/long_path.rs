@@ -0,0 +1,30 @@
+use std::path::{Path, PathBuf};
+
+// Windows refuses paths longer than MAX_PATH unless they start with \\?\. Deep output directories
+// and network shares run into that. Short paths are left alone since \\?\ also turns off the
+// usual path normalization
+#[cfg(windows)]
+pub fn extended(path: &Path) -> PathBuf {
+    // MAX_PATH less room for the subdirectories and file names unpack adds under the directory
+    const LIMIT: usize = 260 - 40;
+
+    let Ok(absolute) = std::path::absolute(path) else {
+        return path.to_path_buf();
+    };
+    let Some(text) = absolute.to_str() else {
+        return path.to_path_buf();
+    };
+    if text.starts_with(r"\\?\") || text.len() < LIMIT {
+        return path.to_path_buf();
+    }
+    match text.strip_prefix(r"\\") {
+        // \\server\share becomes \\?\UNC\server\share
+        Some(share) => PathBuf::from(format!(r"\\?\UNC\{}", share)),
+        None => PathBuf::from(format!(r"\\?\{}", text)),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
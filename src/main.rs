@@ -1,4 +1,3 @@
-use env_logger;
 use pdfcon::Run;
 use pdfcon::command;
 use pdfcon::error::PDFConError;
@@ -22,5 +21,10 @@ fn main() -> Result<(), PDFConError> {
             p.run()
         }
         command::PDFCon::UNPACK(up) => up.run(),
+        command::PDFCon::SPLIT(s) => s.run(),
+        command::PDFCon::MERGE(m) => m.run(),
+        command::PDFCon::INFO(i) => i.run(),
+        command::PDFCon::PRESCAN(p) => p.run(),
+        command::PDFCon::BENCH(b) => b.run(),
     }
 }
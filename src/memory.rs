@@ -0,0 +1,38 @@
+use crate::error::PDFConError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Bytes held by reservations that haven't been dropped yet. Shared by every run in the process
+// since they all take from the same memory
+static RESERVED: AtomicUsize = AtomicUsize::new(0);
+
+// Room for the big buffers of one image. It's given back when this drops so hold it for as long
+// as the buffers are alive
+pub struct Reservation(usize);
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.0 > 0 {
+            RESERVED.fetch_sub(self.0, Ordering::Relaxed);
+        }
+    }
+}
+
+// Check there's room for a buffer before building it so going over the limit fails the image
+// instead of getting the process killed. The limit comes from the run asking so runs with
+// different limits can share the process. Without a limit nothing is counted
+pub fn reserve(bytes: usize, limit: Option<usize>) -> Result<Reservation, PDFConError> {
+    let Some(limit) = limit.filter(|&l| l > 0) else {
+        return Ok(Reservation(0));
+    };
+    RESERVED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reserved| {
+            reserved.checked_add(bytes).filter(|&total| total <= limit)
+        })
+        .map(|_| Reservation(bytes))
+        .map_err(|reserved| {
+            PDFConError::MemoryLimitError(format!(
+                "{} bytes needed with {} of {} bytes in use",
+                bytes, reserved, limit
+            ))
+        })
+}
@@ -0,0 +1,229 @@
+use crate::Run;
+use crate::constants::tick_speed;
+use crate::error::PDFConError;
+use crate::progress::{close_bar, spinner};
+use crate::split::standalone_page;
+use log::{debug, warn};
+use lopdf::xref::XrefType;
+use lopdf::{Dictionary, Document, Object, ObjectId, StringFormat, dictionary};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// Name trees can nest. Stop following kids past this depth in case one refers to itself
+const MAX_TREE_DEPTH: usize = 16;
+
+// Keys and values of one name tree
+type NameEntries = Vec<(Vec<u8>, Object)>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Merge {
+    pub in_file: Vec<PathBuf>,
+    pub out_file: PathBuf,
+}
+
+// Every key and value of a name tree, in tree order
+fn name_tree_entries(doc: &Document, node: &Dictionary, depth: usize, entries: &mut NameEntries) {
+    if depth > MAX_TREE_DEPTH {
+        warn!("Not following a name tree past {} levels", MAX_TREE_DEPTH);
+        return;
+    }
+    if let Ok(names) = node.get(b"Names").and_then(|n| doc.dereference(n))
+        && let Ok(names) = names.1.as_array()
+    {
+        for pair in names.chunks(2) {
+            if let [key, value] = pair
+                && let Ok(key) = doc.dereference(key).and_then(|k| k.1.as_str())
+            {
+                entries.push((key.to_vec(), value.clone()));
+            }
+        }
+    }
+    if let Ok(kids) = node.get(b"Kids").and_then(|k| doc.dereference(k))
+        && let Ok(kids) = kids.1.as_array()
+    {
+        for kid in kids {
+            if let Ok((_, Object::Dictionary(kid))) = doc.dereference(kid) {
+                name_tree_entries(doc, kid, depth + 1, entries);
+            }
+        }
+    }
+}
+
+// Links reach named destinations through a Dest or D string. Point the ones using a renamed
+// destination at its new name
+fn rename_destination(object: &mut Object, from: &[u8], to: &[u8]) {
+    let dict = match object {
+        Object::Array(items) => {
+            return items
+                .iter_mut()
+                .for_each(|i| rename_destination(i, from, to));
+        }
+        Object::Dictionary(d) => d,
+        Object::Stream(s) => &mut s.dict,
+        _ => return,
+    };
+    for (key, value) in dict.iter_mut() {
+        match value {
+            Object::String(name, _) if (key == b"Dest" || key == b"D") && name == from => {
+                *name = to.to_vec();
+            }
+            _ => rename_destination(value, from, to),
+        }
+    }
+}
+
+impl Merge {
+    // Name trees of every document, keyed by tree like Dests or EmbeddedFiles. A key that an
+    // earlier document already used gets a number added. Renamed destinations are followed
+    // through the document's links so they still land in the right place
+    fn merge_names(
+        &self,
+        doc: &mut Document,
+        merged: &mut BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, Object>>,
+    ) {
+        let trees: Vec<(Vec<u8>, NameEntries)> = doc
+            .catalog()
+            .and_then(|c| c.get(b"Names"))
+            .and_then(|n| doc.dereference(n))
+            .and_then(|n| n.1.as_dict())
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|(tree, root)| {
+                        let (_, root) = doc.dereference(root).ok()?;
+                        let mut entries = Vec::new();
+                        name_tree_entries(doc, root.as_dict().ok()?, 0, &mut entries);
+                        Some((tree.clone(), entries))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for (tree, entries) in trees {
+            let names = merged.entry(tree.clone()).or_default();
+            for (key, value) in entries {
+                let mut unique = key.clone();
+                let mut copy = 2;
+                while names.contains_key(&unique) {
+                    unique = [key.as_slice(), format!(" ({})", copy).as_bytes()].concat();
+                    copy += 1;
+                }
+                if unique != key {
+                    debug!(
+                        "Renaming {} entry {} to {} since an earlier pdf uses it",
+                        String::from_utf8_lossy(&tree),
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(&unique)
+                    );
+                    if tree == b"Dests" {
+                        for object in doc.objects.values_mut() {
+                            rename_destination(object, &key, &unique);
+                        }
+                    }
+                }
+                names.insert(unique, value);
+            }
+        }
+    }
+
+    // Flat trees are fine at any size a merge ends up with. Keys have to be sorted, which the
+    // BTreeMap already takes care of
+    fn names_dictionary(&self, merged: BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, Object>>) -> Dictionary {
+        let mut names = Dictionary::new();
+        for (tree, entries) in merged {
+            let flat: Vec<Object> = entries
+                .into_iter()
+                .flat_map(|(key, value)| [Object::String(key, StringFormat::Literal), value])
+                .collect();
+            names.set(tree, dictionary! { "Names" => flat });
+        }
+        names
+    }
+}
+
+impl Run for Merge {
+    fn run(&self) -> Result<(), PDFConError> {
+        let mut merged = Document::with_version("1.4");
+        // Xref streams need pdf 1.5. A table works for every version
+        merged.reference_table.cross_reference_type = XrefType::CrossReferenceTable;
+        let mut pages: Vec<(ObjectId, Dictionary)> = Vec::new();
+        let mut names = BTreeMap::new();
+        let mut info = None;
+        let mut next_id = 1;
+
+        for in_file in &self.in_file {
+            let spnr = spinner("Parsing PDF", tick_speed());
+            spnr.set_message(in_file.to_string_lossy().to_string());
+            // Load without the unpack filter. Everything the pages use has to come along
+            let mut doc = Document::load(in_file)?;
+            close_bar(spnr, " ● Parsing Complete! ");
+
+            // Ids from every document end up in one file so each starts past the last one
+            doc.renumber_objects_with(next_id);
+            next_id = doc.max_id + 1;
+            if doc.version > merged.version {
+                merged.version = doc.version.clone();
+            }
+
+            // Before the pages are copied since renaming a destination rewrites their links
+            self.merge_names(&mut doc, &mut names);
+            // Resources and the rest are pushed down onto the pages. The Pages nodes they came
+            // from are replaced so resource names from different documents never share a
+            // dictionary
+            for page_id in doc.page_iter().collect::<Vec<_>>() {
+                pages.push((page_id, standalone_page(&doc, page_id)?));
+            }
+
+            // Info from the first pdf that has any. Later ones would only overwrite its title
+            if info.is_none() {
+                info = doc.trailer.get(b"Info").ok().cloned();
+            }
+            let catalog = doc.catalog()?;
+            if catalog.has(b"Outlines") || catalog.has(b"AcroForm") {
+                warn!(
+                    "Bookmarks and form fields of {} aren't carried over",
+                    in_file.to_string_lossy()
+                );
+            }
+
+            merged.objects.extend(doc.objects);
+        }
+        merged.max_id = next_id - 1;
+
+        let pages_id = merged.new_object_id();
+        let kids: Vec<Object> = pages.iter().map(|(id, _)| Object::Reference(*id)).collect();
+        let count = kids.len() as i64;
+        for (page_id, mut page) in pages {
+            page.set("Parent", pages_id);
+            merged.objects.insert(page_id, Object::Dictionary(page));
+        }
+        merged.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => count,
+            }),
+        );
+
+        let mut catalog = dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        };
+        if !names.is_empty() {
+            catalog.set("Names", self.names_dictionary(names));
+        }
+        let catalog_id = merged.add_object(catalog);
+        merged.trailer.set("Root", catalog_id);
+        if let Some(info) = info {
+            merged.trailer.set("Info", info);
+        }
+
+        // The old catalogs, page trees and name trees are no longer referenced by anything
+        let unused = merged.prune_objects();
+        debug!("Dropped {} objects nothing refers to", unused.len());
+        merged.renumber_objects();
+        merged.save(&self.out_file)?;
+        Ok(())
+    }
+}
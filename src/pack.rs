@@ -1,14 +1,19 @@
 use crate::constants::tick_speed;
 use crate::pdf_image;
-use crate::progress::{bar, close_bar, update_end_cap};
+use crate::pdfa;
+use crate::priority;
+use crate::progress::{ParallelProgressIterator, bar, close_bar, update_end_cap};
 use crate::{Run, error::PDFConError};
-use indicatif::ParallelProgressIterator;
-use log::{debug, error};
+use flate2::Compression;
+use glob::Pattern;
+use log::{debug, error, warn};
 use lopdf::content::Content;
 use lopdf::{Document, Object, Stream, content::Operation, dictionary};
 use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::io::BufWriter;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Pack {
@@ -16,6 +21,22 @@ pub struct Pack {
     pub threads: usize,
     pub in_directory: PathBuf,
     pub out_file: PathBuf,
+    pub globs: Vec<String>,
+    pub pdfa: bool,
+    pub rotations: Option<PathBuf>,
+    pub nice: Option<i32>,
+    // Check the inputs decode without writing the pdf
+    pub validate_only: bool,
+    // Rendering intent written on every image, like Perceptual. Left out when None so viewers
+    // use their own default
+    pub intent: Option<String>,
+    // File with one image path per line. Pages follow its order and in_directory and globs
+    // aren't used
+    pub file_list: Option<PathBuf>,
+    // Deflate level from 0 to 9 for images that aren't jpegs. Best when None
+    pub compression: Option<u32>,
+    // Include images in every directory under in_directory, ordered by directory then name
+    pub recursive: bool,
 }
 
 #[derive(Debug)]
@@ -39,16 +60,320 @@ impl ImageFile {
     }
 }
 
+fn image_type(path: &Path) -> Option<ImageType> {
+    match path.extension()?.to_str()? {
+        "png" => Some(ImageType::PNG),
+        "jpeg" | "jpg" => Some(ImageType::JPG),
+        _ => None,
+    }
+}
+
+fn digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        number.push(c);
+    }
+    number
+}
+
+// Runs of digits compare by their value so chapter 2 comes before chapter 10. Leading zeros only
+// break ties
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let order = match (a.peek(), b.peek()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (digits(&mut a), digits(&mut b));
+                let (x_value, y_value) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x_value
+                    .len()
+                    .cmp(&y_value.len())
+                    .then_with(|| x_value.cmp(y_value))
+                    .then_with(|| y.len().cmp(&x.len()))
+            }
+            (Some(x), Some(y)) => {
+                let order = x.cmp(y);
+                a.next();
+                b.next();
+                order
+            }
+        };
+        if order.is_ne() {
+            return order;
+        }
+    }
+}
+
+// Directories first, a directory's own images before those of the directories in it, then the
+// file names. Every part is compared naturally
+fn natural_path_cmp(a: &Path, b: &Path) -> Ordering {
+    let directories = |path: &Path| -> Vec<String> {
+        path.parent()
+            .map(|p| {
+                p.components()
+                    .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let name = |path: &Path| {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    let (a_directories, b_directories) = (directories(a), directories(b));
+    a_directories
+        .iter()
+        .zip(&b_directories)
+        .map(|(x, y)| natural_cmp(x, y))
+        .find(|o| o.is_ne())
+        .unwrap_or_else(|| a_directories.len().cmp(&b_directories.len()))
+        .then_with(|| natural_cmp(&name(a), &name(b)))
+}
+
+// Assemble a document with one page per image. Each page is sized to its image
+pub fn build_document(
+    pre_processed: Vec<pdf_image::optimize::ImageData>,
+) -> Result<Document, PDFConError> {
+    // Use the latest PDF version
+    let mut doc = Document::with_version("1.7");
+
+    // Object IDs are used for cross referencing in PDF documents.
+    // lopdf helps keep track of these. They're simple integers.
+    // Calls to doc.new_object_id and doc.add_object produce a new object ID
+    // Pages is the root node of the page tree
+    let pages_id = doc.new_object_id();
+
+    // Content is a wrapper struct around an operations struct that contains a vector of operations
+    // The operations struct contains a vector of operations that match up with a particular PDF operator and
+    // operands
+    // The PDF spec has more details on the operators and operands
+    // Note, the operators and operands are specified in a reverse order than they actually
+    // appear in the PDF file itself
+
+    // Streams are a dictionary followed by a sequence of bytes. What the bytes represent depends on the
+    // context. The stream dictionary is set internally by lopdf and normally doesn't need to be manually
+    // manipulated. It contains keys such as Length, Filter, DecodeParams, etc.
+
+    let mut page_ids = Vec::new();
+    for image_data in pre_processed {
+        match image_data {
+            pdf_image::optimize::ImageData::PNG(compressed_data, width, height, color_type) => {
+                let (color_type, bits) = color_type.to_pdf_format();
+                let dic = dictionary!(
+                    "Type" => Object::Name(b"XObject".to_vec()),
+                    "Subtype" => Object::Name(b"Image".to_vec()),
+                    "Width" => width,
+                    "Height" => height,
+                    "ColorSpace" => Object::Name(color_type),
+                    "BitsPerComponent" => bits,
+                    "Filter" => Object::Name(b"FlateDecode".to_vec())
+                );
+                let img_object = Stream::new(dic, compressed_data);
+                let img_id = doc.add_object(img_object);
+                let img_name = format!("X{}", img_id.0);
+
+                let cm_operation = Operation::new(
+                    "cm",
+                    vec![
+                        width.into(),
+                        0.into(),
+                        0.into(),
+                        height.into(),
+                        0.into(),
+                        0.into(),
+                    ],
+                );
+
+                let do_operation =
+                    Operation::new("Do", vec![Object::Name(img_name.as_bytes().to_vec())]);
+                let content = Content {
+                    operations: vec![cm_operation, do_operation],
+                };
+
+                let content_id =
+                    doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+
+                let page_id = doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "Contents" => content_id,
+                    "MediaBox" => vec![0.into(), 0.into(), width.into(), height.into()]
+                });
+
+                doc.add_xobject(page_id, img_name.as_bytes(), img_id)
+                    .unwrap();
+
+                page_ids.push(page_id);
+            }
+            pdf_image::optimize::ImageData::JPEG(
+                compressed_data,
+                width,
+                height,
+                color_type,
+            ) => {
+                let (color_type, bits) = color_type.to_pdf_format();
+                let dic = dictionary!(
+                    "Type" => Object::Name(b"XObject".to_vec()),
+                    "Subtype" => Object::Name(b"Image".to_vec()),
+                    "Width" => width,
+                    "Height" => height,
+                    "ColorSpace" => Object::Name(color_type),
+                    "BitsPerComponent" => bits,
+                    "Filter" => Object::Name(b"DCTDecode".to_vec())
+                );
+                let img_object = Stream::new(dic, compressed_data);
+                let img_id = doc.add_object(img_object);
+                let img_name = format!("X{}", img_id.0);
+
+                let cm_operation = Operation::new(
+                    "cm",
+                    vec![
+                        width.into(),
+                        0.into(),
+                        0.into(),
+                        height.into(),
+                        0.into(),
+                        0.into(),
+                    ],
+                );
+
+                let do_operation =
+                    Operation::new("Do", vec![Object::Name(img_name.as_bytes().to_vec())]);
+                let content = Content {
+                    operations: vec![cm_operation, do_operation],
+                };
+
+                let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
+
+                let page_id = doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "Contents" => content_id,
+                    "MediaBox" => vec![0.into(), 0.into(), width.into(), height.into()]
+                });
+
+                doc.add_xobject(page_id, img_name.as_bytes(), img_id)
+                    .unwrap();
+
+                page_ids.push(page_id);
+            }
+        }
+    }
+
+    let pages_dict = dictionary! {
+        "Type" => "Pages",
+        "Count" => page_ids.len() as u32,
+        "Kids" => page_ids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
+    };
+
+    doc.objects.insert(pages_id, Object::Dictionary(pages_dict));
+
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+
+    doc.trailer.set("Root", catalog_id);
+
+    Ok(doc)
+}
+
+// Sidecar with one "file_name angle" pair per line. Blank lines and lines starting with # are
+// skipped. The name is everything before the last space so names can contain spaces
+pub fn read_rotations(path: &Path) -> Result<HashMap<String, i64>, PDFConError> {
+    let text = std::fs::read_to_string(path)?;
+    let mut rotations = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            PDFConError::RotationsError(format!(
+                "{} line {}: {}",
+                path.to_string_lossy(),
+                index + 1,
+                reason
+            ))
+        };
+        let (name, angle) = line
+            .rsplit_once(char::is_whitespace)
+            .ok_or_else(|| invalid("expected a file name and an angle"))?;
+        let angle = angle
+            .parse::<i64>()
+            .ok()
+            .filter(|a| a % 90 == 0)
+            .ok_or_else(|| invalid("the angle has to be a multiple of 90"))?;
+        rotations.insert(name.trim_end().to_string(), angle.rem_euclid(360));
+    }
+    Ok(rotations)
+}
+
+// Set Intent on every image in the document
+pub fn apply_intent(doc: &mut Document, intent: &str) {
+    for object in doc.objects.values_mut() {
+        if let Object::Stream(stream) = object
+            && stream
+                .dict
+                .get(b"Subtype")
+                .and_then(Object::as_name)
+                .is_ok_and(|s| s == b"Image")
+        {
+            stream
+                .dict
+                .set("Intent", Object::Name(intent.as_bytes().to_vec()));
+        }
+    }
+}
+
+// Paths are used as written, so relative ones are relative to where pdfcon runs. Blank lines are
+// skipped. Anything else that isn't a png or jpeg on disk is an error pointing at its line
+pub fn read_file_list(path: &Path) -> Result<Vec<ImageFile>, PDFConError> {
+    let text = std::fs::read_to_string(path)?;
+    let mut files = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            PDFConError::FileListError(format!(
+                "{} line {}: {}",
+                path.to_string_lossy(),
+                index + 1,
+                reason
+            ))
+        };
+        let location = PathBuf::from(line);
+        if !location.is_file() {
+            return Err(invalid(&format!("{} doesn't exist", line)));
+        }
+        let image_type = image_type(&location)
+            .ok_or_else(|| invalid(&format!("{} isn't a png or jpeg", line)))?;
+        files.push(ImageFile::new(location, image_type));
+    }
+    Ok(files)
+}
+
 impl Pack {
+    fn compression(&self) -> Compression {
+        self.compression
+            .map_or(Compression::best(), Compression::new)
+    }
+
     fn optimize(
         &self,
         image_file: &ImageFile,
     ) -> Result<pdf_image::optimize::ImageData, PDFConError> {
         let file = std::fs::OpenOptions::new()
             .read(true)
-            .open(image_file.location.to_owned())?;
+            .open(&image_file.location)?;
         match image_file.image_type {
-            ImageType::PNG => pdf_image::optimize::process_png_optimized(file),
+            ImageType::PNG => pdf_image::optimize::process_png_optimized(file, self.compression()),
             ImageType::JPG => pdf_image::optimize::optimize_jpeg(file),
         }
     }
@@ -59,9 +384,9 @@ impl Pack {
     ) -> Result<pdf_image::optimize::ImageData, PDFConError> {
         let file = std::fs::OpenOptions::new()
             .read(true)
-            .open(image_file.location.to_owned())?;
+            .open(&image_file.location)?;
         match image_file.image_type {
-            ImageType::PNG => pdf_image::optimize::process_png_optimized(file),
+            ImageType::PNG => pdf_image::optimize::process_png_optimized(file, self.compression()),
             ImageType::JPG => pdf_image::optimize::jpeg(file),
         }
     }
@@ -69,6 +394,7 @@ impl Pack {
     fn image_file_from_entry(
         &self,
         entry: Result<std::fs::DirEntry, std::io::Error>,
+        patterns: &[Pattern],
     ) -> Option<ImageFile> {
         let unwrapped_entry = match entry {
             Ok(e) => e,
@@ -97,31 +423,143 @@ impl Pack {
 
         let path = unwrapped_entry.path();
 
-        let image_type = match path.extension()?.to_str()? {
-            "png" => ImageType::PNG,
-            "jpeg" | "jpg" => ImageType::JPG,
-            _ => {
-                // File was not a supported image. This should be logged
-                debug!("File type not supported");
+        if !patterns.is_empty() {
+            let name = path.file_name()?.to_string_lossy();
+            if !patterns.iter().any(|p| p.matches(&name)) {
+                debug!("File does not match any glob");
                 return None;
             }
+        }
+
+        let Some(image_type) = image_type(&path) else {
+            // File was not a supported image. This should be logged
+            debug!("File type not supported");
+            return None;
         };
 
         Some(ImageFile::new(path, image_type))
     }
 
-    fn para_process(&self) -> Result<(), PDFConError> {
-        let directory = std::fs::read_dir(&self.in_directory)?;
+    // --recursive. Symlinked directories are followed but each directory is only read once so a
+    // link back up the tree can't loop. Subdirectories that can't be read are skipped
+    fn walk_directory(
+        &self,
+        directory: &Path,
+        patterns: &[Pattern],
+        visited: &mut HashSet<PathBuf>,
+        files: &mut Vec<ImageFile>,
+    ) -> Result<(), PDFConError> {
+        for entry in std::fs::read_dir(directory)? {
+            // is_dir follows symlinks where the entry's own file type wouldn't
+            let Some(path) = entry.as_ref().ok().map(|e| e.path()).filter(|p| p.is_dir()) else {
+                files.extend(self.image_file_from_entry(entry, patterns));
+                continue;
+            };
+            match path.canonicalize() {
+                Ok(real) if visited.contains(&real) => warn!(
+                    "Skipping {} since it leads back to a directory that was already read",
+                    path.to_string_lossy()
+                ),
+                Ok(real) => {
+                    visited.insert(real);
+                    if let Err(e) = self.walk_directory(&path, patterns, visited, files) {
+                        warn!("Skipping {}: {{{}}}", path.to_string_lossy(), e);
+                    }
+                }
+                Err(e) => warn!("Skipping {}: {{{}}}", path.to_string_lossy(), e),
+            }
+        }
+        Ok(())
+    }
+
+    // Images are named in a rotations file by their path under in_directory, with / between
+    // directories, so images of the same name in different directories of --recursive stay apart.
+    // For a flat directory that's just the file name. Images from elsewhere in a file list go by
+    // their file name
+    fn rotation_key(&self, location: &Path) -> String {
+        let relative = location
+            .strip_prefix(&self.in_directory)
+            .ok()
+            .filter(|r| !r.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new(location.file_name().unwrap_or_default()));
+        relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    // Supported images in the input directory in name order, which is page order. --recursive
+    // goes by directory first and compares naturally so page2 comes before page10. A file list
+    // is taken as it is
+    fn input_files(&self) -> Result<Vec<ImageFile>, PDFConError> {
+        if let Some(list) = &self.file_list {
+            return read_file_list(list);
+        }
+        let patterns = self
+            .globs
+            .iter()
+            .map(|g| Pattern::new(g))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut files = Vec::new();
+        if self.recursive {
+            let mut visited = HashSet::from([self.in_directory.canonicalize()?]);
+            self.walk_directory(&self.in_directory, &patterns, &mut visited, &mut files)?;
+            files.par_sort_by(|a, b| natural_path_cmp(&a.location, &b.location));
+        } else {
+            files = std::fs::read_dir(&self.in_directory)?
+                .filter_map(|e| self.image_file_from_entry(e, &patterns))
+                .collect();
+            files.par_sort_by_key(|k| k.location.to_owned());
+        }
+        Ok(files)
+    }
 
-        let mut files: Vec<ImageFile> = directory
-            .filter_map(|e| {
-                let entry = self.image_file_from_entry(e)?;
+    // Decode every input the same way packing would and report the ones that fail. Nothing is
+    // written
+    fn validate(&self) -> Result<(), PDFConError> {
+        let files = self.input_files()?;
 
-                Some(entry)
-            })
+        let pb = bar("Validating Images", files.len() as u64, tick_speed());
+
+        let results: Vec<(&ImageFile, Result<(), PDFConError>)> = files
+            .par_iter()
+            .progress_with(pb.clone())
+            .map(|image_file| (image_file, self.read_file(image_file).map(|_| ())))
             .collect();
 
-        files.par_sort_by_key(|k| k.location.to_owned());
+        close_bar(pb, " ● Validating Complete! ");
+
+        let mut failed = 0;
+        for (image_file, result) in results {
+            if let Err(e) = result {
+                failed += 1;
+                error!(
+                    "Failed to decode {}: {{{}}}",
+                    image_file.location.to_string_lossy(),
+                    e
+                );
+            }
+        }
+        if failed > 0 {
+            return Err(PDFConError::ValidateError(format!(
+                "{} of {} images can't be decoded",
+                failed,
+                files.len()
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn para_process(&self) -> Result<(), PDFConError> {
+        let files = self.input_files()?;
+
+        let mut rotations = match &self.rotations {
+            Some(path) => read_rotations(path)?,
+            None => HashMap::new(),
+        };
 
         // Initialize the progress bar
         let pb = bar("Converting to PDF", files.len() as u64, tick_speed());
@@ -136,13 +574,13 @@ impl Pack {
                 // Update bars end cap based on current progress
                 update_end_cap(&pb, pos, total);
 
-                if self.optimize {
+                let image = if self.optimize {
                     match self.optimize(image_file) {
                         Ok(bytes) => Some(bytes),
                         Err(e) => {
                             // LOG and ignore
-                            error!("Failed to optimize image file: {}", e.to_string());
-                            return None;
+                            error!("Failed to optimize image file: {}", e);
+                            None
                         }
                     }
                 } else {
@@ -153,163 +591,50 @@ impl Pack {
                         Err(_e) => {
                             // LOG and ignore
                             error!("Failed to read the file");
-                            return None;
+                            None
                         }
                     }
-                }
+                };
+                Some((image_file, image?))
             })
-            .collect::<Vec<pdf_image::optimize::ImageData>>();
+            .collect::<Vec<(&ImageFile, pdf_image::optimize::ImageData)>>();
 
         // Finish bar and display message
         close_bar(pb, " ● Converting Complete! ");
 
-        // Use the latest PDF version
-        let mut doc = Document::with_version("1.7");
-
-        // Object IDs are used for cross referencing in PDF documents.
-        // lopdf helps keep track of these. They're simple integers.
-        // Calls to doc.new_object_id and doc.add_object produce a new object ID
-        // Pages is the root node of the page tree
-        let pages_id = doc.new_object_id();
-
-        // Content is a wrapper struct around an operations struct that contains a vector of operations
-        // The operations struct contains a vector of operations that match up with a particular PDF operator and
-        // operands
-        // The PDF spec has more details on the operators and operands
-        // Note, the operators and operands are specified in a reverse order than they actually
-        // appear in the PDF file itself
-
-        // Streams are a dictionary followed by a sequence of bytes. What the bytes represent depends on the
-        // context. The stream dictionary is set internally by lopdf and normally doesn't need to be manually
-        // manipulated. It contains keys such as Length, Filter, DecodeParams, etc.
-
-        let mut page_ids = Vec::new();
-        let mut parent = pages_id;
-        for image_data in pre_processed {
-            match image_data {
-                pdf_image::optimize::ImageData::PNG(compressed_data, width, height, color_type) => {
-                    let (color_type, bits) = color_type.to_pdf_format();
-                    let dic = dictionary!(
-                        "Type" => Object::Name(b"XObject".to_vec()),
-                        "Subtype" => Object::Name(b"Image".to_vec()),
-                        "Width" => width as u32,
-                        "Height" => height as u32,
-                        "ColorSpace" => Object::Name(color_type),
-                        "BitsPerComponent" => bits as u32,
-                        "Filter" => Object::Name(b"FlateDecode".to_vec())
-                    );
-                    let img_object = Stream::new(dic, compressed_data);
-                    let img_id = doc.add_object(img_object);
-                    let img_name = format!("X{}", img_id.0);
-
-                    let cm_operation = Operation::new(
-                        "cm",
-                        vec![
-                            (width as u32).into(),
-                            0.into(),
-                            0.into(),
-                            (height as u32).into(),
-                            0.into(),
-                            0.into(),
-                        ],
-                    );
-
-                    let do_operation =
-                        Operation::new("Do", vec![Object::Name(img_name.as_bytes().to_vec())]);
-                    let content = Content {
-                        operations: vec![cm_operation, do_operation],
-                    };
-
-                    let content_id =
-                        doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
-
-                    let page_id = doc.add_object(dictionary! {
-                        "Type" => "Page",
-                        "Parent" => parent,
-                        "Contents" => content_id,
-                        "MediaBox" => vec![0.into(), 0.into(), (width as u32).into(), (height as u32).into()]
-                    });
-
-                    doc.add_xobject(page_id, img_name.as_bytes(), img_id)
-                        .unwrap();
-
-                    page_ids.push(page_id);
-                    parent = page_id;
-                }
-                pdf_image::optimize::ImageData::JPEG(
-                    compressed_data,
-                    width,
-                    height,
-                    color_type,
-                ) => {
-                    let (color_type, bits) = color_type.to_pdf_format();
-                    let dic = dictionary!(
-                        "Type" => Object::Name(b"XObject".to_vec()),
-                        "Subtype" => Object::Name(b"Image".to_vec()),
-                        "Width" => width as u32,
-                        "Height" => height as u32,
-                        "ColorSpace" => Object::Name(color_type),
-                        "BitsPerComponent" => bits as u32,
-                        "Filter" => Object::Name(b"DCTDecode".to_vec())
-                    );
-                    let img_object = Stream::new(dic, compressed_data);
-                    let img_id = doc.add_object(img_object);
-                    let img_name = format!("X{}", img_id.0);
-
-                    let cm_operation = Operation::new(
-                        "cm",
-                        vec![
-                            (width as u32).into(),
-                            0.into(),
-                            0.into(),
-                            (height as u32).into(),
-                            0.into(),
-                            0.into(),
-                        ],
-                    );
-
-                    let do_operation =
-                        Operation::new("Do", vec![Object::Name(img_name.as_bytes().to_vec())]);
-                    let content = Content {
-                        operations: vec![cm_operation, do_operation],
-                    };
-
-                    let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
-
-                    let page_id = doc.add_object(dictionary! {
-                        "Type" => "Page",
-                        "Parent" => parent,
-                        "Contents" => content_id,
-                        "MediaBox" => vec![0.into(), 0.into(), (width as u32).into(), (height as u32).into()]
-                    });
-
-                    doc.add_xobject(page_id, img_name.as_bytes(), img_id)
-                        .unwrap();
-
-                    page_ids.push(page_id);
-                    parent = page_id;
-                }
-            }
+        // Pages come out in the same order as the images so a rotation can be matched by index
+        let page_rotations = pre_processed
+            .iter()
+            .map(|(image_file, _)| {
+                rotations
+                    .remove(&self.rotation_key(&image_file.location))
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+        for name in rotations.keys() {
+            warn!("No image named {} to rotate", name);
         }
 
-        let pages_dict = dictionary! {
-            "Type" => "Pages",
-            "Count" => page_ids.len() as u32,
-            "Kids" => page_ids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
-        };
-
-        doc.objects.insert(pages_id, Object::Dictionary(pages_dict));
+        let mut doc = build_document(pre_processed.into_iter().map(|(_, i)| i).collect())?;
+        let pages = doc.get_pages();
+        for (page_id, rotation) in pages.values().zip(page_rotations) {
+            if rotation != 0 {
+                doc.get_dictionary_mut(*page_id)?.set("Rotate", rotation);
+            }
+        }
 
-        let catalog_id = doc.add_object(dictionary! {
-            "Type" => "Catalog",
-            "Pages" => pages_id,
-        });
+        if let Some(intent) = &self.intent {
+            apply_intent(&mut doc, intent);
+        }
 
-        doc.trailer.set("Root", catalog_id);
+        if self.pdfa {
+            pdfa::apply(&mut doc)?;
+        }
 
         let file = std::fs::OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(&self.out_file)?;
         let mut writer = BufWriter::new(file);
 
@@ -321,11 +646,19 @@ impl Pack {
 
 impl Run for Pack {
     fn run(&self) -> Result<(), PDFConError> {
-        rayon::ThreadPoolBuilder::new()
+        if let Some(nice) = self.nice {
+            priority::lower_priority(nice);
+        }
+        // A pool of our own instead of the global one so a host already using rayon is left alone
+        let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.threads)
-            .build_global()?;
+            .build()?;
 
-        self.para_process()?;
-        Ok(())
+        pool.install(|| {
+            if self.validate_only {
+                return self.validate();
+            }
+            self.para_process()
+        })
     }
 }
@@ -3,8 +3,9 @@ use std::io::{BufWriter, Write};
 use crate::error::PDFConError;
 use flate2::write::ZlibEncoder;
 use image::{ImageEncoder, codecs::png};
+use log::{debug, warn};
 use oxipng;
-use std::path::PathBuf;
+use std::path::Path;
 
 pub enum PDFConColorSpace {
     RGB8,
@@ -12,6 +13,12 @@ pub enum PDFConColorSpace {
     L8,
     L16,
     CMYK,
+    // CIE L*a*b*. Converted to 8 bit sRGB when written out
+    Lab {
+        white_point: [f32; 3],
+        range: [f32; 4],
+        bits: u8,
+    },
 }
 
 impl PDFConColorSpace {
@@ -22,17 +29,24 @@ impl PDFConColorSpace {
             Self::L8 => (b"DeviceGray".to_vec(), 8),
             Self::L16 => (b"DeviceGray".to_vec(), 16),
             Self::CMYK => (b"DeviceCMYK".to_vec(), 8),
+            Self::Lab { .. } => (b"DeviceRGB".to_vec(), 8),
         }
     }
 
-    pub fn from_pdf_format(info: (&[u8], u8)) -> Self {
-        match info {
-            (b"DeviceRGB", 8) => Self::RGB8,
-            (b"DeviceRGB", 16) => Self::RGB16,
-            (b"DeviceGray", 8) => Self::L8,
-            (b"DeviceGray", 16) => Self::L16,
-            (b"DeviceCMYK", 8) => Self::CMYK,
-            _ => unreachable!(),
+    // The color space for a pdf device space name like DeviceRGB and the image's
+    // BitsPerComponent. Other spaces and bit depths are an UnsupportedColorSpace error
+    pub fn from_pdf_format(name: &[u8], bits: u8) -> Result<Self, PDFConError> {
+        match (name, bits) {
+            (b"DeviceRGB", 8) => Ok(Self::RGB8),
+            (b"DeviceRGB", 16) => Ok(Self::RGB16),
+            (b"DeviceGray", 8) => Ok(Self::L8),
+            (b"DeviceGray", 16) => Ok(Self::L16),
+            (b"DeviceCMYK", 8) => Ok(Self::CMYK),
+            (name, bits) => Err(PDFConError::UnsupportedColorSpace(format!(
+                "{} with {} bits per component",
+                String::from_utf8_lossy(name),
+                bits
+            ))),
         }
     }
 
@@ -43,6 +57,7 @@ impl PDFConColorSpace {
             Self::CMYK => image::ExtendedColorType::Cmyk8,
             Self::RGB8 => image::ExtendedColorType::Rgb8,
             Self::RGB16 => image::ExtendedColorType::Rgb16,
+            Self::Lab { .. } => image::ExtendedColorType::Rgb8,
         }
     }
 
@@ -53,80 +68,1494 @@ impl PDFConColorSpace {
             Self::CMYK => 8,
             Self::RGB8 => 8,
             Self::RGB16 => 16,
+            Self::Lab { .. } => 8,
+        }
+    }
+
+    // Size in bytes of a decoded sample buffer. Rows are padded out to a whole byte
+    pub fn data_len(&self, width: u32, height: u32) -> usize {
+        let (components, bits) = match self {
+            Self::L8 => (1, 8),
+            Self::L16 => (1, 16),
+            Self::RGB8 => (3, 8),
+            Self::RGB16 => (3, 16),
+            Self::CMYK => (4, 8),
+            Self::Lab { bits, .. } => (3, *bits as usize),
+        };
+        (width as usize * components * bits).div_ceil(8) * height as usize
+    }
+}
+
+// Bradford cone response matrix and its inverse. Used to adapt from the declared white point to D65
+const BRADFORD: [[f32; 3]; 3] = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+const BRADFORD_INV: [[f32; 3]; 3] = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+const D65: [f32; 3] = [0.95047, 1.0, 1.08883];
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.969266, 1.8760108, 0.041556],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+fn mul_vec(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+        m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+        m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+    ]
+}
+
+fn mul_mat(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, cell) in row.iter_mut().enumerate() {
+            *cell = a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j];
         }
     }
+    out
+}
+
+fn srgb_gamma(linear: f32) -> u8 {
+    let c = linear.clamp(0.0, 1.0);
+    let encoded = if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+// 8 bit sRGB from Lab samples of 8 or 16 bits. white_point and range are the WhitePoint and Range
+// of the Lab color space array
+pub fn lab_to_rgb8(content: &[u8], white_point: [f32; 3], range: [f32; 4], bits: u8) -> Vec<u8> {
+    // Fold the white point adaptation and the sRGB conversion into a single matrix
+    let source = mul_vec(&BRADFORD, white_point);
+    let dest = mul_vec(&BRADFORD, D65);
+    let scale = [
+        [dest[0] / source[0], 0.0, 0.0],
+        [0.0, dest[1] / source[1], 0.0],
+        [0.0, 0.0, dest[2] / source[2]],
+    ];
+    let to_srgb = mul_mat(
+        &XYZ_TO_SRGB,
+        &mul_mat(&BRADFORD_INV, &mul_mat(&scale, &BRADFORD)),
+    );
+
+    let f_inv = |t: f32| {
+        if t > 6.0 / 29.0 {
+            t * t * t
+        } else {
+            3.0 * (6.0 / 29.0) * (6.0 / 29.0) * (t - 4.0 / 29.0)
+        }
+    };
+
+    let samples: Vec<f32> = if bits == 16 {
+        content
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]) as f32 / 65535.0)
+            .collect()
+    } else {
+        content.iter().map(|&c| c as f32 / 255.0).collect()
+    };
+
+    samples
+        .chunks_exact(3)
+        .flat_map(|lab| {
+            let l = lab[0] * 100.0;
+            let a = range[0] + lab[1] * (range[1] - range[0]);
+            let b = range[2] + lab[2] * (range[3] - range[2]);
+
+            let fy = (l + 16.0) / 116.0;
+            let xyz = [
+                white_point[0] * f_inv(fy + a / 500.0),
+                white_point[1] * f_inv(fy),
+                white_point[2] * f_inv(fy - b / 200.0),
+            ];
+            let rgb = mul_vec(&to_srgb, xyz);
+            [srgb_gamma(rgb[0]), srgb_gamma(rgb[1]), srgb_gamma(rgb[2])]
+        })
+        .collect()
+}
+
+// 8 bit RGB from 8 bit CMYK samples. There's no color profile to go by so this is the naive
+// conversion and the colors are approximate
+pub fn cmyk_to_rgb8(content: &[u8]) -> Vec<u8> {
+    content
+        .chunks_exact(4)
+        .flat_map(|px| {
+            let k = 255 - px[3] as u32;
+            [0, 1, 2].map(|i| ((255 - px[i] as u32) * k / 255) as u8)
+        })
+        .collect()
+}
+
+// Decoded image samples as an image that can be saved or worked on. content is the stream after
+// its filters, width and height come from the image dictionary and alpha is an optional 8 bit
+// soft mask of width * height samples. 16 bit samples are big endian like in the pdf. CMYK and
+// Lab come out as 8 bit RGB
+pub fn samples_to_image(
+    content: &[u8],
+    width: u32,
+    height: u32,
+    color_space: &PDFConColorSpace,
+    alpha: Option<&[u8]>,
+) -> Result<image::DynamicImage, PDFConError> {
+    let expected = color_space.data_len(width, height);
+    if content.len() != expected {
+        return Err(PDFConError::PixelDataError(format!(
+            "{}x{} needs {} bytes but got {}",
+            width,
+            height,
+            expected,
+            content.len()
+        )));
+    }
+    if let Some(alpha) = alpha
+        && alpha.len() != width as usize * height as usize
+    {
+        return Err(PDFConError::PixelDataError(format!(
+            "{}x{} needs a {} byte soft mask but got {}",
+            width,
+            height,
+            width as usize * height as usize,
+            alpha.len()
+        )));
+    }
+
+    let wide = |samples: &[u8]| -> Vec<u16> {
+        samples
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect()
+    };
+    let samples = match color_space {
+        PDFConColorSpace::CMYK => SampleBuffer::Rgb8(cmyk_to_rgb8(content)),
+        PDFConColorSpace::Lab {
+            white_point,
+            range,
+            bits,
+        } => SampleBuffer::Rgb8(lab_to_rgb8(content, *white_point, *range, *bits)),
+        PDFConColorSpace::RGB8 => SampleBuffer::Rgb8(content.to_vec()),
+        PDFConColorSpace::L8 => SampleBuffer::L8(content.to_vec()),
+        PDFConColorSpace::RGB16 => SampleBuffer::Rgb16(wide(content)),
+        PDFConColorSpace::L16 => SampleBuffer::L16(wide(content)),
+    };
+
+    // 16 bit samples get the mask scaled up to match
+    let with_alpha = |samples: &[u8], channels: usize, alpha: &[u8]| -> Vec<u8> {
+        samples
+            .chunks_exact(channels)
+            .zip(alpha)
+            .flat_map(|(px, &a)| px.iter().copied().chain([a]))
+            .collect()
+    };
+    let with_alpha16 = |samples: &[u16], channels: usize, alpha: &[u8]| -> Vec<u16> {
+        samples
+            .chunks_exact(channels)
+            .zip(alpha)
+            .flat_map(|(px, &a)| px.iter().copied().chain([a as u16 * 257]))
+            .collect()
+    };
+    let image = match (samples, alpha) {
+        (SampleBuffer::L8(s), None) => {
+            image::GrayImage::from_raw(width, height, s).map(image::DynamicImage::from)
+        }
+        (SampleBuffer::Rgb8(s), None) => {
+            image::RgbImage::from_raw(width, height, s).map(image::DynamicImage::from)
+        }
+        (SampleBuffer::L16(s), None) => {
+            image::ImageBuffer::<image::Luma<u16>, _>::from_raw(width, height, s)
+                .map(image::DynamicImage::from)
+        }
+        (SampleBuffer::Rgb16(s), None) => {
+            image::ImageBuffer::<image::Rgb<u16>, _>::from_raw(width, height, s)
+                .map(image::DynamicImage::from)
+        }
+        (SampleBuffer::L8(s), Some(a)) => {
+            image::GrayAlphaImage::from_raw(width, height, with_alpha(&s, 1, a))
+                .map(image::DynamicImage::from)
+        }
+        (SampleBuffer::Rgb8(s), Some(a)) => {
+            image::RgbaImage::from_raw(width, height, with_alpha(&s, 3, a))
+                .map(image::DynamicImage::from)
+        }
+        (SampleBuffer::L16(s), Some(a)) => image::ImageBuffer::<image::LumaA<u16>, _>::from_raw(
+            width,
+            height,
+            with_alpha16(&s, 1, a),
+        )
+        .map(image::DynamicImage::from),
+        (SampleBuffer::Rgb16(s), Some(a)) => image::ImageBuffer::<image::Rgba<u16>, _>::from_raw(
+            width,
+            height,
+            with_alpha16(&s, 3, a),
+        )
+        .map(image::DynamicImage::from),
+    };
+    // The lengths were checked above so this only guards against a mistake in the conversions
+    image.ok_or_else(|| {
+        PDFConError::PixelDataError(format!(
+            "samples don't make up a {}x{} image",
+            width, height
+        ))
+    })
+}
+
+// Samples after conversion to a layout image can hold
+enum SampleBuffer {
+    L8(Vec<u8>),
+    Rgb8(Vec<u8>),
+    L16(Vec<u16>),
+    Rgb16(Vec<u16>),
 }
 
+// FlateDecode streams should be zlib wrapped but some writers store bare deflate data. Those
+// fail the zlib header check so try again without it
 pub fn decompress(content: &[u8]) -> Result<Vec<u8>, PDFConError> {
+    match inflate(content, true) {
+        Ok(output) => Ok(output),
+        Err(e) if !has_zlib_header(content) => {
+            debug!("Not a zlib stream, trying raw deflate: {}", e);
+            inflate(content, false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn has_zlib_header(content: &[u8]) -> bool {
+    content.len() >= 2
+        && content[0] & 0x0F == 8
+        && (u16::from(content[0]) << 8 | u16::from(content[1])) % 31 == 0
+}
+
+fn inflate(content: &[u8], zlib_header: bool) -> Result<Vec<u8>, PDFConError> {
     let mut output = Vec::new();
     let out_writer = BufWriter::new(&mut output);
 
-    let mut decompress =
-        flate2::write::ZlibDecoder::new_with_decompress(out_writer, flate2::Decompress::new(true));
+    let mut decompress = flate2::write::ZlibDecoder::new_with_decompress(
+        out_writer,
+        flate2::Decompress::new(zlib_header),
+    );
 
-    decompress.write_all(&content)?;
+    decompress.write_all(content)?;
     decompress.flush()?;
     let _ = decompress.finish()?;
 
     Ok(output)
 }
 
-pub fn encode_and_save_png(
+// Samples per pixel, bits per sample and pixels per row from a FlateDecode DecodeParms
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PredictorLayout {
+    pub colors: usize,
+    pub bits: usize,
+    pub columns: usize,
+}
+
+// Undo the TIFF or PNG predictor a stream was encoded with. Pixels can be several bytes wide so
+// each byte is only ever predicted from the same byte of the neighbouring pixel
+pub fn unpredict(
+    content: &[u8],
+    predictor: i64,
+    layout: PredictorLayout,
+) -> Result<Vec<u8>, PDFConError> {
+    let PredictorLayout {
+        colors,
+        bits,
+        columns,
+    } = layout;
+    let bpp = (colors * bits).div_ceil(8).max(1);
+    let row_len = colors
+        .checked_mul(bits)
+        .and_then(|b| b.checked_mul(columns))
+        .map(|b| b.div_ceil(8))
+        .filter(|len| *len > 0)
+        .ok_or_else(|| PDFConError::PredictorError(String::from("rows have no size")))?;
+
+    match predictor {
+        1 => Ok(content.to_vec()),
+        2 => unpredict_tiff(content, row_len, layout),
+        // The PNG predictor number is only a hint. Every row says which filter it used
+        10..=15 => unpredict_png(content, row_len, bpp),
+        _ => Err(PDFConError::PredictorError(format!(
+            "predictor {} isn't defined",
+            predictor
+        ))),
+    }
+}
+
+fn unpredict_png(content: &[u8], row_len: usize, bpp: usize) -> Result<Vec<u8>, PDFConError> {
+    let mut output = Vec::with_capacity(content.len() / (row_len + 1) * row_len);
+    let mut previous = vec![0u8; row_len];
+    // A truncated last row is dropped. It'd only come out as garbage
+    for row in content.chunks_exact(row_len + 1) {
+        let (filter, row) = (row[0], &row[1..]);
+        let mut current = row.to_vec();
+        for i in 0..row_len {
+            let left = if i >= bpp { current[i - bpp] } else { 0 };
+            let up = previous[i];
+            let up_left = if i >= bpp { previous[i - bpp] } else { 0 };
+            let prediction = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((u16::from(left) + u16::from(up)) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => {
+                    return Err(PDFConError::PredictorError(format!(
+                        "unknown png filter type {}",
+                        filter
+                    )));
+                }
+            };
+            current[i] = current[i].wrapping_add(prediction);
+        }
+        output.extend_from_slice(&current);
+        previous = current;
+    }
+    Ok(output)
+}
+
+// TIFF predictor 2 stores every sample as the difference to the same sample one pixel to the left
+fn unpredict_tiff(
+    content: &[u8],
+    row_len: usize,
+    layout: PredictorLayout,
+) -> Result<Vec<u8>, PDFConError> {
+    let colors = layout.colors;
+    let mut output = content.to_vec();
+    for row in output.chunks_exact_mut(row_len) {
+        match layout.bits {
+            8 => {
+                for i in colors..row_len {
+                    row[i] = row[i].wrapping_add(row[i - colors]);
+                }
+            }
+            16 => {
+                for i in colors..row_len / 2 {
+                    let left =
+                        u16::from_be_bytes([row[2 * (i - colors)], row[2 * (i - colors) + 1]]);
+                    let sample = u16::from_be_bytes([row[2 * i], row[2 * i + 1]]);
+                    row[2 * i..2 * i + 2].copy_from_slice(&sample.wrapping_add(left).to_be_bytes());
+                }
+            }
+            bits => {
+                return Err(PDFConError::PredictorError(format!(
+                    "tiff predictor with {} bits per component",
+                    bits
+                )));
+            }
+        }
+    }
+    Ok(output)
+}
+
+// Blend pixels over a solid background using a soft mask. Outputs have no alpha channel so this
+// decides what shows through transparent areas. Only 8 bit gray and rgb are handled
+pub fn composite_background(
+    content: &[u8],
+    color_space: &PDFConColorSpace,
+    alpha: &[u8],
+    background: [u8; 3],
+) -> Option<Vec<u8>> {
+    let blend = |c: u8, bg: u8, a: u8| {
+        ((c as u32 * a as u32 + bg as u32 * (255 - a as u32) + 127) / 255) as u8
+    };
+    match color_space {
+        PDFConColorSpace::RGB8 => Some(
+            content
+                .chunks_exact(3)
+                .zip(alpha)
+                .flat_map(|(px, &a)| {
+                    [
+                        blend(px[0], background[0], a),
+                        blend(px[1], background[1], a),
+                        blend(px[2], background[2], a),
+                    ]
+                })
+                .collect(),
+        ),
+        PDFConColorSpace::L8 => {
+            let [r, g, b] = background.map(u32::from);
+            let luma = ((r * 299 + g * 587 + b * 114 + 500) / 1000) as u8;
+            Some(
+                content
+                    .iter()
+                    .zip(alpha)
+                    .map(|(&c, &a)| blend(c, luma, a))
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+// Undo the blend against the matte color of a soft mask with a Matte entry. Without it the colors
+// of partly transparent pixels still have the matte mixed in and show up as a fringe. matte has
+// one 8 bit sample per component. Same 8 bit gray and rgb as composite_background
+pub fn unpremultiply(
+    content: &[u8],
+    color_space: &PDFConColorSpace,
+    alpha: &[u8],
+    matte: &[u8],
+) -> Option<Vec<u8>> {
+    let channels = match color_space {
+        PDFConColorSpace::RGB8 => 3,
+        PDFConColorSpace::L8 => 1,
+        _ => return None,
+    };
+    if matte.len() != channels {
+        return None;
+    }
+    // c = m + (c' - m) / a. Fully transparent pixels have no color left to recover
+    let recover = |c: u8, m: u8, a: u8| {
+        if a == 0 {
+            return m;
+        }
+        let c = m as f32 + (c as f32 - m as f32) * 255.0 / a as f32;
+        c.round().clamp(0.0, 255.0) as u8
+    };
+    Some(
+        content
+            .chunks_exact(channels)
+            .zip(alpha)
+            .flat_map(|(px, &a)| px.iter().zip(matte).map(move |(&c, &m)| recover(c, m, a)))
+            .collect(),
+    )
+}
+
+// Put a soft mask next to the samples as an alpha channel. Same 8 bit gray and rgb as
+// composite_background. encode_png needs PngOptions::alpha set to write it
+pub fn interleave_alpha(
+    content: &[u8],
+    color_space: &PDFConColorSpace,
+    alpha: &[u8],
+) -> Option<Vec<u8>> {
+    match color_space {
+        PDFConColorSpace::RGB8 => Some(
+            content
+                .chunks_exact(3)
+                .zip(alpha)
+                .flat_map(|(px, &a)| [px[0], px[1], px[2], a])
+                .collect(),
+        ),
+        PDFConColorSpace::L8 => Some(
+            content
+                .iter()
+                .zip(alpha)
+                .flat_map(|(&c, &a)| [c, a])
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+// Turn the samples clockwise by 90, 180 or 270 degrees. Returns them with the new width and height
+pub fn rotate(
     content: &[u8],
     width: u32,
     height: u32,
-    color_space: &PDFConColorSpace,
-    out_path: &PathBuf,
-    optimize: bool,
-) -> Result<(), PDFConError> {
-    let mut encoded = Vec::new();
-    let encoder_writer = BufWriter::new(&mut encoded);
-
-    let encoder = png::PngEncoder::new_with_quality(
-        encoder_writer,
-        png::CompressionType::Best,
-        png::FilterType::Adaptive,
-    );
+    bytes_per_pixel: usize,
+    degrees: u32,
+) -> (Vec<u8>, u32, u32) {
+    let (w, h) = (width as usize, height as usize);
+    let (out_width, out_height) = match degrees {
+        90 | 270 => (h, w),
+        _ => (w, h),
+    };
+    let mut rotated = Vec::with_capacity(content.len());
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let (src_x, src_y) = match degrees {
+                90 => (y, h - 1 - x),
+                180 => (w - 1 - x, h - 1 - y),
+                270 => (w - 1 - y, x),
+                _ => (x, y),
+            };
+            let at = (src_y * w + src_x) * bytes_per_pixel;
+            rotated.extend(&content[at..at + bytes_per_pixel]);
+        }
+    }
+    (rotated, out_width as u32, out_height as u32)
+}
+
+// Palette indexes to the samples they stand for. palette holds components bytes per entry like
+// the lookup of an Indexed color space. Indexes past the end of it are clamped to the last entry
+pub fn apply_palette(indexes: &[u8], palette: &[u8], components: usize) -> Vec<u8> {
+    let entries = palette.len() / components;
+    if entries == 0 {
+        return vec![0; indexes.len() * components];
+    }
+    indexes
+        .iter()
+        .flat_map(|&i| {
+            let at = (i as usize).min(entries - 1) * components;
+            palette[at..at + components].iter().copied()
+        })
+        .collect()
+}
+
+// Crop away borders of one color. The top left pixel picks the color and pixels whose samples are
+// all within fuzz of it count as border. Samples wider than a byte are compared by their high
+// byte. None when there's no border or the whole image is that color
+pub fn trim(
+    content: &[u8],
+    width: u32,
+    height: u32,
+    bytes_per_pixel: usize,
+    sample_bytes: usize,
+    fuzz: u8,
+) -> Option<(Vec<u8>, u32, u32)> {
+    let (w, h) = (width as usize, height as usize);
+    let row_len = w * bytes_per_pixel;
+    if w == 0 || h == 0 || content.len() < row_len * h {
+        return None;
+    }
+    let border = &content[..bytes_per_pixel];
+    let is_border = |x: usize, y: usize| {
+        let at = y * row_len + x * bytes_per_pixel;
+        content[at..at + bytes_per_pixel]
+            .iter()
+            .zip(border)
+            .step_by(sample_bytes)
+            .all(|(&c, &b)| c.abs_diff(b) <= fuzz)
+    };
+    let row_is_border = |y: usize| (0..w).all(|x| is_border(x, y));
+    let column_is_border =
+        |x: usize, rows: std::ops::Range<usize>| rows.into_iter().all(|y| is_border(x, y));
+
+    // Nothing but border. Cropping it would leave no image at all
+    let top = (0..h).find(|&y| !row_is_border(y))?;
+    let bottom = (top..h).rev().find(|&y| !row_is_border(y)).unwrap_or(top) + 1;
+    let left = (0..w)
+        .find(|&x| !column_is_border(x, top..bottom))
+        .unwrap_or(0);
+    let right = (left..w)
+        .rev()
+        .find(|&x| !column_is_border(x, top..bottom))
+        .unwrap_or(left)
+        + 1;
+    if (left, top, right, bottom) == (0, 0, w, h) {
+        return None;
+    }
+
+    let mut trimmed = Vec::with_capacity((right - left) * (bottom - top) * bytes_per_pixel);
+    for y in top..bottom {
+        let at = y * row_len;
+        trimmed.extend(&content[at + left * bytes_per_pixel..at + right * bytes_per_pixel]);
+    }
+    Some((trimmed, (right - left) as u32, (bottom - top) as u32))
+}
+
+// pHash. One bit for each of the 64 lowest frequencies of a 32x32 gray copy, set when it's above
+// their median. Scanner noise sits in the higher frequencies so near identical pages come out a
+// few bits apart. A coefficient also has to clear the median by about two gray levels of swing,
+// otherwise a blank page would get random bits from whatever noise survived the downscale
+pub fn perceptual_hash(image: &image::DynamicImage) -> u64 {
+    const SIZE: usize = 32;
+    const NOISE_FLOOR: f64 = (SIZE * SIZE) as f64;
+    let small = image
+        .resize_exact(
+            SIZE as u32,
+            SIZE as u32,
+            image::imageops::FilterType::Triangle,
+        )
+        .into_luma8();
+    let pixels: Vec<f64> = small.pixels().map(|p| p.0[0] as f64).collect();
+
+    // DCT-II along the rows and then down the columns, only for the frequencies that are kept
+    let basis: Vec<[f64; SIZE]> = (0..8)
+        .map(|u| {
+            std::array::from_fn(|x| {
+                ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * SIZE) as f64).cos()
+            })
+        })
+        .collect();
+    let rows: Vec<[f64; 8]> = pixels
+        .chunks_exact(SIZE)
+        .map(|row| std::array::from_fn(|u| row.iter().zip(&basis[u]).map(|(p, b)| p * b).sum()))
+        .collect();
+    let coefficients: [f64; 64] = std::array::from_fn(|i| {
+        let (v, u) = (i / 8, i % 8);
+        rows.iter().zip(&basis[v]).map(|(r, b)| r[u] * b).sum()
+    });
 
-    encoder.write_image(content, width, height, color_space.into_extended())?;
+    // The first coefficient is the average brightness and would throw the median off
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    coefficients
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c > median + NOISE_FLOOR)
+        .fold(0, |hash, (i, _)| hash | 1 << i)
+}
+
+// How much ink is a lot of ink. The squared coefficient of variation of a profile, so it doesn't
+// depend on how big the page is or how dark its text is
+fn profile_spread(profile: &[usize]) -> f64 {
+    let n = profile.len().max(1) as f64;
+    let mean = profile.iter().sum::<usize>() as f64 / n;
+    if mean == 0.0 {
+        return 0.0;
+    }
+    let variance = profile
+        .iter()
+        .map(|&p| (p as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    variance / (mean * mean)
+}
 
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(out_path)?;
-    let mut writer = BufWriter::new(file);
+// Ascenders are more common than descenders so the ink of a line of text sits below its middle.
+// Positive when that's towards the end of the profile. Weighted by the ink of each line
+fn line_skew(profile: &[usize]) -> Option<f64> {
+    let gap = profile.iter().max()? / 20;
+    let mut lines = Vec::new();
+    let mut start = None;
+    for (i, &ink) in profile.iter().chain([&0]).enumerate() {
+        match (start, ink > gap) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                lines.push(s..i);
+                start = None;
+            }
+            _ => (),
+        }
+    }
 
-    if optimize {
-        let mut options = oxipng::Options::default();
-        options.strip = oxipng::StripChunks::All;
-        writer.write_all(&oxipng::optimize_from_memory(&encoded, &options)?)?;
-        writer.flush()?;
+    let (mut skew, mut total, mut counted) = (0.0, 0.0, 0);
+    for line in lines.into_iter().filter(|l| l.len() >= 4) {
+        let ink: f64 = profile[line.clone()].iter().map(|&p| p as f64).sum();
+        let centroid = line
+            .clone()
+            .map(|i| i as f64 * profile[i] as f64)
+            .sum::<f64>()
+            / ink;
+        let middle = (line.start + line.end - 1) as f64 / 2.0;
+        skew += (centroid - middle) / line.len() as f64 * ink;
+        total += ink;
+        counted += 1;
+    }
+    // A handful of lines isn't enough to go on
+    (counted >= 3).then(|| skew / total)
+}
+
+// Clockwise turn that makes a scanned page of text upright, worked out from its dark pixels. Lines
+// of text make the row sums swing between ink and gaps much more than the column sums, or the
+// other way round when the page lies on its side. Which way up it is comes from line_skew. None
+// unless both are clear so photos and pages with little text are left as they are
+pub fn detect_orientation(image: &image::DynamicImage) -> Option<u32> {
+    // Keeps lines of text apart while staying quick on big scans
+    const MAX_SIDE: u32 = 1200;
+    // How much more one direction has to swing than the other
+    const AXIS_RATIO: f64 = 2.0;
+    // Fraction of a line's height its ink has to sit off the middle
+    const MIN_SKEW: f64 = 0.02;
+
+    let gray = if image.width().max(image.height()) > MAX_SIDE {
+        image
+            .resize(MAX_SIDE, MAX_SIDE, image::imageops::FilterType::Triangle)
+            .into_luma8()
     } else {
-        writer.write_all(&encoded)?;
-        writer.flush()?;
+        image.to_luma8()
+    };
+    let (w, h) = (gray.width() as usize, gray.height() as usize);
+    if w == 0 || h == 0 {
+        return None;
     }
+    // Ink is anything darker than half the page's average brightness
+    let mean = gray.pixels().map(|p| p.0[0] as usize).sum::<usize>() / (w * h);
+    let dark: Vec<bool> = gray
+        .pixels()
+        .map(|p| (p.0[0] as usize) < mean / 2)
+        .collect();
+    let rows: Vec<usize> = dark
+        .chunks_exact(w)
+        .map(|row| row.iter().filter(|d| **d).count())
+        .collect();
+    let columns: Vec<usize> = (0..w)
+        .map(|x| (0..h).filter(|y| dark[y * w + x]).count())
+        .collect();
 
-    Ok(())
+    let (row_spread, column_spread) = (profile_spread(&rows), profile_spread(&columns));
+    let sideways = if row_spread > column_spread * AXIS_RATIO {
+        false
+    } else if column_spread > row_spread * AXIS_RATIO {
+        true
+    } else {
+        return None;
+    };
+    let skew = line_skew(if sideways { &columns } else { &rows })?;
+    if skew.abs() < MIN_SKEW {
+        return None;
+    }
+    // The top of the text is where the ink isn't. Turning clockwise brings the left side up
+    Some(match (sideways, skew > 0.0) {
+        (false, true) => 0,
+        (false, false) => 180,
+        (true, true) => 90,
+        (true, false) => 270,
+    })
 }
 
-pub fn save_jpeg(content: &[u8], out_path: &PathBuf, optimize: bool) -> Result<(), PDFConError> {
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(out_path)?;
-    let mut writer = BufWriter::new(file);
+// Jpegs aren't decoded so they're turned with an Exif orientation tag instead. Viewers apply it
+// and the scan data is left untouched. The tag goes after a JFIF header if there is one
+pub fn embed_orientation(jpeg: &[u8], degrees: u32) -> Vec<u8> {
+    let orientation: u16 = match degrees {
+        90 => 6,
+        180 => 3,
+        270 => 8,
+        _ => return jpeg.to_vec(),
+    };
+    // Big endian TIFF header, then an IFD holding only the orientation as a SHORT
+    let mut exif = b"Exif\0\0MM\0\x2a".to_vec();
+    exif.extend(8u32.to_be_bytes());
+    exif.extend(1u16.to_be_bytes());
+    exif.extend([0x01, 0x12, 0x00, 0x03]);
+    exif.extend(1u32.to_be_bytes());
+    exif.extend(orientation.to_be_bytes());
+    exif.extend([0; 2]);
+    // No next IFD
+    exif.extend([0; 4]);
+
+    let mut at = 2;
+    if jpeg.len() >= 6 && jpeg[2..4] == [0xFF, 0xE0] {
+        at += 2 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+    let at = at.min(jpeg.len());
+
+    let mut out = jpeg[..at].to_vec();
+    out.extend([0xFF, 0xE1]);
+    out.extend((exif.len() as u16 + 2).to_be_bytes());
+    out.extend(exif);
+    out.extend(&jpeg[at..]);
+    out
+}
+
+// Exif data is a small TIFF file. Offsets in it count from the TIFF header
+struct ExifTiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl ExifTiff<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = [*self.data.get(at)?, *self.data.get(at + 1)?];
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    // Where an IFD and everything it points at ends, following the Exif, GPS and
+    // interoperability IFDs inside it
+    fn ifd_end(&self, at: usize, depth: usize) -> Option<usize> {
+        // Deeper than any real Exif. Stops an IFD pointing back at itself
+        const MAX_DEPTH: usize = 4;
+
+        let count = self.u16(at)? as usize;
+        let mut end = at + 2 + count * 12 + 4;
+        if end > self.data.len() || depth > MAX_DEPTH {
+            return None;
+        }
+        for entry in (at + 2..).step_by(12).take(count) {
+            let tag = self.u16(entry)?;
+            let width = match self.u16(entry + 2)? {
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                _ => 1,
+            };
+            let size = width * self.u32(entry + 4)? as usize;
+            let value = self.u32(entry + 8)? as usize;
+            // Values of up to four bytes sit in the entry itself
+            if size > 4 {
+                end = end.max(value.saturating_add(size));
+            }
+            if matches!(tag, 0x8769 | 0x8825 | 0xA005) {
+                end = end.max(self.ifd_end(value, depth + 1)?);
+            }
+        }
+        Some(end)
+    }
+}
+
+// The thumbnail is the second IFD along with the jpeg it points at. Unlinking it is enough for
+// readers. It's cut off too when it comes after everything that's kept, which is where cameras
+// put it
+fn exif_without_thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    let tiff = ExifTiff {
+        data: data.strip_prefix(b"Exif\0\0")?,
+        big_endian: match data.get(6..8)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        },
+    };
+    let first = tiff.u32(4)? as usize;
+    let link = first + 2 + tiff.u16(first)? as usize * 12;
+    let thumbnail = tiff.u32(link)? as usize;
+    if thumbnail == 0 {
+        return None;
+    }
+    let kept = tiff.ifd_end(first, 0)?;
+
+    let mut out = tiff.data.to_vec();
+    out[link..link + 4].fill(0);
+    // JPEGInterchangeFormat, the offset of the thumbnail's jpeg
+    let count = tiff.u16(thumbnail).unwrap_or_default() as usize;
+    let image = (thumbnail + 2..)
+        .step_by(12)
+        .take(count)
+        .find(|entry| tiff.u16(*entry) == Some(0x0201))
+        .and_then(|entry| tiff.u32(entry + 8))
+        .map_or(thumbnail, |offset| offset as usize);
+    let cut = thumbnail.min(image);
+    if kept <= cut {
+        out.truncate(cut);
+    }
+    Some([b"Exif\0\0".as_slice(), &out].concat())
+}
 
-    let content = if optimize {
-        &optimize::optimize_jpeg_mem(content)?
+// A JFIF header can carry an uncompressed thumbnail after its fixed fields
+fn jfif_without_thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(b"JFIF\0") || data.len() < 14 || data[12..14] == [0, 0] {
+        return None;
+    }
+    let mut out = data[..14].to_vec();
+    out[12..14].fill(0);
+    Some(out)
+}
+
+// Drops the thumbnails a jpeg carries in its JFIF and Exif headers. Only those headers change.
+// Every other segment, like the Adobe APP14 that says how to read CMYK, and the scan data are
+// copied over byte for byte. None when there was no thumbnail
+pub fn strip_thumbnails(jpeg: &[u8]) -> Option<Vec<u8>> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = jpeg[..2].to_vec();
+    let mut stripped = false;
+    let mut at = 2;
+    while at + 4 <= jpeg.len() && jpeg[at] == 0xFF {
+        let marker = jpeg[at + 1];
+        // From the start of scan on it's compressed data. Fill bytes and markers without a length
+        // are left for the copy below too
+        if marker == 0xDA || marker == 0xFF || (0xD0..=0xD9).contains(&marker) {
+            break;
+        }
+        let length = u16::from_be_bytes([jpeg[at + 2], jpeg[at + 3]]) as usize;
+        let end = at + 2 + length;
+        if length < 2 || end > jpeg.len() {
+            break;
+        }
+        let data = &jpeg[at + 4..end];
+        let replaced = match marker {
+            // A JFIF extension segment holds nothing but a thumbnail
+            0xE0 if data.starts_with(b"JFXX\0") => Some(Vec::new()),
+            0xE0 => jfif_without_thumbnail(data),
+            0xE1 => exif_without_thumbnail(data),
+            _ => None,
+        };
+        match replaced {
+            Some(data) => {
+                stripped = true;
+                if !data.is_empty() {
+                    out.extend([0xFF, marker]);
+                    out.extend(((data.len() + 2) as u16).to_be_bytes());
+                    out.extend(data);
+                }
+            }
+            None => out.extend(&jpeg[at..end]),
+        }
+        at = end;
+    }
+    if !stripped {
+        return None;
+    }
+    out.extend(&jpeg[at..]);
+    Some(out)
+}
+
+pub fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend((data.len() as u32).to_be_bytes());
+    chunk.extend(kind);
+    chunk.extend(data);
+    let mut crc = flate2::Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    chunk.extend(crc.sum().to_be_bytes());
+    chunk
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
     } else {
-        &content.to_vec()
+        c
+    }
+}
+
+// Filter every row with each png filter and keep whichever has the smallest sum of absolute
+// differences. The same heuristic libpng uses for adaptive filtering
+fn filter_rows(content: &[u8], row_len: usize, bpp: usize) -> Vec<u8> {
+    let mut filtered = Vec::with_capacity(content.len() + content.len() / row_len.max(1) + 1);
+    let zero_row = vec![0u8; row_len];
+    let mut candidate = vec![0u8; row_len];
+    let mut best = vec![0u8; row_len];
+
+    for (index, row) in content.chunks_exact(row_len).enumerate() {
+        let prev = if index == 0 {
+            &zero_row[..]
+        } else {
+            &content[(index - 1) * row_len..index * row_len]
+        };
+
+        let mut best_filter = 0u8;
+        let mut best_score = u64::MAX;
+        for filter in 0..5u8 {
+            for i in 0..row_len {
+                let a = if i >= bpp { row[i - bpp] } else { 0 };
+                let b = prev[i];
+                let c = if i >= bpp { prev[i - bpp] } else { 0 };
+                candidate[i] = match filter {
+                    0 => row[i],
+                    1 => row[i].wrapping_sub(a),
+                    2 => row[i].wrapping_sub(b),
+                    3 => row[i].wrapping_sub(((a as u16 + b as u16) / 2) as u8),
+                    _ => row[i].wrapping_sub(paeth(a, b, c)),
+                };
+            }
+            let score = candidate
+                .iter()
+                .map(|&v| (v as i8).unsigned_abs() as u64)
+                .sum();
+            if score < best_score {
+                best_score = score;
+                best_filter = filter;
+                best.copy_from_slice(&candidate);
+            }
+        }
+
+        filtered.push(best_filter);
+        filtered.extend_from_slice(&best);
+    }
+    filtered
+}
+
+// The png color type, bit depth and channel count for samples of this type
+fn png_color_type(color_type: image::ExtendedColorType) -> Option<(u8, u8, usize)> {
+    match color_type {
+        image::ExtendedColorType::L8 => Some((0, 8, 1)),
+        image::ExtendedColorType::L16 => Some((0, 16, 1)),
+        image::ExtendedColorType::Rgb8 => Some((2, 8, 3)),
+        image::ExtendedColorType::Rgb16 => Some((2, 16, 3)),
+        image::ExtendedColorType::La8 => Some((4, 8, 2)),
+        image::ExtendedColorType::Rgba8 => Some((6, 8, 4)),
+        _ => None,
+    }
+}
+
+// Whether encode_png can write samples in this color space. Png has no CMYK
+pub fn png_can_write(color_space: &PDFConColorSpace) -> bool {
+    png_color_type(color_space.into_extended()).is_some()
+}
+
+// Encode a png with an exact deflate level. The image encoder only exposes fast, default and best
+pub fn encode_png_with_level(
+    content: &[u8],
+    width: u32,
+    height: u32,
+    color_type: image::ExtendedColorType,
+    level: u32,
+) -> Result<Vec<u8>, PDFConError> {
+    let Some((png_color, bit_depth, channels)) = png_color_type(color_type) else {
+        return Err(PDFConError::UnsupportedColorSpace(format!(
+            "{:?} can't be written as a png",
+            color_type
+        )));
+    };
+    let bpp = channels * bit_depth as usize / 8;
+    let row_len = width as usize * bpp;
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend(width.to_be_bytes());
+    ihdr.extend(height.to_be_bytes());
+    ihdr.extend([bit_depth, png_color, 0, 0, 0]);
+
+    let filtered = filter_rows(content, row_len, bpp);
+    let idat = compress_zlib(filtered, flate2::Compression::new(level))?;
+
+    let mut encoded = b"\x89PNG\r\n\x1a\n".to_vec();
+    encoded.extend(png_chunk(b"IHDR", &ihdr));
+    encoded.extend(png_chunk(b"IDAT", &idat));
+    encoded.extend(png_chunk(b"IEND", &[]));
+    Ok(encoded)
+}
+
+// A run of distinct colors and how many pixels use each. Median cut splits these until there are
+// as many as the palette has room for
+struct ColorBox {
+    colors: Vec<([u8; 3], u32)>,
+}
+
+impl ColorBox {
+    // The channel with the widest spread and how wide it is
+    fn widest(&self) -> (usize, u8) {
+        (0..3)
+            .map(|c| {
+                let (low, high) = self
+                    .colors
+                    .iter()
+                    .fold((255, 0), |(l, h), (px, _)| (px[c].min(l), px[c].max(h)));
+                (c, high - low)
+            })
+            .max_by_key(|&(_, spread)| spread)
+            .unwrap_or((0, 0))
+    }
+
+    // Sorted along the widest channel and cut where half the pixels fall on each side
+    fn split(mut self) -> (Self, Self) {
+        let (channel, _) = self.widest();
+        self.colors.sort_unstable_by_key(|(px, _)| px[channel]);
+        let total: u64 = self.colors.iter().map(|&(_, n)| n as u64).sum();
+        let mut seen = 0;
+        let cut = self
+            .colors
+            .iter()
+            .position(|&(_, n)| {
+                seen += n as u64;
+                seen * 2 >= total
+            })
+            .map_or(1, |p| p + 1)
+            .clamp(1, self.colors.len() - 1);
+        let rest = self.colors.split_off(cut);
+        (self, Self { colors: rest })
+    }
+
+    // Weighted by pixel count so a few stray colors don't pull the entry away from the bulk
+    fn average(&self) -> [u8; 3] {
+        let total: u64 = self.colors.iter().map(|&(_, n)| n as u64).sum();
+        let mut sums = [0u64; 3];
+        for (px, n) in &self.colors {
+            for c in 0..3 {
+                sums[c] += px[c] as u64 * *n as u64;
+            }
+        }
+        sums.map(|s| ((s + total / 2) / total.max(1)) as u8)
+    }
+}
+
+// Reduce 8 bit gray or RGB samples to at most colors palette entries by median cut. Returns the
+// palette and one index per pixel
+pub fn quantize(content: &[u8], channels: usize, colors: usize) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let pixel = |px: &[u8]| match channels {
+        1 => [px[0]; 3],
+        _ => [px[0], px[1], px[2]],
     };
-    writer.write_all(&content)?;
-    writer.flush()?;
+    let mut counts: std::collections::HashMap<[u8; 3], u32> = std::collections::HashMap::new();
+    for px in content.chunks_exact(channels) {
+        *counts.entry(pixel(px)).or_default() += 1;
+    }
+
+    let mut boxes = vec![ColorBox {
+        colors: counts.into_iter().collect(),
+    }];
+    while boxes.len() < colors.clamp(1, 256) {
+        let Some(widest) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.colors.len() > 1)
+            .max_by_key(|(_, b)| b.widest().1)
+            .map(|(i, _)| i)
+        else {
+            // Every box is down to one color so the image already fits
+            break;
+        };
+        let (low, high) = boxes.swap_remove(widest).split();
+        boxes.push(low);
+        boxes.push(high);
+    }
+
+    let mut lookup = std::collections::HashMap::new();
+    let palette = boxes
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            for (px, _) in &b.colors {
+                lookup.insert(*px, i as u8);
+            }
+            b.average()
+        })
+        .collect();
+    let indices = content
+        .chunks_exact(channels)
+        .map(|px| lookup[&pixel(px)])
+        .collect();
+    (palette, indices)
+}
+
+// Write palette indices as an indexed png. Small palettes pack several pixels into each byte
+pub fn encode_indexed_png(
+    indices: &[u8],
+    palette: &[[u8; 3]],
+    width: u32,
+    height: u32,
+    level: u32,
+) -> Result<Vec<u8>, PDFConError> {
+    let bit_depth: u8 = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let per_byte = 8 / bit_depth as usize;
+    let row_len = (width as usize).div_ceil(per_byte);
+
+    // Palettes compress best unfiltered so every row gets filter type 0
+    let mut rows = Vec::with_capacity((row_len + 1) * height as usize);
+    for row in indices.chunks_exact(width.max(1) as usize) {
+        rows.push(0);
+        for packed in row.chunks(per_byte) {
+            let byte = packed.iter().enumerate().fold(0u8, |byte, (i, &index)| {
+                byte | (index << (8 - bit_depth as usize * (i + 1)))
+            });
+            rows.push(byte);
+        }
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend(width.to_be_bytes());
+    ihdr.extend(height.to_be_bytes());
+    ihdr.extend([bit_depth, 3, 0, 0, 0]);
+
+    let plte: Vec<u8> = palette.iter().flatten().copied().collect();
+    let idat = compress_zlib(rows, flate2::Compression::new(level))?;
+
+    let mut encoded = b"\x89PNG\r\n\x1a\n".to_vec();
+    encoded.extend(png_chunk(b"IHDR", &ihdr));
+    encoded.extend(png_chunk(b"PLTE", &plte));
+    encoded.extend(png_chunk(b"IDAT", &idat));
+    encoded.extend(png_chunk(b"IEND", &[]));
+    Ok(encoded)
+}
+
+// Where an extracted image came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Provenance {
+    pub source: String,
+    pub page: u32,
+    pub object: (u32, u16),
+}
+
+impl Provenance {
+    fn entries(&self) -> [(&'static str, String); 3] {
+        [
+            ("Source", self.source.clone()),
+            ("Page", self.page.to_string()),
+            ("Object", format!("{} {} R", self.object.0, self.object.1)),
+        ]
+    }
+
+    // One tEXt chunk per entry. tEXt is latin-1 only so anything else goes in an iTXt chunk
+    fn png_chunks(&self) -> Vec<u8> {
+        let mut chunks = Vec::new();
+        for (keyword, text) in self.entries() {
+            let mut data = keyword.as_bytes().to_vec();
+            data.push(0);
+            if text.is_ascii() {
+                data.extend(text.as_bytes());
+                chunks.extend(png_chunk(b"tEXt", &data));
+            } else {
+                // Uncompressed with empty language and translated keyword
+                data.extend([0, 0, 0, 0]);
+                data.extend(text.as_bytes());
+                chunks.extend(png_chunk(b"iTXt", &data));
+            }
+        }
+        chunks
+    }
+
+    fn jpeg_comment(&self) -> Vec<u8> {
+        let text = self
+            .entries()
+            .iter()
+            .map(|(keyword, text)| format!("{keyword}: {text}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        // The segment length covers itself and can't go past u16
+        let text = &text.as_bytes()[..text.len().min(u16::MAX as usize - 2)];
+
+        let mut segment = vec![0xFF, 0xFE];
+        segment.extend(((text.len() + 2) as u16).to_be_bytes());
+        segment.extend(text);
+        segment
+    }
+
+    // Text chunks go straight after IHDR which is always the first chunk
+    pub fn embed_png(&self, png: &[u8]) -> Vec<u8> {
+        let ihdr_end = (8 + 25).min(png.len());
+        let mut out = png[..ihdr_end].to_vec();
+        out.extend(self.png_chunks());
+        out.extend(&png[ihdr_end..]);
+        out
+    }
+
+    // The comment goes after any APPn segments so JFIF and Exif headers stay up front
+    pub fn embed_jpeg(&self, jpeg: &[u8]) -> Vec<u8> {
+        let mut at = 2;
+        while at + 4 <= jpeg.len() && jpeg[at] == 0xFF && (0xE0..=0xEF).contains(&jpeg[at + 1]) {
+            at += 2 + u16::from_be_bytes([jpeg[at + 2], jpeg[at + 3]]) as usize;
+        }
+        let at = at.min(jpeg.len());
+
+        let mut out = jpeg[..at].to_vec();
+        out.extend(self.jpeg_comment());
+        out.extend(&jpeg[at..]);
+        out
+    }
+}
+
+// How encode_png writes out its file
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PngOptions<'a> {
+    pub optimize: bool,
+    pub compression: Option<u32>,
+    pub provenance: Option<&'a Provenance>,
+    // The samples come from interleave_alpha and end in an alpha channel
+    pub alpha: bool,
+    // --quantize. Reduce to this many colors and write an indexed png
+    pub quantize: Option<usize>,
+    // --bit-depth 8. 16 bit samples are scaled down before encoding, dithered if asked
+    pub eight_bit: bool,
+    pub dither: bool,
+}
+
+// 16 bit samples, big endian like in the pdf, scaled to the nearest 8 bit value. dither moves
+// where each sample rounds by a 4x4 Bayer pattern so smooth gradients don't turn into bands
+pub fn to_eight_bit(content: &[u8], width: u32, channels: usize, dither: bool) -> Vec<u8> {
+    const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+    let width = width.max(1) as usize;
+    content
+        .chunks_exact(2)
+        .enumerate()
+        .map(|(index, sample)| {
+            let value = u16::from_be_bytes([sample[0], sample[1]]) as f32 * 255.0 / 65535.0;
+            let offset = if dither {
+                let pixel = index / channels;
+                let (x, y) = (pixel % width, pixel / width);
+                (BAYER[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5
+            } else {
+                0.0
+            };
+            (value + offset).round().clamp(0.0, 255.0) as u8
+        })
+        .collect()
+}
+
+// The finished png file. Writing it is up to the caller so it can stay in memory
+pub fn encode_png(
+    content: &[u8],
+    width: u32,
+    height: u32,
+    color_space: &PDFConColorSpace,
+    options: PngOptions,
+) -> Result<Vec<u8>, PDFConError> {
+    let reduced;
+    let (content, color_space) = match color_space {
+        PDFConColorSpace::L16 if options.eight_bit => {
+            reduced = to_eight_bit(content, width, 1, options.dither);
+            (&reduced[..], &PDFConColorSpace::L8)
+        }
+        PDFConColorSpace::RGB16 if options.eight_bit => {
+            reduced = to_eight_bit(content, width, 3, options.dither);
+            (&reduced[..], &PDFConColorSpace::RGB8)
+        }
+        _ => (content, color_space),
+    };
+
+    let converted;
+    let content = match color_space {
+        PDFConColorSpace::Lab {
+            white_point,
+            range,
+            bits,
+        } => {
+            converted = lab_to_rgb8(content, *white_point, *range, *bits);
+            &converted[..]
+        }
+        _ => content,
+    };
+
+    let color_type = match (color_space, options.alpha) {
+        (PDFConColorSpace::RGB8, true) => image::ExtendedColorType::Rgba8,
+        (PDFConColorSpace::L8, true) => image::ExtendedColorType::La8,
+        _ => color_space.into_extended(),
+    };
+
+    let channels = match color_type {
+        image::ExtendedColorType::L8 => Some(1),
+        image::ExtendedColorType::Rgb8 => Some(3),
+        _ => None,
+    };
+    if options.quantize.is_some() && channels.is_none() {
+        debug!("Not quantizing {:?} samples", color_type);
+    }
+
+    let encoded = match (options.quantize.zip(channels), options.compression) {
+        (Some((colors, channels)), level) => {
+            let (palette, indices) = quantize(content, channels, colors);
+            encode_indexed_png(&indices, &palette, width, height, level.unwrap_or(9))?
+        }
+        (None, Some(level)) => encode_png_with_level(content, width, height, color_type, level)?,
+        (None, None) => {
+            let mut encoded = Vec::new();
+            let encoder_writer = BufWriter::new(&mut encoded);
+
+            let encoder = png::PngEncoder::new_with_quality(
+                encoder_writer,
+                png::CompressionType::Best,
+                png::FilterType::Adaptive,
+            );
+            encoder.write_image(content, width, height, color_type)?;
+            encoded
+        }
+    };
+
+    // oxipng strips every ancillary chunk so provenance is added after it has run
+    let encoded = if options.optimize {
+        let oxipng_options = oxipng::Options {
+            strip: oxipng::StripChunks::All,
+            ..Default::default()
+        };
+        oxipng::optimize_from_memory(&encoded, &oxipng_options)?
+    } else {
+        encoded
+    };
+    Ok(match options.provenance {
+        Some(p) => p.embed_png(&encoded),
+        None => encoded,
+    })
+}
+
+// The one channel of a jpeg as 8 bit samples, with its width and height
+pub fn decode_gray_jpeg(content: &[u8]) -> Result<(Vec<u8>, u32, u32), PDFConError> {
+    let image = image::load_from_memory_with_format(content, image::ImageFormat::Jpeg)?;
+    if image.color().channel_count() != 1 {
+        return Err(PDFConError::UnsupportedColorSpace(format!(
+            "Indexed over a jpeg with {} components",
+            image.color().channel_count()
+        )));
+    }
+    let (width, height) = (image.width(), image.height());
+    Ok((image.into_luma8().into_raw(), width, height))
+}
+
+// A jpeg has to open with a start of image marker and close with an end of image marker. Some
+// writers pad the stream after the end marker so trailing whitespace and nulls are ignored
+pub fn validate_jpeg(content: &[u8]) -> Result<(), PDFConError> {
+    if !content.starts_with(&[0xFF, 0xD8]) {
+        return Err(PDFConError::CorruptJpegError(
+            "missing start of image marker".to_string(),
+        ));
+    }
+
+    let end = content
+        .iter()
+        .rposition(|b| !matches!(b, 0x00 | b'\r' | b'\n' | b' '))
+        .map_or(0, |p| p + 1);
+    if !content[..end].ends_with(&[0xFF, 0xD9]) {
+        return Err(PDFConError::CorruptJpegError(
+            "truncated before the end of image marker".to_string(),
+        ));
+    }
 
     Ok(())
 }
 
+// The jpeg as it should be written, checked and optionally optimized. out_path only names it in
+// warnings since the caller does the writing
+pub fn finish_jpeg(
+    content: &[u8],
+    out_path: &Path,
+    optimize: bool,
+    salvage: bool,
+    provenance: Option<&Provenance>,
+    rotation: u32,
+    strip: bool,
+) -> Result<Vec<u8>, PDFConError> {
+    let valid = match validate_jpeg(content) {
+        Ok(()) => true,
+        Err(e) if salvage => {
+            warn!("Writing damaged jpeg {}: {}", out_path.to_string_lossy(), e);
+            false
+        }
+        Err(e) => return Err(e),
+    };
+
+    let thumbless;
+    let content = match strip.then(|| strip_thumbnails(content)).flatten() {
+        Some(stripped) => {
+            debug!(
+                "Stripped {} bytes of thumbnails from {}",
+                content.len() - stripped.len(),
+                out_path.to_string_lossy()
+            );
+            thumbless = stripped;
+            &thumbless[..]
+        }
+        None => content,
+    };
+
+    // Don't hand a damaged stream to mozjpeg. Write out whatever we have instead
+    let optimized;
+    let content = if optimize && valid {
+        optimized = optimize::optimize_jpeg_mem(content)?;
+        &optimized[..]
+    } else {
+        content
+    };
+    // mozjpeg drops markers it doesn't know so the orientation goes in afterwards
+    let oriented;
+    let content = if rotation != 0 && valid {
+        oriented = embed_orientation(content, rotation);
+        &oriented[..]
+    } else {
+        content
+    };
+    Ok(match provenance {
+        // A damaged stream might not have a usable header to put the comment after
+        Some(p) if valid => p.embed_jpeg(content),
+        _ => content.to_vec(),
+    })
+}
+
 // Unless
 impl From<mozjpeg::ColorSpace> for PDFConColorSpace {
     fn from(c: mozjpeg::ColorSpace) -> Self {
@@ -170,7 +1599,7 @@ pub mod optimize {
     use crate::error::PDFConError;
     use flate2::Compression;
     use image::{self, ColorType};
-    use log::error;
+    use log::{debug, error};
     use mozjpeg;
     use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
 
@@ -179,7 +1608,11 @@ pub mod optimize {
         JPEG(Vec<u8>, u32, u32, PDFConColorSpace),
     }
 
-    pub fn process_png_optimized(file: std::fs::File) -> Result<ImageData, PDFConError> {
+    // compression is the deflate level the samples are stored with
+    pub fn process_png_optimized(
+        file: std::fs::File,
+        compression: Compression,
+    ) -> Result<ImageData, PDFConError> {
         let reader = BufReader::new(file);
 
         let png_reader = image::ImageReader::with_format(reader, image::ImageFormat::Png);
@@ -192,7 +1625,7 @@ pub mod optimize {
                 let width = temp.width();
                 let height = temp.height();
                 // Compress the buffer
-                let compressed = compress_zlib(temp.to_vec(), Compression::best())?;
+                let compressed = compress_zlib(temp.to_vec(), compression)?;
                 Ok(ImageData::PNG(
                     compressed,
                     width,
@@ -210,7 +1643,7 @@ pub mod optimize {
                         .iter()
                         .flat_map(|&x| x.to_be_bytes())
                         .collect(),
-                    Compression::best(),
+                    compression,
                 )?;
                 Ok(ImageData::PNG(
                     compressed,
@@ -224,7 +1657,7 @@ pub mod optimize {
                 let width = temp.width();
                 let height = temp.height();
                 // Compress the buffer
-                let compressed = compress_zlib(temp.to_vec(), Compression::best())?;
+                let compressed = compress_zlib(temp.to_vec(), compression)?;
                 Ok(ImageData::PNG(
                     compressed,
                     width,
@@ -242,7 +1675,7 @@ pub mod optimize {
                         .iter()
                         .flat_map(|&x| x.to_be_bytes())
                         .collect(),
-                    Compression::best(),
+                    compression,
                 )?;
                 Ok(ImageData::PNG(
                     compressed,
@@ -262,7 +1695,7 @@ pub mod optimize {
                         .iter()
                         .flat_map(|&x| x.to_be_bytes())
                         .collect(),
-                    Compression::best(),
+                    compression,
                 )?;
                 Ok(ImageData::PNG(
                     compressed,
@@ -283,7 +1716,8 @@ pub mod optimize {
         {
             Ok(d) => d,
             Err(e) => {
-                error!("Decompress err: {}", e.to_string());
+                // Runs inside the unpack workers. The caller logs the failure once the bar is closed
+                debug!("Decompress err: {}", e);
                 return Err(PDFConError::MozDecompressBufferError);
             }
         };
@@ -349,7 +1783,7 @@ pub mod optimize {
             {
                 Ok(d) => d,
                 Err(e) => {
-                    error!("Decompress err: {}", e.to_string());
+                    error!("Decompress err: {}", e);
                     return Err(PDFConError::MozDecompressBufferError);
                 }
             };
@@ -447,3 +1881,122 @@ pub mod optimize {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every byte differs from its neighbours so a predictor that reads the wrong one shows up
+    fn samples(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + i / 7) as u8).collect()
+    }
+
+    fn png_predict(content: &[u8], row_len: usize, bpp: usize, filter: u8) -> Vec<u8> {
+        let mut predicted = Vec::new();
+        let mut previous = vec![0u8; row_len];
+        for row in content.chunks_exact(row_len) {
+            predicted.push(filter);
+            for i in 0..row_len {
+                let left = if i >= bpp { row[i - bpp] } else { 0 };
+                let up_left = if i >= bpp { previous[i - bpp] } else { 0 };
+                let prediction = match filter {
+                    0 => 0,
+                    1 => left,
+                    2 => previous[i],
+                    3 => ((u16::from(left) + u16::from(previous[i])) / 2) as u8,
+                    _ => paeth(left, previous[i], up_left),
+                };
+                predicted.push(row[i].wrapping_sub(prediction));
+            }
+            previous = row.to_vec();
+        }
+        predicted
+    }
+
+    fn tiff_predict(content: &[u8], row_len: usize, layout: PredictorLayout) -> Vec<u8> {
+        let colors = layout.colors;
+        let mut predicted = content.to_vec();
+        for (row, original) in predicted
+            .chunks_exact_mut(row_len)
+            .zip(content.chunks_exact(row_len))
+        {
+            if layout.bits == 8 {
+                for i in colors..row_len {
+                    row[i] = original[i].wrapping_sub(original[i - colors]);
+                }
+            } else {
+                for i in colors..row_len / 2 {
+                    let sample =
+                        |j: usize| u16::from_be_bytes([original[2 * j], original[2 * j + 1]]);
+                    let delta = sample(i).wrapping_sub(sample(i - colors));
+                    row[2 * i..2 * i + 2].copy_from_slice(&delta.to_be_bytes());
+                }
+            }
+        }
+        predicted
+    }
+
+    const RGB8: PredictorLayout = PredictorLayout {
+        colors: 3,
+        bits: 8,
+        columns: 5,
+    };
+    const GRAY16: PredictorLayout = PredictorLayout {
+        colors: 1,
+        bits: 16,
+        columns: 5,
+    };
+
+    #[test]
+    fn png_predictor_round_trips_three_color_eight_bit() {
+        let content = samples(15 * 4);
+        for filter in 0..5 {
+            let predicted = png_predict(&content, 15, 3, filter);
+            assert_eq!(unpredict(&predicted, 15, RGB8).unwrap(), content);
+        }
+        let adaptive = filter_rows(&content, 15, 3);
+        assert_eq!(unpredict(&adaptive, 10, RGB8).unwrap(), content);
+    }
+
+    #[test]
+    fn png_predictor_round_trips_one_color_sixteen_bit() {
+        let content = samples(10 * 4);
+        for filter in 0..5 {
+            let predicted = png_predict(&content, 10, 2, filter);
+            assert_eq!(unpredict(&predicted, 12, GRAY16).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn tiff_predictor_round_trips_three_color_eight_bit() {
+        let content = samples(15 * 4);
+        let predicted = tiff_predict(&content, 15, RGB8);
+        assert_eq!(unpredict(&predicted, 2, RGB8).unwrap(), content);
+    }
+
+    #[test]
+    fn tiff_predictor_round_trips_one_color_sixteen_bit() {
+        let content = samples(10 * 4);
+        let predicted = tiff_predict(&content, 10, GRAY16);
+        assert_eq!(unpredict(&predicted, 2, GRAY16).unwrap(), content);
+    }
+
+    #[test]
+    fn decompress_falls_back_to_raw_deflate() {
+        let content = samples(4096);
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&content).unwrap();
+        let deflated = encoder.finish().unwrap();
+        assert!(!has_zlib_header(&deflated));
+        assert_eq!(decompress(&deflated).unwrap(), content);
+    }
+
+    #[test]
+    fn decompress_reads_zlib() {
+        let content = samples(4096);
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&content).unwrap();
+        assert_eq!(decompress(&encoder.finish().unwrap()).unwrap(), content);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::error::PDFConError;
+use log::warn;
+use lopdf::{Document, Object, Stream, StringFormat, dictionary};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Packed documents are image only so PDF/A-2b mostly comes down to metadata and a color intent.
+// Images are written in device spaces so an sRGB output intent covers DeviceRGB and DeviceGray.
+// DeviceCMYK would need a CMYK intent which we don't ship
+
+const PRODUCER: &str = concat!("pdfcon ", env!("CARGO_PKG_VERSION"));
+const OUTPUT_CONDITION: &str = "sRGB IEC61966-2.1";
+
+// Broken down UTC time. Avoids pulling in a date crate for two timestamps
+struct Timestamp {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+}
+
+impl Timestamp {
+    fn now() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        // Civil from days. See http://howardhinnant.github.io/date_algorithms.html
+        let days = (secs / 86400) as i64 + 719468;
+        let era = days.div_euclid(146097);
+        let doe = days.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs % 86400) / 3600,
+            minute: (secs % 3600) / 60,
+            second: secs % 60,
+        }
+    }
+
+    fn to_pdf(&self) -> String {
+        format!(
+            "D:{:04}{:02}{:02}{:02}{:02}{:02}+00'00'",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+
+    fn to_xmp(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for v in xyz {
+        tag.extend(s15_fixed16(v));
+    }
+    tag
+}
+
+// Minimal ICC v2 display profile for sRGB. Primaries are the D50 adapted values from the sRGB spec
+pub fn srgb_icc_profile() -> Vec<u8> {
+    let d50 = [0.9642, 1.0, 0.8249];
+
+    let mut desc = b"desc\0\0\0\0".to_vec();
+    desc.extend(((OUTPUT_CONDITION.len() + 1) as u32).to_be_bytes());
+    desc.extend(OUTPUT_CONDITION.as_bytes());
+    desc.push(0);
+    // Empty unicode and scriptcode descriptions
+    desc.extend([0u8; 4 + 4 + 2 + 1 + 67]);
+
+    let mut cprt = b"text\0\0\0\0".to_vec();
+    cprt.extend(b"No copyright, use freely");
+    cprt.push(0);
+
+    let mut curve = b"curv\0\0\0\0".to_vec();
+    let entries = 1024u32;
+    curve.extend(entries.to_be_bytes());
+    for i in 0..entries {
+        let c = i as f64 / (entries - 1) as f64;
+        let linear = if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        };
+        curve.extend(((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+
+    let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", desc),
+        (b"cprt", cprt),
+        (b"wtpt", xyz_tag(d50)),
+        (b"rXYZ", xyz_tag([0.4361, 0.2225, 0.0139])),
+        (b"gXYZ", xyz_tag([0.3851, 0.7169, 0.0971])),
+        (b"bXYZ", xyz_tag([0.1431, 0.0606, 0.7141])),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+
+    // Tag data starts after the 128 byte header and the tag table. Every tag is 4 byte aligned
+    let mut offset = 128 + 4 + tags.len() * 12;
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    for (signature, tag) in &tags {
+        table.extend(*signature);
+        table.extend((offset as u32).to_be_bytes());
+        table.extend((tag.len() as u32).to_be_bytes());
+        data.extend(tag);
+        let padding = (4 - tag.len() % 4) % 4;
+        data.extend(std::iter::repeat_n(0u8, padding));
+        offset += tag.len() + padding;
+    }
+
+    let now = Timestamp::now();
+    let mut header = Vec::with_capacity(128);
+    header.extend(((128 + table.len() + data.len()) as u32).to_be_bytes());
+    header.extend([0u8; 4]);
+    header.extend(0x0210_0000u32.to_be_bytes());
+    header.extend(b"mntrRGB XYZ ");
+    for part in [
+        now.year as u64,
+        now.month as u64,
+        now.day as u64,
+        now.hour,
+        now.minute,
+        now.second,
+    ] {
+        header.extend((part as u16).to_be_bytes());
+    }
+    header.extend(b"acsp");
+    // Platform, flags, manufacturer, model, attributes and a perceptual rendering intent
+    header.extend([0u8; 4 + 4 + 4 + 4 + 8 + 4]);
+    for v in d50 {
+        header.extend(s15_fixed16(v));
+    }
+    // Creator, profile id and reserved bytes
+    header.extend([0u8; 4 + 16 + 28]);
+
+    header.extend(table);
+    header.extend(data);
+    header
+}
+
+fn xmp_metadata(now: &Timestamp) -> Vec<u8> {
+    format!(
+        r#"<?xpacket begin="{}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:pdf="http://ns.adobe.com/pdf/1.3/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/">
+   <pdfaid:part>2</pdfaid:part>
+   <pdfaid:conformance>B</pdfaid:conformance>
+   <xmp:CreateDate>{}</xmp:CreateDate>
+   <xmp:ModifyDate>{}</xmp:ModifyDate>
+   <xmp:CreatorTool>{}</xmp:CreatorTool>
+   <pdf:Producer>{}</pdf:Producer>
+   <dc:format>application/pdf</dc:format>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        '\u{feff}',
+        now.to_xmp(),
+        now.to_xmp(),
+        PRODUCER,
+        PRODUCER
+    )
+    .into_bytes()
+}
+
+fn document_id(doc: &Document) -> Vec<u8> {
+    let mut id = Vec::with_capacity(16);
+    for salt in [0u8, 1u8] {
+        let mut hasher = DefaultHasher::new();
+        salt.hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        std::process::id().hash(&mut hasher);
+        doc.objects.len().hash(&mut hasher);
+        id.extend(hasher.finish().to_be_bytes());
+    }
+    id
+}
+
+// Add the structures PDF/A-2b needs on top of a document from build_document
+pub fn apply(doc: &mut Document) -> Result<(), PDFConError> {
+    let cmyk_images = doc
+        .objects
+        .values()
+        .filter_map(|o| o.as_stream().ok())
+        .filter(|s| {
+            s.dict
+                .get(b"ColorSpace")
+                .and_then(|c| c.as_name())
+                .is_ok_and(|c| c == b"DeviceCMYK")
+        })
+        .count();
+    if cmyk_images > 0 {
+        warn!(
+            "{} CMYK images can't be covered by the sRGB output intent. The output won't be PDF/A compliant",
+            cmyk_images
+        );
+    }
+
+    let now = Timestamp::now();
+
+    let icc_id = doc.add_object(Stream::new(
+        dictionary! { "N" => 3 },
+        srgb_icc_profile(),
+    ));
+    let output_intent = dictionary! {
+        "Type" => "OutputIntent",
+        "S" => "GTS_PDFA1",
+        "OutputConditionIdentifier" => Object::string_literal(OUTPUT_CONDITION),
+        "Info" => Object::string_literal(OUTPUT_CONDITION),
+        "DestOutputProfile" => icc_id,
+    };
+
+    let metadata_id = doc.add_object(Stream::new(
+        dictionary! {
+            "Type" => "Metadata",
+            "Subtype" => "XML",
+        },
+        xmp_metadata(&now),
+    ));
+
+    let catalog_id = doc.trailer.get(b"Root")?.as_reference()?;
+    let catalog = doc.get_dictionary_mut(catalog_id)?;
+    catalog.set("Metadata", metadata_id);
+    catalog.set("OutputIntents", vec![Object::Dictionary(output_intent)]);
+
+    let info_id = doc.add_object(dictionary! {
+        "Producer" => Object::string_literal(PRODUCER),
+        "Creator" => Object::string_literal(PRODUCER),
+        "CreationDate" => Object::string_literal(now.to_pdf()),
+        "ModDate" => Object::string_literal(now.to_pdf()),
+    });
+    doc.trailer.set("Info", info_id);
+
+    let id = document_id(doc);
+    doc.trailer.set(
+        "ID",
+        vec![
+            Object::String(id.clone(), StringFormat::Hexadecimal),
+            Object::String(id, StringFormat::Hexadecimal),
+        ],
+    );
+
+    Ok(())
+}
@@ -0,0 +1,310 @@
+use crate::Run;
+use crate::constants::tick_speed;
+use crate::error::PDFConError;
+use crate::info::report;
+use crate::progress::{close_bar, spinner};
+use crate::unpack::samples_supported;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::PathBuf;
+
+// Filters unpack decodes. Anything else in a chain is passed over and the image comes out wrong
+const FILTERS: [&[u8]; 2] = [b"FlateDecode", b"DCTDecode"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Prescan {
+    pub in_file: PathBuf,
+}
+
+// Images that use something and how many of those unpack can write
+#[derive(Default)]
+struct Usage {
+    images: usize,
+    supported: usize,
+}
+
+impl Usage {
+    fn add(&mut self, supported: bool) {
+        self.images += 1;
+        self.supported += supported as usize;
+    }
+
+    fn status(&self) -> String {
+        let verdict = if self.supported == self.images {
+            String::from("supported")
+        } else if self.supported == 0 {
+            String::from("not supported")
+        } else {
+            format!("supported in {} of them", self.supported)
+        };
+        format!(
+            "{} {}, {}",
+            self.images,
+            plural(self.images, "image"),
+            verdict
+        )
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        noun.to_string()
+    } else {
+        format!("{noun}s")
+    }
+}
+
+fn resolve<'a>(doc: &'a Document, object: &'a Object) -> &'a Object {
+    doc.dereference(object).map_or(object, |(_, o)| o)
+}
+
+fn is_subtype(stream: &lopdf::Stream, subtype: &[u8]) -> bool {
+    stream
+        .dict
+        .get(b"Subtype")
+        .and_then(|s| s.as_name())
+        .is_ok_and(|s| s == subtype)
+}
+
+fn filters(doc: &Document, dict: &Dictionary) -> Vec<Vec<u8>> {
+    let Ok(filter) = dict.get(b"Filter").map(|f| resolve(doc, f)) else {
+        return Vec::new();
+    };
+    match filter {
+        Object::Name(name) => vec![name.clone()],
+        Object::Array(items) => items
+            .iter()
+            .filter_map(|i| resolve(doc, i).as_name().ok())
+            .map(|n| n.to_vec())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// The family of a color space, like DeviceRGB or the ICCBased of [/ICCBased 5 0 R]
+fn color_family(doc: &Document, dict: &Dictionary) -> Option<Vec<u8>> {
+    match resolve(doc, dict.get(b"ColorSpace").ok()?) {
+        Object::Name(name) => Some(name.clone()),
+        Object::Array(items) => Some(resolve(doc, items.first()?).as_name().ok()?.to_vec()),
+        _ => None,
+    }
+}
+
+// Whether unpack can write an image's colors. Jpegs are written as they are so they can be in any
+// color space, everything else has to convert to something a png holds at the bits it has
+fn colors_supported(doc: &Document, dict: &Dictionary, is_jpeg: bool, image_mask: bool) -> bool {
+    match color_family(doc, dict) {
+        Some(_) => is_jpeg || samples_supported(doc, None, dict),
+        None => is_jpeg && !image_mask,
+    }
+}
+
+// Inline images sit in the content itself between BI, ID and EI
+fn has_inline_image(content: &[u8]) -> bool {
+    let mut tokens = content.split(|b| b.is_ascii_whitespace());
+    tokens.any(|t| t == b"BI") && tokens.any(|t| t == b"ID")
+}
+
+fn draws_images(doc: &Document, form: &lopdf::Stream) -> bool {
+    let Some(xobjects) = form
+        .dict
+        .get(b"Resources")
+        .ok()
+        .and_then(|r| resolve(doc, r).as_dict().ok())
+        .and_then(|r| r.get(b"XObject").ok())
+        .and_then(|x| resolve(doc, x).as_dict().ok())
+    else {
+        return false;
+    };
+    xobjects.iter().any(|(_, x)| {
+        resolve(doc, x)
+            .as_stream()
+            .is_ok_and(|s| is_subtype(s, b"Image"))
+    })
+}
+
+impl Run for Prescan {
+    fn run(&self) -> Result<(), PDFConError> {
+        let spnr = spinner("Parsing PDF", tick_speed());
+        spnr.set_message(self.in_file.to_string_lossy().to_string());
+        let doc = Document::load(&self.in_file)?;
+        close_bar(spnr, " ● Parsing Complete! ");
+
+        // Masks are images too but they're written as part of the image they belong to
+        let mut masks: HashSet<ObjectId> = HashSet::new();
+        for object in doc.objects.values() {
+            if let Ok(stream) = object.as_stream() {
+                for key in [b"SMask".as_slice(), b"Mask"] {
+                    if let Ok(id) = stream.dict.get(key).and_then(|m| m.as_reference()) {
+                        masks.insert(id);
+                    }
+                }
+            }
+        }
+
+        let mut filter_usage: BTreeMap<Vec<u8>, Usage> = BTreeMap::new();
+        let mut color_usage: BTreeMap<Vec<u8>, Usage> = BTreeMap::new();
+        let mut feature_usage: BTreeMap<&str, Usage> = BTreeMap::new();
+        let mut images = Usage::default();
+        let mut forms = 0;
+        let mut forms_with_images = 0;
+        let mut inline = 0;
+        for (id, object) in &doc.objects {
+            let Ok(stream) = object.as_stream() else {
+                continue;
+            };
+            if is_subtype(stream, b"Form") {
+                forms += 1;
+                forms_with_images += draws_images(&doc, stream) as usize;
+                let content = stream
+                    .decompressed_content()
+                    .unwrap_or_else(|_| stream.content.clone());
+                inline += has_inline_image(&content) as usize;
+                continue;
+            }
+            if !is_subtype(stream, b"Image") || masks.contains(id) {
+                continue;
+            }
+
+            let filters = filters(&doc, &stream.dict);
+            let is_jpeg = filters.iter().any(|f| f == b"DCTDecode");
+            let filters_ok = filters.iter().all(|f| FILTERS.contains(&f.as_slice()));
+            // A filter listed twice still only counts the image once
+            for filter in filters.iter().collect::<BTreeSet<_>>() {
+                filter_usage
+                    .entry(filter.clone())
+                    .or_default()
+                    .add(FILTERS.contains(&filter.as_slice()));
+            }
+
+            let image_mask = stream
+                .dict
+                .get(b"ImageMask")
+                .and_then(|m| resolve(&doc, m).as_bool())
+                .unwrap_or(false);
+            let color_ok = colors_supported(&doc, &stream.dict, is_jpeg, image_mask);
+            if let Some(family) = color_family(&doc, &stream.dict) {
+                color_usage.entry(family).or_default().add(color_ok);
+            }
+
+            if stream.dict.has(b"SMask") {
+                feature_usage.entry("SMask").or_default().add(true);
+            }
+            // Written without the mask applied
+            if stream.dict.has(b"Mask") {
+                feature_usage.entry("Mask").or_default().add(false);
+            }
+            if image_mask {
+                feature_usage.entry("ImageMask").or_default().add(false);
+            }
+            images.add(filters_ok && color_ok && !image_mask);
+        }
+        for page_id in doc.page_iter() {
+            if doc
+                .get_page_content(page_id)
+                .is_ok_and(|content| has_inline_image(&content))
+            {
+                inline += 1;
+            }
+        }
+
+        let name = |n: &[u8]| format!("/{}", String::from_utf8_lossy(n));
+        report("File", self.in_file.to_string_lossy().to_string());
+        report("Images", images.images.to_string());
+        for (filter, usage) in &filter_usage {
+            report(&format!("Filter {}", name(filter)), usage.status());
+        }
+        for (family, usage) in &color_usage {
+            report(&format!("Color space {}", name(family)), usage.status());
+        }
+        for (feature, usage) in &feature_usage {
+            report(&format!("Feature {}", feature), usage.status());
+        }
+        if inline > 0 {
+            report(
+                "Feature inline images",
+                format!(
+                    "{} {}, not supported",
+                    inline,
+                    plural(inline, "content stream")
+                ),
+            );
+        }
+        if forms > 0 {
+            let status = if forms_with_images == 0 {
+                String::from("no images inside them")
+            } else {
+                format!(
+                    "{} with images inside them, not supported",
+                    forms_with_images
+                )
+            };
+            report(
+                "Feature forms",
+                format!("{} {}, {}", forms, plural(forms, "form"), status),
+            );
+        }
+        report(
+            "Verdict",
+            if images.supported == images.images && inline == 0 && forms_with_images == 0 {
+                String::from("everything found can be extracted")
+            } else {
+                format!(
+                    "{} of {} images can be extracted as they are",
+                    images.supported, images.images
+                )
+            },
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    fn image(doc: &Document, color_space: &str, bits: i64) -> bool {
+        let dict = dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => 4,
+            "Height" => 4,
+            "ColorSpace" => Object::Name(color_space.as_bytes().to_vec()),
+            "BitsPerComponent" => bits,
+        };
+        colors_supported(doc, &dict, false, false)
+    }
+
+    #[test]
+    fn eight_and_sixteen_bit_device_images_are_supported() {
+        let doc = Document::with_version("1.5");
+        assert!(image(&doc, "DeviceGray", 8));
+        assert!(image(&doc, "DeviceRGB", 8));
+        assert!(image(&doc, "DeviceRGB", 16));
+    }
+
+    #[test]
+    fn bilevel_images_are_not_supported() {
+        let doc = Document::with_version("1.5");
+        assert!(!image(&doc, "DeviceGray", 1));
+        assert!(!image(&doc, "DeviceRGB", 4));
+    }
+
+    #[test]
+    fn cmyk_samples_are_not_supported() {
+        let doc = Document::with_version("1.5");
+        assert!(!image(&doc, "DeviceCMYK", 8));
+    }
+
+    #[test]
+    fn jpegs_are_written_in_any_color_space() {
+        let doc = Document::with_version("1.5");
+        let dict = dictionary! {
+            "ColorSpace" => "DeviceCMYK",
+            "BitsPerComponent" => 8,
+        };
+        assert!(colors_supported(&doc, &dict, true, false));
+    }
+}
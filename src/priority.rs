@@ -0,0 +1,21 @@
+use log::warn;
+
+// Nice the whole process so a long run doesn't starve everything else on the machine. Rayon's
+// workers inherit it as long as this runs before the pool is built
+#[cfg(unix)]
+pub fn lower_priority(nice: i32) {
+    // Safe to call with any value. The kernel clamps it and reports failures through errno
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    if result != 0 {
+        warn!(
+            "Failed to set the nice level to {}: {}",
+            nice,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub fn lower_priority(_nice: i32) {
+    warn!("Lowering the priority is only supported on unix. Running at normal priority");
+}
@@ -0,0 +1,101 @@
+use crate::constants::{bc_yellow, bold, c_gray};
+use console::Term;
+use log::error;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Off unless --profile turned it on. Checked before anything is timed so a run without it pays
+// for one atomic load per phase
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// Nanoseconds spent in each phase, summed over every thread
+static TOTALS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+static STARTED: Mutex<Option<Instant>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    // Reading the pdf and building the document
+    Parse,
+    // Undoing stream filters
+    Decode,
+    // Turning samples into png or jpeg files
+    Encode,
+    // Handing finished files to disk or memory
+    Write,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [Phase::Parse, Phase::Decode, Phase::Encode, Phase::Write];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::Decode => "decode",
+            Phase::Encode => "encode",
+            Phase::Write => "write",
+        }
+    }
+}
+
+// Start a fresh set of totals. Everything timed from here on counts towards the report
+pub fn enable() {
+    for total in &TOTALS {
+        total.store(0, Ordering::Relaxed);
+    }
+    *STARTED.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+    TOTALS[phase as usize].fetch_add(elapsed, Ordering::Relaxed);
+    result
+}
+
+// Phases run on every worker at once so their times add up to more than the run took. The share
+// of the summed time is what says where a pdf spends it
+pub fn report() {
+    if !ENABLED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let wall = STARTED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .map(|s| s.elapsed())
+        .unwrap_or_default();
+    let totals =
+        Phase::ALL.map(|p| Duration::from_nanos(TOTALS[p as usize].load(Ordering::Relaxed)));
+    let summed: Duration = totals.iter().sum();
+
+    let mut lines = vec![format!(
+        "{} {:.3}s {}",
+        bc_yellow().apply_to(" ● Profile"),
+        wall.as_secs_f64(),
+        c_gray().apply_to("wall clock")
+    )];
+    for (phase, total) in Phase::ALL.iter().zip(totals) {
+        let share = if summed.is_zero() {
+            0.0
+        } else {
+            total.as_secs_f64() / summed.as_secs_f64() * 100.0
+        };
+        lines.push(format!(
+            "   {:<7}{:>10.3}s {}",
+            bold().apply_to(phase.name()),
+            total.as_secs_f64(),
+            c_gray().apply_to(format!("{:5.1}%", share))
+        ));
+    }
+    for line in lines {
+        if Term::stderr().write_line(&line).is_err() {
+            error!("Failed to print to console");
+            return;
+        }
+    }
+}
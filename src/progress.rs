@@ -1,30 +1,108 @@
-use crate::constants::{bc_drk_green, bc_green, bc_lgt_green, bc_yellow, bold, c_gray};
+#[cfg(feature = "progress")]
+use crate::constants::{bc_drk_green, bold, c_gray};
+use crate::constants::{bc_green, bc_lgt_green, bc_yellow};
 use console::Term;
-use indicatif::{ProgressBar, ProgressStyle};
+#[cfg(feature = "progress")]
+use indicatif::{ProgressState, ProgressStyle};
 use log::error;
+#[cfg(feature = "progress")]
+use std::fmt::Write;
+use std::io::Read;
+use std::path::Path;
+#[cfg(feature = "progress")]
+use std::time::Duration;
 
+// Without the progress feature the bars are stand-ins that draw nothing. Either way the rest of
+// the crate only goes through what's exported here
+#[cfg(feature = "progress")]
+pub use indicatif::{ParallelProgressIterator, ProgressBar};
+#[cfg(not(feature = "progress"))]
+pub use quiet::{ParallelProgressIterator, ProgressBar};
+
+// The first few items of a run say little about how long the rest will take
+#[cfg(feature = "progress")]
+const ETA_SETTLE: Duration = Duration::from_secs(1);
+
+// Whole seconds only so the estimate doesn't flicker between redraws
+#[cfg(feature = "progress")]
+fn eta(state: &ProgressState, w: &mut dyn Write) {
+    if state.elapsed() < ETA_SETTLE || state.pos() == 0 {
+        let _ = write!(w, "-:--");
+        return;
+    }
+    let secs = state.eta().as_secs_f64().round() as u64;
+    let _ = write!(w, "{}:{:02}", secs / 60, secs % 60);
+}
+
+#[cfg(feature = "progress")]
+fn rate(state: &ProgressState, w: &mut dyn Write) {
+    let _ = write!(w, "{:.1}/s", state.per_sec());
+}
+
+#[cfg(feature = "progress")]
 pub fn bar(prefix: &str, total_progress: u64, tick_speed: u64) -> ProgressBar {
     let pb = ProgressBar::new(total_progress);
     pb.set_style(ProgressStyle::default_bar()
                 .progress_chars("█▓█")
                 .tick_strings(&["∙∙∙", "●∙∙", "∙●∙", "∙∙●", "∙∙●"])
                 .template(format!(
-                " {{spinner:.yellow.bold}} {{prefix:.yellow.bold}}{} {}{{wide_bar:.2.bold/:.65.bold}}{{msg}} {{percent:.green.bold}}{} {}{{pos:.8}}{}{{len:.8}}{} ",
-                bold().apply_to(":").to_string(),
-                bc_lgt_green().apply_to("").to_string(),
-                bc_lgt_green().apply_to("%").to_string(),
-                c_gray().apply_to("(").to_string(),
-                c_gray().apply_to("/").to_string(),
-                c_gray().apply_to(")").to_string()
+                " {{spinner:.yellow.bold}} {{prefix:.yellow.bold}}{} {}{{wide_bar:.2.bold/:.65.bold}}{{msg}} {{percent:.green.bold}}{} {}{{pos:.8}}{}{{len:.8}}{} {{rate:.8}} {}{{eta:.8}} ",
+                bold().apply_to(":"),
+                bc_lgt_green().apply_to(""),
+                bc_lgt_green().apply_to("%"),
+                c_gray().apply_to("("),
+                c_gray().apply_to("/"),
+                c_gray().apply_to(")"),
+                c_gray().apply_to("eta ")
             ).as_str())
+        .map(|style| style.with_key("eta", eta).with_key("rate", rate))
         .unwrap_or(ProgressStyle::default_bar()));
     pb.set_prefix(prefix.to_string());
     pb.set_message(bc_drk_green().apply_to("").to_string());
     pb.enable_steady_tick(std::time::Duration::from_millis(tick_speed));
 
-    return pb;
+    pb
+}
+
+// Bytes instead of items. For reading a file whose size is known up front
+#[cfg(feature = "progress")]
+pub fn byte_bar(prefix: &str, total_bytes: u64, tick_speed: u64) -> ProgressBar {
+    let pb = ProgressBar::new(total_bytes);
+    pb.set_style(ProgressStyle::default_bar()
+                .progress_chars("█▓█")
+                .tick_strings(&["∙∙∙", "●∙∙", "∙●∙", "∙∙●", "∙∙●"])
+                .template(format!(
+                " {{spinner:.yellow.bold}} {{prefix:.yellow.bold}}{} {{wide_bar:.2.bold/:.65.bold}} {{percent:.green.bold}}{} {}{{binary_bytes:.8}}{}{{binary_total_bytes:.8}}{} {{binary_bytes_per_sec:.8}} {}{{eta:.8}} ",
+                bold().apply_to(":"),
+                bc_lgt_green().apply_to("%"),
+                c_gray().apply_to("("),
+                c_gray().apply_to("/"),
+                c_gray().apply_to(")"),
+                c_gray().apply_to("eta ")
+            ).as_str())
+        .map(|style| style.with_key("eta", eta))
+        .unwrap_or(ProgressStyle::default_bar()));
+    pb.set_prefix(prefix.to_string());
+    pb.enable_steady_tick(std::time::Duration::from_millis(tick_speed));
+
+    pb
 }
 
+// The whole file, read through a byte_bar. Parsing can't start before all of it is in memory and
+// on a multi-gigabyte input that wait is long enough for a spinner to look stuck
+pub fn read_file(path: &Path, prefix: &str, tick_speed: u64) -> std::io::Result<Vec<u8>> {
+    let file = std::fs::File::open(path)?;
+    // The size is only a hint. A file that grows or shrinks while it's read still reads fine
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let pb = byte_bar(prefix, size, tick_speed);
+    let mut buffer = Vec::with_capacity(size as usize);
+    let result = pb.wrap_read(file).read_to_end(&mut buffer);
+    pb.finish_and_clear();
+    result?;
+    Ok(buffer)
+}
+
+#[cfg(feature = "progress")]
 pub fn spinner(prefix: &str, tick_speed: u64) -> ProgressBar {
     let spnr = ProgressBar::new_spinner();
 
@@ -32,30 +110,103 @@ pub fn spinner(prefix: &str, tick_speed: u64) -> ProgressBar {
                 .tick_strings(&["∙∙∙", "●∙∙", "∙●∙", "∙∙●", "∙∙●"])
                 .template(format!(
                 " {{spinner:.yellow.bold}} {{prefix:.yellow.bold}} {{wide_msg}} {}{{elapsed:.8}}{} ",
-                c_gray().apply_to("(").to_string(),
-                c_gray().apply_to(")").to_string()
+                c_gray().apply_to("("),
+                c_gray().apply_to(")")
             ).as_str())
         .unwrap_or(ProgressStyle::default_spinner()));
     spnr.set_prefix(prefix.to_string());
     spnr.enable_steady_tick(std::time::Duration::from_millis(tick_speed));
 
-    return spnr;
+    spnr
 }
 
+// Only ever touches the message so the rate and eta fields are left alone
 pub fn update_end_cap(bar: &ProgressBar, pos: u64, total: u64) {
-    if pos >= total - 2 && pos < total {
+    if pos >= total.saturating_sub(2) && pos < total {
         bar.set_message(bc_green().apply_to("").to_string());
     } else if pos == total {
         bar.set_message(bc_lgt_green().apply_to("").to_string());
     }
 }
 
+// Bars draw to stderr so the closing message goes there too. Keeps stdout clean for pipes
 pub fn close_bar(bar: ProgressBar, msg: &str) {
     bar.finish_and_clear();
-    match Term::stdout().write_line(format!("{}", bc_yellow().apply_to(msg)).as_str()) {
+    match Term::stderr().write_line(format!("{}", bc_yellow().apply_to(msg)).as_str()) {
         Ok(out) => out,
         Err(_e) => {
             error!("Failed to print to console");
         }
     }
 }
+
+#[cfg(not(feature = "progress"))]
+pub fn bar(_prefix: &str, total_progress: u64, _tick_speed: u64) -> ProgressBar {
+    ProgressBar::new(total_progress)
+}
+
+#[cfg(not(feature = "progress"))]
+pub fn byte_bar(_prefix: &str, total_bytes: u64, _tick_speed: u64) -> ProgressBar {
+    ProgressBar::new(total_bytes)
+}
+
+#[cfg(not(feature = "progress"))]
+pub fn spinner(_prefix: &str, _tick_speed: u64) -> ProgressBar {
+    ProgressBar::new(0)
+}
+
+// The parts of indicatif the commands use, doing nothing. Positions are still counted since
+// callers read them back
+#[cfg(not(feature = "progress"))]
+mod quiet {
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[derive(Clone, Debug, Default)]
+    pub struct ProgressBar {
+        position: Arc<AtomicU64>,
+        length: u64,
+    }
+
+    impl ProgressBar {
+        pub fn new(length: u64) -> Self {
+            Self {
+                position: Arc::new(AtomicU64::new(0)),
+                length,
+            }
+        }
+
+        pub fn inc(&self, delta: u64) {
+            self.position.fetch_add(delta, Ordering::Relaxed);
+        }
+
+        pub fn position(&self) -> u64 {
+            self.position.load(Ordering::Relaxed)
+        }
+
+        pub fn length(&self) -> Option<u64> {
+            Some(self.length)
+        }
+
+        pub fn set_message(&self, _msg: impl Into<Cow<'static, str>>) {}
+
+        pub fn finish_and_clear(&self) {}
+
+        pub fn wrap_read<R: std::io::Read>(&self, read: R) -> R {
+            read
+        }
+    }
+
+    // Counts each item as it's handed out like indicatif's does
+    pub trait ParallelProgressIterator: rayon::iter::ParallelIterator {
+        fn progress_with(
+            self,
+            progress: ProgressBar,
+        ) -> impl rayon::iter::ParallelIterator<Item = Self::Item> {
+            self.inspect(move |_| progress.inc(1))
+        }
+    }
+
+    impl<T: rayon::iter::ParallelIterator> ParallelProgressIterator for T {}
+}
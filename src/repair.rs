@@ -0,0 +1,278 @@
+use lopdf::xref::{Xref, XrefEntry, XrefType};
+use lopdf::{Document, Object, ObjectId, Reader, dictionary};
+use std::collections::{BTreeMap, HashSet};
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\r' | b'\n' | b'\x0c' | b'\0')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    is_whitespace(byte) || matches!(byte, b'<' | b'>' | b'[' | b']' | b'(' | b')' | b'/' | b'%')
+}
+
+// Walk back over an unsigned integer ending right before the given offset
+fn number_before(buffer: &[u8], end: usize) -> Option<(u32, usize)> {
+    let start = buffer[..end]
+        .iter()
+        .rposition(|b| !b.is_ascii_digit())
+        .map_or(0, |p| p + 1);
+    let number = std::str::from_utf8(&buffer[start..end])
+        .ok()?
+        .parse()
+        .ok()?;
+    Some((number, start))
+}
+
+fn skip_whitespace_back(buffer: &[u8], end: usize) -> usize {
+    buffer[..end]
+        .iter()
+        .rposition(|b| !is_whitespace(*b))
+        .map_or(0, |p| p + 1)
+}
+
+// Every "id gen obj" header in the file. Later copies of an object win like they would through
+// an incremental update
+fn find_objects(buffer: &[u8]) -> BTreeMap<u32, (u16, usize)> {
+    let mut objects = BTreeMap::new();
+    let mut from = 0;
+    while let Some(found) = buffer[from..].windows(3).position(|w| w == b"obj") {
+        let keyword = from + found;
+        from = keyword + 3;
+        if buffer.get(keyword + 3).is_some_and(|b| !is_delimiter(*b)) {
+            continue;
+        }
+        let header = || {
+            let gen_end = skip_whitespace_back(buffer, keyword);
+            // Needs whitespace between the generation and obj. Also rules out endobj
+            if gen_end == keyword {
+                return None;
+            }
+            let (generation, gen_start) = number_before(buffer, gen_end)?;
+            let id_end = skip_whitespace_back(buffer, gen_start);
+            if id_end == gen_start {
+                return None;
+            }
+            let (id, id_start) = number_before(buffer, id_end)?;
+            if id_start > 0 && !is_delimiter(buffer[id_start - 1]) {
+                return None;
+            }
+            Some((id, u16::try_from(generation).ok()?, id_start))
+        };
+        if let Some((id, generation, offset)) = header() {
+            objects.insert(id, (generation, offset));
+        }
+    }
+    objects
+}
+
+// The last "/key id gen R" in the file. Trailers and xref streams are never compressed so this
+// still finds the reference when the xref pointing at them is broken
+fn last_reference(buffer: &[u8], key: &[u8]) -> Option<(u32, u16)> {
+    let mut found = None;
+    for start in 0..buffer.len().saturating_sub(key.len()) {
+        if &buffer[start..start + key.len()] != key {
+            continue;
+        }
+        let rest = String::from_utf8_lossy(
+            &buffer[start + key.len()..(start + key.len() + 32).min(buffer.len())],
+        );
+        let mut parts = rest.split_ascii_whitespace();
+        if let (Some(id), Some(generation), Some(r)) = (parts.next(), parts.next(), parts.next())
+            && r.starts_with('R')
+            && let (Ok(id), Ok(generation)) = (id.parse(), generation.parse())
+        {
+            found = Some((id, generation));
+        }
+    }
+    found
+}
+
+// Find the catalog by looking through the objects themselves
+fn find_catalog(buffer: &[u8], objects: &BTreeMap<u32, (u16, usize)>) -> Option<(u32, u16)> {
+    objects.iter().find_map(|(id, (generation, offset))| {
+        let body = &buffer[*offset..];
+        let end = body
+            .windows(6)
+            .position(|w| w == b"endobj")
+            .unwrap_or(body.len());
+        let body = String::from_utf8_lossy(&body[..end]);
+        let compact: String = body.split_ascii_whitespace().collect();
+        compact
+            .contains("/Type/Catalog")
+            .then_some((*id, *generation))
+    })
+}
+
+// Rebuild the cross-reference table by scanning the file for objects. The original bytes are left
+// as they are and a fresh xref and trailer are appended so lopdf reads it like any other pdf.
+// None when there's nothing that looks like a document in there
+pub fn rebuild_xref(buffer: &[u8]) -> Option<Vec<u8>> {
+    // Offsets are counted from the header the same way lopdf does
+    let header = buffer.windows(5).position(|w| w == b"%PDF-")?;
+    let body = &buffer[header..];
+
+    let objects = find_objects(body);
+    let root = last_reference(body, b"/Root").or_else(|| find_catalog(body, &objects))?;
+    let size = objects.keys().next_back()? + 1;
+
+    let mut rebuilt = body.to_vec();
+    if !rebuilt.ends_with(b"\n") {
+        rebuilt.push(b'\n');
+    }
+    let xref_start = rebuilt.len();
+    rebuilt.extend_from_slice(format!("xref\n0 {}\n", size).as_bytes());
+    for id in 0..size {
+        let entry = match objects.get(&id) {
+            Some((generation, offset)) => format!("{:010} {:05} n \n", offset, generation),
+            None => String::from("0000000000 65535 f \n"),
+        };
+        rebuilt.extend_from_slice(entry.as_bytes());
+    }
+    let mut trailer = format!("trailer\n<< /Size {} /Root {} {} R", size, root.0, root.1);
+    if let Some(info) = last_reference(body, b"/Info") {
+        trailer.push_str(&format!(" /Info {} {} R", info.0, info.1));
+    }
+    trailer.push_str(&format!(" >>\nstartxref\n{}\n%%EOF\n", xref_start));
+    rebuilt.extend_from_slice(trailer.as_bytes());
+
+    Some(rebuilt)
+}
+
+// What --tolerant got back out of a damaged file
+#[derive(Debug, Default)]
+pub struct Recovery {
+    // Objects with an "id gen obj" header anywhere in the file
+    pub found: usize,
+    // Objects that have a header but couldn't be parsed, with their id and byte offset
+    pub unreadable: Vec<(u32, usize)>,
+    // Pages that were no longer reachable from the page tree and got put back
+    pub reattached_pages: usize,
+}
+
+impl Recovery {
+    pub fn is_damaged(&self) -> bool {
+        !self.unreadable.is_empty() || self.reattached_pages > 0
+    }
+}
+
+// lopdf drops any object it fails to parse without saying which. Parse the ones that are missing
+// again on their own. Whatever still fails is damaged, the rest were left out by the load filter
+fn unreadable_objects(
+    body: &[u8],
+    objects: &BTreeMap<u32, (u16, usize)>,
+    doc: &Document,
+) -> Vec<(u32, usize)> {
+    let mut table = Xref::new(
+        objects.keys().next_back().map_or(0, |id| id + 1),
+        XrefType::CrossReferenceTable,
+    );
+    for (id, (generation, offset)) in objects {
+        if let Ok(offset) = u32::try_from(*offset) {
+            table.insert(
+                *id,
+                XrefEntry::Normal {
+                    offset,
+                    generation: *generation,
+                },
+            );
+        }
+    }
+    let mut document = Document::new();
+    document.reference_table = table;
+    let reader = Reader {
+        buffer: body,
+        document,
+    };
+
+    objects
+        .iter()
+        .filter(|(id, (generation, _))| !doc.objects.contains_key(&(**id, *generation)))
+        .filter(|(id, (generation, _))| {
+            reader
+                .get_object((**id, *generation), &mut HashSet::new())
+                .is_err()
+        })
+        .map(|(id, (_, offset))| (*id, *offset))
+        .collect()
+}
+
+// Pages whose Pages node was lost still sit in the file. Hang them off the root of the page tree,
+// or a new one when that's gone too, so they get extracted after the pages that are still
+// reachable. Returns how many were put back
+fn reattach_pages(doc: &mut Document) -> usize {
+    let reachable: HashSet<ObjectId> = doc.page_iter().collect();
+    let orphans: Vec<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(id, object)| {
+            !reachable.contains(id)
+                && object
+                    .as_dict()
+                    .is_ok_and(|d| d.get(b"Type").and_then(Object::as_name).ok() == Some(b"Page"))
+        })
+        .map(|(id, _)| *id)
+        .collect();
+    if orphans.is_empty() {
+        return 0;
+    }
+
+    let root = doc
+        .catalog()
+        .and_then(|c| c.get(b"Pages"))
+        .and_then(Object::as_reference)
+        .ok()
+        .filter(|id| doc.get_dictionary(*id).is_ok());
+    let root = match root {
+        Some(root) => root,
+        None => {
+            let root = doc.add_object(dictionary! {
+                "Type" => "Pages",
+                "Kids" => Vec::<Object>::new(),
+                "Count" => 0,
+            });
+            match doc.catalog_mut() {
+                Ok(catalog) => catalog.set("Pages", root),
+                Err(_) => {
+                    let catalog = doc.add_object(dictionary! {
+                        "Type" => "Catalog",
+                        "Pages" => root,
+                    });
+                    doc.trailer.set("Root", catalog);
+                }
+            }
+            root
+        }
+    };
+
+    for orphan in &orphans {
+        if let Ok(page) = doc.get_dictionary_mut(*orphan) {
+            page.set("Parent", root);
+        }
+    }
+    if let Ok(pages) = doc.get_dictionary_mut(root) {
+        let mut kids = pages
+            .get(b"Kids")
+            .and_then(Object::as_array)
+            .cloned()
+            .unwrap_or_default();
+        kids.extend(orphans.iter().map(|id| Object::Reference(*id)));
+        pages.set("Kids", kids);
+        pages.set("Count", reachable.len() as i64 + orphans.len() as i64);
+    }
+    orphans.len()
+}
+
+// Work out what a load lost from buffer, the file doc was read from, and put back the pages that
+// fell out of the page tree
+pub fn recover(buffer: &[u8], doc: &mut Document) -> Recovery {
+    // Offsets are counted from the header the same way lopdf does
+    let header = buffer.windows(5).position(|w| w == b"%PDF-").unwrap_or(0);
+    let body = &buffer[header..];
+    let objects = find_objects(body);
+
+    Recovery {
+        found: objects.len(),
+        unreadable: unreadable_objects(body, &objects, doc),
+        reattached_pages: reattach_pages(doc),
+    }
+}
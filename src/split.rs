@@ -0,0 +1,208 @@
+use crate::Run;
+use crate::constants::tick_speed;
+use crate::error::PDFConError;
+use crate::progress::{bar, close_bar, spinner};
+use crate::unpack::check_directory;
+use log::error;
+use lopdf::{Dictionary, Document, Object, ObjectId, dictionary};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+// Attributes a page can take from the Pages nodes above it. They're copied onto the page since
+// its parents don't come along
+const INHERITED: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+// Deeper than any real page tree. Stops a Parent loop from going on forever
+const MAX_TREE_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Split {
+    pub in_file: PathBuf,
+    pub out_directory: PathBuf,
+    pub force: bool,
+}
+
+fn is_page(object: &Object) -> bool {
+    let dict = match object {
+        Object::Dictionary(d) => d,
+        Object::Stream(s) => &s.dict,
+        _ => return false,
+    };
+    dict.get(b"Type")
+        .and_then(Object::as_name)
+        .is_ok_and(|t| t == b"Page")
+}
+
+// Parent is skipped everywhere. Following it would reach every page of the document
+fn references(object: &Object, found: &mut Vec<ObjectId>) {
+    let dict = match object {
+        Object::Reference(id) => return found.push(*id),
+        Object::Array(items) => return items.iter().for_each(|i| references(i, found)),
+        Object::Dictionary(d) => d,
+        Object::Stream(s) => &s.dict,
+        _ => return,
+    };
+    for (key, value) in dict.iter() {
+        if key != b"Parent" {
+            references(value, found);
+        }
+    }
+}
+
+// Point references at anything that isn't copied to null, which is what a missing object reads
+// as anyway. Otherwise they'd end up pointing at something else once the ids are renumbered
+fn drop_references(object: &mut Object, kept: &HashSet<ObjectId>) {
+    let dict = match object {
+        Object::Reference(id) if !kept.contains(id) => return *object = Object::Null,
+        Object::Array(items) => {
+            return items.iter_mut().for_each(|i| drop_references(i, kept));
+        }
+        Object::Dictionary(d) => d,
+        Object::Stream(s) => &mut s.dict,
+        _ => return,
+    };
+    for (_, value) in dict.iter_mut() {
+        drop_references(value, kept);
+    }
+}
+
+// The page with what it inherits filled in and no Parent so it stands on its own
+pub(crate) fn standalone_page(
+    doc: &Document,
+    page_id: ObjectId,
+) -> Result<Dictionary, PDFConError> {
+    let mut page = doc.get_dictionary(page_id)?.clone();
+    let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+    for _ in 0..MAX_TREE_DEPTH {
+        let Some(node) = parent.and_then(|id| doc.get_dictionary(id).ok()) else {
+            break;
+        };
+        for key in INHERITED {
+            if !page.has(key)
+                && let Ok(value) = node.get(key)
+            {
+                page.set(key, value.clone());
+            }
+        }
+        parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+    page.remove(b"Parent");
+    Ok(page)
+}
+
+impl Split {
+    // Every object the page needs, directly or through other objects. Other pages are left out so
+    // links between pages don't pull the whole document into every file
+    fn page_objects(
+        &self,
+        doc: &Document,
+        page_id: ObjectId,
+        page: &Object,
+    ) -> BTreeMap<ObjectId, Object> {
+        let mut objects = BTreeMap::new();
+        let mut pending = Vec::new();
+        references(page, &mut pending);
+        while let Some(id) = pending.pop() {
+            if id == page_id || objects.contains_key(&id) {
+                continue;
+            }
+            let Ok(object) = doc.get_object(id) else {
+                continue;
+            };
+            if is_page(object) {
+                continue;
+            }
+            references(object, &mut pending);
+            objects.insert(id, object.clone());
+        }
+        objects.insert(page_id, page.clone());
+
+        let kept: HashSet<ObjectId> = objects.keys().copied().collect();
+        for object in objects.values_mut() {
+            drop_references(object, &kept);
+        }
+        objects
+    }
+
+    fn single_page(&self, doc: &Document, page_id: ObjectId) -> Result<Document, PDFConError> {
+        let page = Object::Dictionary(standalone_page(doc, page_id)?);
+
+        let mut out = Document::with_version(doc.version.clone());
+        // Xref streams need pdf 1.5. Write the same kind the source had
+        out.reference_table.cross_reference_type = doc.reference_table.cross_reference_type;
+        out.objects = self.page_objects(doc, page_id, &page);
+        out.max_id = out.objects.keys().map(|(id, _)| *id).max().unwrap_or(0);
+
+        let pages_id = out.add_object(dictionary! {
+            "Type" => "Pages",
+            "Kids" => vec![Object::Reference(page_id)],
+            "Count" => 1,
+        });
+        out.get_dictionary_mut(page_id)?.set("Parent", pages_id);
+        let catalog_id = out.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        out.trailer.set("Root", catalog_id);
+
+        // Ids come from the source document and can be spread out. Close the gaps so the xref
+        // table stays small
+        out.renumber_objects();
+        Ok(out)
+    }
+
+    fn check_clobber(&self) -> Result<(), PDFConError> {
+        // A directory that doesn't exist yet is as good as empty
+        let has_files =
+            std::fs::read_dir(&self.out_directory).is_ok_and(|mut e| e.next().is_some());
+        if has_files && !self.force {
+            return Err(PDFConError::OutputNotEmptyError(
+                self.out_directory.to_string_lossy().to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Run for Split {
+    fn run(&self) -> Result<(), PDFConError> {
+        check_directory(&self.out_directory)?;
+        self.check_clobber()?;
+        std::fs::create_dir_all(&self.out_directory)?;
+
+        let spnr = spinner("Parsing PDF", tick_speed());
+        spnr.set_message(self.in_file.to_string_lossy().to_string());
+        // Load without the unpack filter. Fonts and the rest have to come along into each page
+        let doc = Document::load(&self.in_file)?;
+        close_bar(spnr, " ● Parsing Complete! ");
+
+        let pages = doc.get_pages();
+        // At least four digits so names line up like 0001.pdf
+        let width = pages.len().to_string().len().max(4);
+
+        // Errors are held on to until the bar is closed so they don't interleave with it
+        let pb = bar("Splitting PDF", pages.len() as u64, tick_speed());
+        let mut failed = Vec::new();
+        for (page_num, page_id) in pages {
+            let path = self
+                .out_directory
+                .join(format!("{:0width$}.pdf", page_num, width = width));
+            let result = self
+                .single_page(&doc, page_id)
+                .and_then(|mut page| Ok(page.save(&path).map(|_| ())?));
+            if let Err(e) = result {
+                failed.push((page_num, e));
+            }
+            pb.inc(1);
+        }
+        close_bar(pb, " ● Splitting Complete! ");
+
+        for (page_num, e) in &failed {
+            error!("Failed to split out page {}: {{{}}}", page_num, e);
+        }
+        if !failed.is_empty() {
+            return Err(PDFConError::SplitError);
+        }
+        Ok(())
+    }
+}
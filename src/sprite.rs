@@ -0,0 +1,118 @@
+use crate::error::PDFConError;
+use image::{DynamicImage, GenericImage, ImageBuffer, Rgba, RgbaImage};
+
+// Where one image ended up on the sheet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sprite {
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Shelf packing. Images go left to right in the order they're given and every shelf is only as
+// tall as the tallest image on it. Returns each image's position and the size of the whole sheet
+pub fn layout(sizes: &[(u32, u32)], columns: usize) -> (Vec<(u32, u32)>, u32, u32) {
+    let mut positions = Vec::with_capacity(sizes.len());
+    let (mut sheet_width, mut sheet_height) = (0u32, 0u32);
+    for shelf in sizes.chunks(columns.max(1)) {
+        let mut x = 0u32;
+        for (width, _) in shelf {
+            positions.push((x, sheet_height));
+            x = x.saturating_add(*width);
+        }
+        sheet_width = sheet_width.max(x);
+        let shelf_height = shelf.iter().map(|(_, h)| *h).max().unwrap_or_default();
+        sheet_height = sheet_height.saturating_add(shelf_height);
+    }
+    (positions, sheet_width, sheet_height)
+}
+
+// Copy every image onto one transparent sheet. It's 16 bits per channel as soon as one image is so
+// nothing loses precision
+pub fn build_sheet(
+    images: &[(String, DynamicImage)],
+    columns: usize,
+) -> Result<(DynamicImage, Vec<Sprite>), PDFConError> {
+    if images.is_empty() {
+        return Err(PDFConError::OutputFormatError(String::from(
+            "there are no images to put on the sprite sheet",
+        )));
+    }
+    let sizes: Vec<(u32, u32)> = images
+        .iter()
+        .map(|(_, image)| (image.width(), image.height()))
+        .collect();
+    let (positions, width, height) = layout(&sizes, columns);
+
+    let deep = images.iter().any(|(_, image)| {
+        let color = image.color();
+        color.bytes_per_pixel() / color.channel_count() > 1
+    });
+    let sheet = if deep {
+        let mut sheet = ImageBuffer::<Rgba<u16>, Vec<u16>>::new(width, height);
+        for ((_, image), (x, y)) in images.iter().zip(&positions) {
+            sheet.copy_from(&image.to_rgba16(), *x, *y)?;
+        }
+        DynamicImage::ImageRgba16(sheet)
+    } else {
+        let mut sheet = RgbaImage::new(width, height);
+        for ((_, image), (x, y)) in images.iter().zip(&positions) {
+            sheet.copy_from(&image.to_rgba8(), *x, *y)?;
+        }
+        DynamicImage::ImageRgba8(sheet)
+    };
+
+    let sprites = images
+        .iter()
+        .zip(positions)
+        .map(|((name, image), (x, y))| Sprite {
+            name: name.clone(),
+            x,
+            y,
+            width: image.width(),
+            height: image.height(),
+        })
+        .collect();
+    Ok((sheet, sprites))
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// The layout that goes next to the sheet so tools can cut the images back out
+pub fn layout_json(sheet: &str, width: u32, height: u32, sprites: &[Sprite]) -> String {
+    let entries: Vec<String> = sprites
+        .iter()
+        .map(|s| {
+            format!(
+                "    {{ \"name\": {}, \"x\": {}, \"y\": {}, \"width\": {}, \"height\": {} }}",
+                json_string(&s.name),
+                s.x,
+                s.y,
+                s.width,
+                s.height
+            )
+        })
+        .collect();
+    format!(
+        "{{\n  \"image\": {},\n  \"width\": {},\n  \"height\": {},\n  \"sprites\": [\n{}\n  ]\n}}\n",
+        json_string(sheet),
+        width,
+        height,
+        entries.join(",\n")
+    )
+}
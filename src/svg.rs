@@ -0,0 +1,411 @@
+use crate::error::PDFConError;
+use log::debug;
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Converts the path drawing operators of a page into svg. Text, shading and clipping aren't
+// handled. Raster images drawn on the page are linked to the files unpack wrote for them
+
+// Form xobjects can nest. Stop following them past this depth in case one refers to itself
+const MAX_FORM_DEPTH: usize = 8;
+
+pub(crate) type Matrix = [f64; 6];
+
+pub(crate) const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+// m applied first and then n. Same as the cm operator
+pub(crate) fn concat(m: &Matrix, n: &Matrix) -> Matrix {
+    [
+        m[0] * n[0] + m[1] * n[2],
+        m[0] * n[1] + m[1] * n[3],
+        m[2] * n[0] + m[3] * n[2],
+        m[2] * n[1] + m[3] * n[3],
+        m[4] * n[0] + m[5] * n[2] + n[4],
+        m[4] * n[1] + m[5] * n[3] + n[5],
+    ]
+}
+
+pub(crate) fn transform(m: &Matrix, x: f64, y: f64) -> (f64, f64) {
+    (m[0] * x + m[2] * y + m[4], m[1] * x + m[3] * y + m[5])
+}
+
+// Short numbers keep the files readable. Three decimals is far below a device pixel
+fn num(value: f64) -> String {
+    let formatted = format!("{:.3}", value);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    match trimmed {
+        "" | "-0" => String::from("0"),
+        t => t.to_string(),
+    }
+}
+
+fn matrix_attr(m: &Matrix) -> String {
+    format!(
+        "matrix({} {} {} {} {} {})",
+        num(m[0]),
+        num(m[1]),
+        num(m[2]),
+        num(m[3]),
+        num(m[4]),
+        num(m[5])
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn rgb(r: f64, g: f64, b: f64) -> String {
+    let channel = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(r), channel(g), channel(b))
+}
+
+// Colors are picked by operand count. Pattern and separation spaces fall back to black
+fn color(operands: &[f64]) -> String {
+    match operands {
+        [g] => rgb(*g, *g, *g),
+        [r, g, b] => rgb(*r, *g, *b),
+        [c, m, y, k] => rgb(
+            (1.0 - c) * (1.0 - k),
+            (1.0 - m) * (1.0 - k),
+            (1.0 - y) * (1.0 - k),
+        ),
+        _ => String::from("#000000"),
+    }
+}
+
+#[derive(Clone, Debug)]
+struct GraphicsState {
+    ctm: Matrix,
+    fill: String,
+    stroke: String,
+    line_width: f64,
+    line_cap: i64,
+    line_join: i64,
+}
+
+impl Default for GraphicsState {
+    fn default() -> Self {
+        Self {
+            ctm: IDENTITY,
+            fill: String::from("#000000"),
+            stroke: String::from("#000000"),
+            line_width: 1.0,
+            line_cap: 0,
+            line_join: 0,
+        }
+    }
+}
+
+struct Painter<'a> {
+    doc: &'a Document,
+    images: &'a HashMap<Vec<u8>, String>,
+    elements: String,
+    // Page space bounds of everything drawn. The page box isn't kept by the unpack filter
+    bounds: Option<[f64; 4]>,
+    painted_paths: usize,
+    path: String,
+    path_points: Vec<(f64, f64)>,
+    current_point: (f64, f64),
+}
+
+impl<'a> Painter<'a> {
+    fn new(doc: &'a Document, images: &'a HashMap<Vec<u8>, String>) -> Self {
+        Self {
+            doc,
+            images,
+            elements: String::new(),
+            bounds: None,
+            painted_paths: 0,
+            path: String::new(),
+            path_points: Vec::new(),
+            current_point: (0.0, 0.0),
+        }
+    }
+
+    fn include(&mut self, ctm: &Matrix, x: f64, y: f64) {
+        let (x, y) = transform(ctm, x, y);
+        self.bounds = Some(match self.bounds {
+            Some([min_x, min_y, max_x, max_y]) => {
+                [min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)]
+            }
+            None => [x, y, x, y],
+        });
+    }
+
+    fn path_op(&mut self, op: &str, points: &[f64]) {
+        let _ = write!(self.path, "{}", op);
+        for pair in points.chunks_exact(2) {
+            let _ = write!(self.path, "{} {} ", num(pair[0]), num(pair[1]));
+            self.path_points.push((pair[0], pair[1]));
+        }
+        if let [.., x, y] = points {
+            self.current_point = (*x, *y);
+        }
+    }
+
+    fn end_path(&mut self) {
+        self.path.clear();
+        self.path_points.clear();
+    }
+
+    fn paint(&mut self, state: &GraphicsState, fill: Option<&str>, stroke: bool) {
+        if self.path.is_empty() {
+            return;
+        }
+
+        let points = std::mem::take(&mut self.path_points);
+        for (x, y) in &points {
+            self.include(&state.ctm, *x, *y);
+        }
+
+        let mut element = format!(
+            "<path transform=\"{}\" d=\"{}\"",
+            matrix_attr(&state.ctm),
+            self.path.trim_end()
+        );
+        match fill {
+            Some(rule) => {
+                let _ = write!(element, " fill=\"{}\" fill-rule=\"{}\"", state.fill, rule);
+            }
+            None => element.push_str(" fill=\"none\""),
+        }
+        if stroke {
+            let _ = write!(
+                element,
+                " stroke=\"{}\" stroke-width=\"{}\" stroke-linecap=\"{}\" stroke-linejoin=\"{}\"",
+                state.stroke,
+                num(state.line_width),
+                ["butt", "round", "square"][state.line_cap.clamp(0, 2) as usize],
+                ["miter", "round", "bevel"][state.line_join.clamp(0, 2) as usize],
+            );
+        }
+        element.push_str("/>\n");
+
+        self.elements.push_str(&element);
+        self.painted_paths += 1;
+        self.end_path();
+    }
+
+    // Images fill the unit square with their first row at the top. Svg images start at the
+    // top left so flip them inside the unit square first
+    fn image(&mut self, state: &GraphicsState, href: &str) {
+        let flipped = concat(&[1.0, 0.0, 0.0, -1.0, 0.0, 1.0], &state.ctm);
+        for (x, y) in [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)] {
+            self.include(&state.ctm, x, y);
+        }
+        let _ = writeln!(
+            self.elements,
+            "<image transform=\"{}\" width=\"1\" height=\"1\" preserveAspectRatio=\"none\" xlink:href=\"{}\"/>",
+            matrix_attr(&flipped),
+            escape(href)
+        );
+    }
+
+    fn resolved_dict(&self, dict: Option<&'a Dictionary>, key: &[u8]) -> Option<&'a Dictionary> {
+        let (_, object) = self.doc.dereference(dict?.get(key).ok()?).ok()?;
+        object.as_dict().ok()
+    }
+
+    fn draw_xobject(
+        &mut self,
+        state: &GraphicsState,
+        resources: Option<&'a Dictionary>,
+        name: &[u8],
+        depth: usize,
+    ) -> Result<(), PDFConError> {
+        // Only images on the page itself get written out so only those can be linked
+        if depth == 0
+            && let Some(href) = self.images.get(name)
+        {
+            let href = href.clone();
+            self.image(state, &href);
+            return Ok(());
+        }
+
+        let Some(xobjects) = self.resolved_dict(resources, b"XObject") else {
+            return Ok(());
+        };
+        let Ok(reference) = xobjects.get(name).and_then(|r| r.as_reference()) else {
+            return Ok(());
+        };
+        let Ok(stream) = self.doc.get_object(reference).and_then(|o| o.as_stream()) else {
+            return Ok(());
+        };
+        let is_form = stream
+            .dict
+            .get(b"Subtype")
+            .and_then(|s| s.as_name())
+            .is_ok_and(|s| s == b"Form");
+        if !is_form {
+            return Ok(());
+        }
+        if depth >= MAX_FORM_DEPTH {
+            debug!(
+                "Not following form {} {} R any deeper",
+                reference.0, reference.1
+            );
+            return Ok(());
+        }
+
+        let mut form_state = state.clone();
+        if let Ok(matrix) = stream.dict.get(b"Matrix").and_then(|m| m.as_array())
+            && let Ok(values) = matrix
+                .iter()
+                .map(|v| v.as_float().map(f64::from))
+                .collect::<Result<Vec<_>, _>>()
+            && let Ok(matrix) = <Matrix>::try_from(values)
+        {
+            form_state.ctm = concat(&matrix, &state.ctm);
+        }
+
+        let content = stream
+            .decompressed_content()
+            .unwrap_or_else(|_| stream.content.clone());
+        let form_resources = self
+            .doc
+            .dereference(stream.dict.get(b"Resources").unwrap_or(&Object::Null))
+            .ok()
+            .and_then(|(_, r)| r.as_dict().ok())
+            .or(resources);
+
+        self.run(&content, form_resources, form_state, depth + 1)
+    }
+
+    fn run(
+        &mut self,
+        content: &[u8],
+        resources: Option<&'a Dictionary>,
+        initial: GraphicsState,
+        depth: usize,
+    ) -> Result<(), PDFConError> {
+        let content = Content::decode(content)?;
+
+        let mut stack: Vec<GraphicsState> = Vec::new();
+        let mut state = initial;
+        for operation in content.operations {
+            let numbers: Vec<f64> = operation
+                .operands
+                .iter()
+                .filter_map(|o| o.as_float().ok().map(f64::from))
+                .collect();
+            match (operation.operator.as_str(), numbers.as_slice()) {
+                ("q", _) => stack.push(state.clone()),
+                ("Q", _) => {
+                    if let Some(saved) = stack.pop() {
+                        state = saved;
+                    }
+                }
+                ("cm", [a, b, c, d, e, f]) => {
+                    state.ctm = concat(&[*a, *b, *c, *d, *e, *f], &state.ctm)
+                }
+                ("w", [w]) => state.line_width = *w,
+                ("J", [cap]) => state.line_cap = *cap as i64,
+                ("j", [join]) => state.line_join = *join as i64,
+                ("g" | "rg" | "k" | "sc" | "scn", values) => state.fill = color(values),
+                ("G" | "RG" | "K" | "SC" | "SCN", values) => state.stroke = color(values),
+                ("cs", _) => state.fill = color(&[0.0]),
+                ("CS", _) => state.stroke = color(&[0.0]),
+                ("m", [x, y]) => self.path_op("M", &[*x, *y]),
+                ("l", [x, y]) => self.path_op("L", &[*x, *y]),
+                ("c", [x1, y1, x2, y2, x3, y3]) => {
+                    self.path_op("C", &[*x1, *y1, *x2, *y2, *x3, *y3])
+                }
+                ("v", [x2, y2, x3, y3]) => {
+                    let (x1, y1) = self.current_point;
+                    self.path_op("C", &[x1, y1, *x2, *y2, *x3, *y3])
+                }
+                ("y", [x1, y1, x3, y3]) => self.path_op("C", &[*x1, *y1, *x3, *y3, *x3, *y3]),
+                ("h", _) => self.path_op("Z", &[]),
+                ("re", [x, y, w, h]) => {
+                    self.path_op("M", &[*x, *y]);
+                    self.path_op("L", &[x + w, *y]);
+                    self.path_op("L", &[x + w, y + h]);
+                    self.path_op("L", &[*x, y + h]);
+                    self.path_op("Z", &[]);
+                }
+                ("S", _) => self.paint(&state, None, true),
+                ("s", _) => {
+                    self.path_op("Z", &[]);
+                    self.paint(&state, None, true);
+                }
+                ("f" | "F", _) => self.paint(&state, Some("nonzero"), false),
+                ("f*", _) => self.paint(&state, Some("evenodd"), false),
+                ("B", _) => self.paint(&state, Some("nonzero"), true),
+                ("B*", _) => self.paint(&state, Some("evenodd"), true),
+                ("b", _) => {
+                    self.path_op("Z", &[]);
+                    self.paint(&state, Some("nonzero"), true);
+                }
+                ("b*", _) => {
+                    self.path_op("Z", &[]);
+                    self.paint(&state, Some("evenodd"), true);
+                }
+                ("n", _) => self.end_path(),
+                ("Do", _) => {
+                    if let Some(Ok(name)) = operation.operands.first().map(|o| o.as_name()) {
+                        self.draw_xobject(&state, resources, name, depth)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // The content is drawn in pdf space which has y going up. Flip it about the middle of the
+    // bounds so the view box can use the same numbers
+    fn finish(self) -> Option<String> {
+        if self.painted_paths == 0 {
+            return None;
+        }
+        let [min_x, min_y, max_x, max_y] = self.bounds?;
+        let (width, height) = (max_x - min_x, max_y - min_y);
+
+        let mut svg = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" viewBox=\"{} {} {} {}\" width=\"{}\" height=\"{}\">",
+            num(min_x),
+            num(min_y),
+            num(width),
+            num(height),
+            num(width),
+            num(height)
+        );
+        let _ = writeln!(
+            svg,
+            "<g transform=\"matrix(1 0 0 -1 0 {})\">",
+            num(min_y + max_y)
+        );
+        svg.push_str(&self.elements);
+        svg.push_str("</g>\n</svg>\n");
+        Some(svg)
+    }
+}
+
+// Svg for the vector content of a page. images maps xobject names on the page to the file
+// written for them. Pages without any paths give None so no empty files get written
+pub fn page_svg(
+    doc: &Document,
+    page_id: ObjectId,
+    images: &HashMap<Vec<u8>, String>,
+) -> Result<Option<String>, PDFConError> {
+    let content = doc.get_page_content(page_id)?;
+    let (resources, resource_ids) = doc.get_page_resources(page_id)?;
+    let resources = resources.or_else(|| {
+        resource_ids
+            .first()
+            .and_then(|id| doc.get_dictionary(*id).ok())
+    });
+
+    let mut painter = Painter::new(doc, images);
+    painter.run(&content, resources, GraphicsState::default(), 0)?;
+    Ok(painter.finish())
+}
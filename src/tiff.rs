@@ -0,0 +1,109 @@
+use crate::error::PDFConError;
+use flate2::{Compression, write::ZlibEncoder};
+use image::DynamicImage;
+use std::io::{Seek, SeekFrom, Write};
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+
+// Just enough baseline TIFF to hold a run of pages. Each page is 8 bit gray or RGB pixels
+// deflated into a single strip, and pages are chained through their IFDs in the order they're added
+pub struct MultipageTiff<W: Write + Seek> {
+    out: W,
+    // Where the offset of the next IFD gets patched in. Starts out in the header
+    next_ifd_at: u64,
+}
+
+impl<W: Write + Seek> MultipageTiff<W> {
+    pub fn new(mut out: W) -> Result<Self, PDFConError> {
+        out.write_all(b"II*\0")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(Self {
+            out,
+            next_ifd_at: 4,
+        })
+    }
+
+    // Every offset in the file is 32 bits
+    fn position(&mut self) -> Result<u32, PDFConError> {
+        u32::try_from(self.out.stream_position()?).map_err(|_| {
+            PDFConError::OutputFormatError(String::from("the pages don't fit in a 4 GB tiff"))
+        })
+    }
+
+    // Gray pages stay gray so each page keeps its own photometric interpretation. Alpha is dropped
+    pub fn add_page(&mut self, image: &DynamicImage) -> Result<(), PDFConError> {
+        let (photometric, samples, pixels) = if image.color().has_color() {
+            (2u32, 3u32, image.to_rgb8().into_raw())
+        } else {
+            (1, 1, image.to_luma8().into_raw())
+        };
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&pixels)?;
+        let mut data = encoder.finish()?;
+        let data_len = data.len() as u32;
+        // IFDs have to start on a word boundary
+        if data.len() % 2 == 1 {
+            data.push(0);
+        }
+        let data_at = self.position()?;
+        self.out.write_all(&data)?;
+
+        // Values that don't fit in an entry go between the pixels and the IFD
+        let bits_at = self.position()?;
+        for _ in 0..samples {
+            self.out.write_all(&8u16.to_le_bytes())?;
+        }
+        let resolution_at = bits_at + samples * 2;
+        self.out.write_all(&72u32.to_le_bytes())?;
+        self.out.write_all(&1u32.to_le_bytes())?;
+
+        // One sample is small enough to sit in the entry itself
+        let bits = if samples == 1 { 8 } else { bits_at };
+        let entries: [(u16, u16, u32, u32); 13] = [
+            (256, LONG, 1, image.width()),
+            (257, LONG, 1, image.height()),
+            (258, SHORT, samples, bits),
+            // Deflate
+            (259, SHORT, 1, 8),
+            (262, SHORT, 1, photometric),
+            (273, LONG, 1, data_at),
+            (277, SHORT, 1, samples),
+            (278, LONG, 1, image.height()),
+            (279, LONG, 1, data_len),
+            (282, RATIONAL, 1, resolution_at),
+            (283, RATIONAL, 1, resolution_at),
+            // Chunky
+            (284, SHORT, 1, 1),
+            // Inches
+            (296, SHORT, 1, 2),
+        ];
+
+        let ifd_at = self.position()?;
+        self.out.write_all(&(entries.len() as u16).to_le_bytes())?;
+        for (tag, kind, count, value) in entries {
+            self.out.write_all(&tag.to_le_bytes())?;
+            self.out.write_all(&kind.to_le_bytes())?;
+            self.out.write_all(&count.to_le_bytes())?;
+            // Shorts are left aligned in the value field which little endian gets for free
+            self.out.write_all(&value.to_le_bytes())?;
+        }
+        // No next page until another one is added
+        self.out.write_all(&0u32.to_le_bytes())?;
+
+        // Link the previous IFD (or the header) to this one
+        self.out.seek(SeekFrom::Start(self.next_ifd_at))?;
+        self.out.write_all(&ifd_at.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.next_ifd_at = ifd_at as u64 + 2 + entries.len() as u64 * 12;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), PDFConError> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
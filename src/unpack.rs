@@ -1,27 +1,203 @@
 use crate::Run;
-use crate::constants::{IGNORE_LIST, tick_speed};
+use crate::attachments;
+use crate::cbz::Cbz;
+use crate::checkpoint::Checkpoint;
+use crate::composite;
+use crate::constants::{keeps_type, physical_cores, thread_count, tick_speed};
+use crate::dump;
 use crate::error::PDFConError;
+use crate::icc;
+use crate::info;
+use crate::io_limit;
+use crate::long_path;
+use crate::memory;
 use crate::pdf_image::{self, PDFConColorSpace};
-use crate::progress::{bar, close_bar, spinner, update_end_cap};
-use indicatif::ParallelProgressIterator;
-use log::{debug, error};
-use lopdf::{Dictionary, Document, Object};
+use crate::priority;
+use crate::profile::{self, Phase};
+use crate::progress::{
+    ParallelProgressIterator, ProgressBar, bar, close_bar, read_file, spinner, update_end_cap,
+};
+use crate::repair;
+use crate::sprite;
+use crate::svg;
+use crate::tiff::MultipageTiff;
+use image::ImageDecoder;
+use log::{debug, error, warn};
+use lopdf::{Dictionary, Document, Object, ObjectId, Reader, Stream};
 use rayon::prelude::*;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Unpack {
     pub threads: usize,
+    // Files being written at once. Separate from threads so slow storage isn't thrashed. Defaults
+    // to threads
+    pub io_threads: Option<usize>,
     pub out_directory: PathBuf,
-    pub in_file: PathBuf,
+    pub in_file: Vec<PathBuf>,
     pub optimize: bool,
+    pub contiguous: bool,
+    pub pad: Option<usize>,
+    // --prefix. File names start with this and an underscore
+    pub prefix: Option<String>,
+    pub salvage: bool,
+    // --strip-thumbnails. Drop the JFIF and Exif thumbnails of jpegs that are passed through
+    pub strip_thumbnails: bool,
+    pub png_compression: Option<u32>,
+    pub max_images: Option<usize>,
+    // --top. Only extract this many images, the ones with the most pixels by their declared size
+    pub top: Option<usize>,
+    // Width/height range an image has to fall in to be written
+    pub aspect: Option<(f64, f64)>,
+    pub min_pixels: Option<u64>,
+    pub embed_provenance: bool,
+    pub verify_output: bool,
+    pub svg: bool,
+    pub force: bool,
+    pub reverse: bool,
+    pub bg_color: [u8; 3],
+    pub nice: Option<i32>,
+    pub limit_memory: Option<usize>,
+    pub repair: bool,
+    // --tolerant. Put back pages that fell out of the page tree and report what couldn't be read
+    pub tolerant: bool,
+    pub group_by_type: bool,
+    pub dump_raw: bool,
+    // --dump-dict. Write each image's stream dictionary to a .json named like it
+    pub dump_dict: bool,
+    pub sprite_sheet: Option<PathBuf>,
+    pub sprite_columns: Option<usize>,
+    // --cbz-chunk. Split a .cbz target into parts of this many images
+    pub cbz_chunk: Option<usize>,
+    // --format native. Images are written in the form they're embedded in and never re-encoded
+    pub native: bool,
+    // --exec. Run once per written image with {path} replaced by the image's path
+    pub exec: Option<String>,
+    pub strict: bool,
+    pub extract_text: bool,
+    // Also write the files embedded in the pdfs to the output directory
+    pub attachments: bool,
+    // Also write every ICC profile the color spaces use, one file per distinct profile
+    pub icc_profiles: bool,
+    // Extract the largest of an image's Alternates instead of the image itself
+    pub prefer_highres: bool,
+    // Drop images within this many bits of the perceptual hash of the last image kept
+    pub skip_similar: Option<u32>,
+    // Interleave the pages of two pdfs. reverse_even reads the second one last page first
+    pub collate: bool,
+    pub reverse_even: bool,
+    // --object. Only extract the image with this id instead of going through every page
+    pub object: Option<ObjectId>,
+    // Added to every output number so a continuation can pick up where the last part ended
+    pub page_offset: u32,
+    // --alpha keep. Soft masks become an alpha channel instead of being blended onto bg_color
+    pub keep_alpha: bool,
+    // --checkpoint. Record finished pages every this many images so a rerun can skip them
+    pub checkpoint: Option<usize>,
+    // --quantize. Pngs are reduced to this many colors. Jpegs are left alone
+    pub quantize: Option<usize>,
+    // --bit-depth 8. Scale 16 bit images down to 8 bits, with --dither spreading the rounding
+    pub eight_bit: bool,
+    pub dither: bool,
+    // Turn images by the Rotate of the page they're on so they read the way the page displays
+    pub apply_page_rotation: bool,
+    // Fail images whose colors would only be approximated instead of converting them
+    pub strict_colorspace: bool,
+    // Turn scanned pages of text upright by looking at where their ink is
+    pub auto_orient: bool,
+    // --profile. Print the time spent parsing, decoding, encoding and writing once the run ends
+    pub profile: bool,
+    // --only-types. Keep only objects of these types on load instead of dropping the ignored ones
+    pub only_types: Option<Vec<String>>,
+    // --trim. Crop borders of one color, counting samples this close to it as border too.
+    // Jpegs are left alone
+    pub trim: Option<u8>,
+    // --composite. Put the images of each page back together where the page draws them
+    pub composite: bool,
+}
+
+// The same options the command line gives when none are passed
+impl Default for Unpack {
+    fn default() -> Self {
+        Self {
+            threads: thread_count(None, physical_cores()),
+            io_threads: None,
+            out_directory: PathBuf::from("output/"),
+            in_file: Vec::new(),
+            optimize: false,
+            contiguous: false,
+            pad: None,
+            prefix: None,
+            salvage: false,
+            strip_thumbnails: false,
+            png_compression: None,
+            max_images: None,
+            top: None,
+            aspect: None,
+            min_pixels: None,
+            embed_provenance: false,
+            verify_output: false,
+            svg: false,
+            force: false,
+            reverse: false,
+            bg_color: [255, 255, 255],
+            nice: None,
+            limit_memory: None,
+            repair: false,
+            tolerant: false,
+            group_by_type: false,
+            dump_raw: false,
+            dump_dict: false,
+            sprite_sheet: None,
+            sprite_columns: None,
+            cbz_chunk: None,
+            native: false,
+            exec: None,
+            strict: false,
+            extract_text: false,
+            attachments: false,
+            icc_profiles: false,
+            prefer_highres: false,
+            skip_similar: None,
+            collate: false,
+            reverse_even: false,
+            object: None,
+            page_offset: 0,
+            keep_alpha: true,
+            checkpoint: None,
+            quantize: None,
+            eight_bit: false,
+            dither: false,
+            apply_page_rotation: false,
+            strict_colorspace: false,
+            auto_orient: false,
+            profile: false,
+            only_types: None,
+            trim: None,
+            composite: false,
+        }
+    }
 }
 
+// What lopdf takes to filter objects while loading. A plain fn so it can't see any options
+type FilterFunc = fn((u32, u16), &mut Object) -> Option<((u32, u16), Object)>;
+
 pub fn filter_func(object_id: (u32, u16), object: &mut Object) -> Option<((u32, u16), Object)> {
-    if IGNORE_LIST.contains(&object.type_name().unwrap_or_default()) {
+    let type_name = object.type_name().unwrap_or_default();
+    if !keeps_type(type_name, None) {
         return None;
     }
+    strip_func(object_id, object)
+}
 
+// filter_func without dropping anything by type. Used with --only-types, which is checked once
+// the document is loaded since the filter has no way to see it
+fn strip_func(object_id: (u32, u16), object: &mut Object) -> Option<((u32, u16), Object)> {
     if let Ok(d) = object.as_dict_mut() {
         d.remove(b"Produce");
         d.remove(b"ModDate");
@@ -30,216 +206,3004 @@ pub fn filter_func(object_id: (u32, u16), object: &mut Object) -> Option<((u32,
         d.remove(b"Procset");
         d.remove(b"MediaBox");
         d.remove(b"Annots");
-        if d.is_empty() {
-            return None;
-        }
+        // Emptied dictionaries are kept. Image dictionaries point at things like DecodeParms and
+        // Resources through references and dropping them would leave those dangling
     }
 
     Some((object_id, object.to_owned()))
 }
 
-impl Unpack {
-    fn process_xobject(
-        &self,
-        doc: &Document,
-        page_num: u32,
-        total_pages: usize,
-        reference: &Object,
-    ) -> Result<(), PDFConError> {
-        debug!("Getting xobject information");
-        let ref_id = reference.as_reference()?;
+// Where unpack writes to. Picked from the extension of the output path
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum OutputTarget {
+    Directory(PathBuf),
+    // A single image file. Only works when the pdfs hold exactly one image
+    Image(PathBuf, &'static str),
+    // The same but written to stdout when the path is -
+    Stdout,
+    // Every image as a page of one tiff
+    Tiff(PathBuf),
+    // Every image as a page of a comic book zip
+    Archive(PathBuf),
+}
 
-        debug!("Extracting stream");
-        let stream = doc.get_object(ref_id)?.as_stream()?;
+impl OutputTarget {
+    fn infer(out_directory: &Path) -> Result<Self, PDFConError> {
+        if out_directory == Path::new("-") {
+            return Ok(Self::Stdout);
+        }
+        // An existing directory wins no matter what it's called
+        if out_directory.is_dir() {
+            return Ok(Self::Directory(out_directory.to_path_buf()));
+        }
+        let extension = out_directory
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("png") => Ok(Self::Image(out_directory.to_path_buf(), "png")),
+            Some("jpg") | Some("jpeg") => Ok(Self::Image(out_directory.to_path_buf(), "jpg")),
+            Some("tif") | Some("tiff") => Ok(Self::Tiff(out_directory.to_path_buf())),
+            Some("cbz") | Some("zip") => Ok(Self::Archive(out_directory.to_path_buf())),
+            _ => Ok(Self::Directory(out_directory.to_path_buf())),
+        }
+    }
+}
 
-        debug!("Extracting subtype");
-        let subtype = stream.dict.get(b"Subtype")?.as_name()?;
+// Shared by the page workers of one document
+#[derive(Clone, Copy)]
+struct PageContext<'a> {
+    // File name of the pdf the pages come from
+    source: &'a str,
+    page_offset: u32,
+    // Pages across every document
+    total_pages: u32,
+    padding_width: usize,
+    // Images claimed so far by each page, keyed by page number across every document
+    claimed: &'a Mutex<BTreeMap<u32, usize>>,
+    // Set with --collate. page_offset is unused then since the two documents interleave
+    collated: Option<Collated>,
+    // Images whose colors had to be approximated, across every document
+    lossy: &'a AtomicUsize,
+    checkpoint: Option<&'a Checkpoint>,
+    sink: Sink<'a>,
+    // Picked by --top. Every other image of the document is skipped
+    selected: Option<&'a BTreeSet<ObjectId>>,
+    // Perceptual hashes for --skip-similar, keyed by output path
+    hashes: &'a Mutex<HashMap<PathBuf, u64>>,
+}
 
-        debug!("Checking image");
-        if subtype != b"Image" {
-            // Not an image. No need to continue
-            return Ok(());
-        }
+// An image extracted by extract_images_from_bytes. data is the whole file, ready to be served or
+// written out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtractedImage {
+    // Page of the pdf it's drawn on, from 1
+    pub page: u32,
+    pub object: ObjectId,
+    // File extension for data, png or jpg
+    pub format: &'static str,
+    pub data: Vec<u8>,
+}
 
-        debug!("Grabbing filter");
-        let filters = match stream.dict.get(b"Filter") {
-            Ok(f) => {
-                let first = f.as_name();
-                if first.is_ok() {
-                    Some(vec![first.unwrap()])
-                } else {
-                    let second = f.as_str();
-                    if second.is_ok() {
-                        Some(vec![second.unwrap()])
-                    } else {
-                        let mut vec = Vec::new();
-                        for filter in f.as_array()? {
-                            vec.push(filter.as_name()?);
-                        }
-                        Some(vec)
-                    }
+// Where finished images go
+#[derive(Clone, Copy)]
+enum Sink<'a> {
+    // Written to the path they were named
+    Files,
+    // Kept for extract_images_from_bytes. Nothing touches the disk
+    Memory(&'a Mutex<Vec<ExtractedImage>>),
+}
+
+impl Sink<'_> {
+    fn emit(&self, path: &Path, image: ExtractedImage) -> Result<(), PDFConError> {
+        match self {
+            Self::Files => profile::time(Phase::Write, || {
+                // --group-by-type subdirectories don't exist until their first image
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
                 }
+                io_limit::write(path, &image.data)
+            }),
+            Self::Memory(images) => {
+                images.lock().unwrap_or_else(|e| e.into_inner()).push(image);
+                Ok(())
             }
-            Err(_) => None,
-        };
+        }
+    }
+}
 
-        match filters {
-            Some(filter_list) => {
-                // Filters are applied in reverse order from how they appear so
-                // we're going to reverse this and apply the filters as the appear.
-                // DCTDecode means this is a jpeg so we'll treat it as a jpeg. If DCT isn't present and only FlateDecode is
-                // present then that means we're likely dealing with a png and we'll treat it as a png.
-                // If no filter is present then that means some pdf builder sharted out raw pixel data into the
-                // document. They shouldn't do this ( ImageMagick ) but we probably aught to handle this it.
-                let mut is_jpeg = false;
-                // I'd prefer not to clone but we may have to do that here. We should see if it's possible not to
-                // duplicate the stream contents to process it
-                let mut content = stream.content.clone();
-                for filter in filter_list.into_iter().rev() {
-                    if filter == b"DCTDecode" {
-                        is_jpeg = true;
-                    } else if filter == b"FlateDecode" {
-                        content = pdf_image::decompress(&content)?;
-                    }
-                }
+// Which half of a --collate pair the pages come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Collated {
+    // The odd pages, first to last
+    Fronts,
+    // The even pages. Holds the page count when they were scanned last page first
+    Backs { reversed: Option<u32> },
+}
 
-                // Calculate needed zero padding for page names
-                let padding_width = (total_pages.ilog10() + 1) as usize;
-                let path = self.out_directory.join(format!(
-                    "{:0width$}.{}",
-                    page_num,
-                    if is_jpeg { "jpg" } else { "png" },
-                    width = padding_width
-                ));
+impl PageContext<'_> {
+    // Page number across every document for a page of this one
+    fn page_number(&self, source_page: u32) -> u32 {
+        match self.collated {
+            None => self.page_offset + source_page,
+            Some(Collated::Fronts) => source_page * 2 - 1,
+            Some(Collated::Backs { reversed: None }) => source_page * 2,
+            Some(Collated::Backs {
+                reversed: Some(pages),
+            }) => (pages + 1 - source_page) * 2,
+        }
+    }
 
-                if is_jpeg {
-                    pdf_image::save_jpeg(&content, &path, self.optimize)?
-                } else {
-                    let width = stream.dict.get(b"Width")?.as_i64()? as u32;
-                    let height = stream.dict.get(b"Height")?.as_i64()? as u32;
-                    let bits = stream.dict.get(b"BitsPerComponent")?.as_i64()? as u8;
-                    let color_enum = PDFConColorSpace::from_pdf_format((
-                        stream.dict.get(b"ColorSpace")?.as_name()?,
-                        bits,
+    // The page of this document a page number across every document came from
+    fn source_page(&self, page_num: u32) -> u32 {
+        match self.collated {
+            None => page_num - self.page_offset,
+            Some(Collated::Fronts) => page_num.div_ceil(2),
+            Some(Collated::Backs { reversed: None }) => page_num / 2,
+            Some(Collated::Backs {
+                reversed: Some(pages),
+            }) => pages + 1 - page_num / 2,
+        }
+    }
+}
+
+// Stream dictionary values are allowed to be indirect references. Follow them to the object they point at
+fn get_resolved<'a>(
+    doc: &'a Document,
+    dict: &'a Dictionary,
+    key: &[u8],
+) -> Result<&'a Object, PDFConError> {
+    Ok(doc.dereference(dict.get(key)?)?.1)
+}
+
+// Width, height and color space of an image's samples
+type ImageInfo = (u32, u32, PDFConColorSpace);
+
+// Resources are needed since the color space can be a name from the ColorSpace resources
+fn image_info(
+    doc: &Document,
+    resources: &Dictionary,
+    dict: &Dictionary,
+) -> Result<ImageInfo, PDFConError> {
+    let width = get_resolved(doc, dict, b"Width")?.as_i64()? as u32;
+    let height = get_resolved(doc, dict, b"Height")?.as_i64()? as u32;
+    let bits = get_resolved(doc, dict, b"BitsPerComponent")?.as_i64()? as u8;
+    let color_enum = resolve_color_space(
+        doc,
+        Some(resources),
+        get_resolved(doc, dict, b"ColorSpace")?,
+        bits,
+    )?;
+
+    Ok((width, height, color_enum))
+}
+
+// Whether the samples of an image can be written as a png. Prescan goes by this so its report
+// can't promise more than unpack does
+pub(crate) fn samples_supported(
+    doc: &Document,
+    resources: Option<&Dictionary>,
+    dict: &Dictionary,
+) -> bool {
+    let Ok(bits) = get_resolved(doc, dict, b"BitsPerComponent").and_then(|b| Ok(b.as_i64()?))
+    else {
+        return false;
+    };
+    get_resolved(doc, dict, b"ColorSpace")
+        .and_then(|c| resolve_color_space(doc, resources, c, bits as u8))
+        .is_ok_and(|c| pdf_image::png_can_write(&c))
+}
+
+fn resolve_color_space(
+    doc: &Document,
+    resources: Option<&Dictionary>,
+    color_space: &Object,
+    bits: u8,
+) -> Result<PDFConColorSpace, PDFConError> {
+    if let Ok(name) = color_space.as_name() {
+        // Anything other than a device space is a name from the resources. Resolve it only once
+        // so a resource naming itself can't loop
+        let named = resources
+            .filter(|_| !name.starts_with(b"Device"))
+            .and_then(|r| get_resolved(doc, r, b"ColorSpace").ok())
+            .and_then(|c| c.as_dict().ok())
+            .and_then(|c| get_resolved(doc, c, name).ok());
+        return match named {
+            Some(named) => resolve_color_space(doc, None, named, bits),
+            None => PDFConColorSpace::from_pdf_format(name, bits),
+        };
+    }
+
+    // Everything that isn't a device space is an array with the family name first
+    let family = color_space.as_array()?;
+    let name = match family.first() {
+        Some(n) => doc.dereference(n)?.1.as_name()?,
+        None => {
+            return Err(PDFConError::UnsupportedColorSpace(
+                "empty color space array".to_string(),
+            ));
+        }
+    };
+
+    match name {
+        b"Lab" => {
+            let params = match family.get(1) {
+                Some(p) => doc.dereference(p)?.1.as_dict()?,
+                None => {
+                    return Err(PDFConError::UnsupportedColorSpace(
+                        "Lab without a parameter dictionary".to_string(),
                     ));
+                }
+            };
+            let numbers = |key: &[u8]| -> Result<Vec<f32>, PDFConError> {
+                let mut values = Vec::new();
+                for value in get_resolved(doc, params, key)?.as_array()? {
+                    values.push(doc.dereference(value)?.1.as_float()?);
+                }
+                Ok(values)
+            };
 
-                    pdf_image::encode_and_save_png(
-                        &content,
-                        width,
-                        height,
-                        &color_enum,
-                        &path,
-                        self.optimize,
-                    )?
+            let white_point = match numbers(b"WhitePoint")?.as_slice() {
+                [x, y, z] => [*x, *y, *z],
+                _ => {
+                    return Err(PDFConError::UnsupportedColorSpace(
+                        "Lab with a malformed WhitePoint".to_string(),
+                    ));
                 }
-            }
-            None => {
-                // This is a raw pixel buffer. We can encode this in any format we'd like
-                // Treat it like its a png
-                debug!("Raw pixel buffer");
-                let width = stream.dict.get(b"Width")?.as_i64()? as u32;
-                let height = stream.dict.get(b"Height")?.as_i64()? as u32;
-                let bits = stream.dict.get(b"BitsPerComponent")?.as_i64()? as u8;
-                let color_enum = PDFConColorSpace::from_pdf_format((
-                    stream.dict.get(b"ColorSpace")?.as_name()?,
+            };
+            // Range is optional and defaults to [-100 100 -100 100]
+            let range = match numbers(b"Range").as_deref() {
+                Ok([a_min, a_max, b_min, b_max]) => [*a_min, *a_max, *b_min, *b_max],
+                _ => [-100.0, 100.0, -100.0, 100.0],
+            };
+
+            match bits {
+                8 | 16 => Ok(PDFConColorSpace::Lab {
+                    white_point,
+                    range,
                     bits,
-                ));
+                }),
+                _ => Err(PDFConError::UnsupportedColorSpace(format!(
+                    "Lab with {} bits per component",
+                    bits
+                ))),
+            }
+        }
+        _ => Err(PDFConError::UnsupportedColorSpace(
+            String::from_utf8_lossy(name).to_string(),
+        )),
+    }
+}
+
+// The base color space and lookup table of an Indexed color space. None for any other space
+fn indexed_color_space(
+    doc: &Document,
+    resources: &Dictionary,
+    dict: &Dictionary,
+) -> Result<Option<(PDFConColorSpace, Vec<u8>)>, PDFConError> {
+    // Jpegs without a color space or with one that isn't Indexed are written as they are
+    let Ok(mut color_space) = get_resolved(doc, dict, b"ColorSpace") else {
+        return Ok(None);
+    };
+    if let Ok(name) = color_space.as_name() {
+        let named = get_resolved(doc, resources, b"ColorSpace")
+            .and_then(|c| Ok(c.as_dict()?))
+            .and_then(|c| get_resolved(doc, c, name));
+        match named {
+            Ok(named) => color_space = named,
+            Err(_) => return Ok(None),
+        }
+    }
+    let Ok(family) = color_space.as_array() else {
+        return Ok(None);
+    };
+    let [name, base, _hival, lookup] = family.as_slice() else {
+        return Ok(None);
+    };
+    if !doc
+        .dereference(name)
+        .and_then(|(_, n)| n.as_name())
+        .is_ok_and(|n| n == b"Indexed")
+    {
+        return Ok(None);
+    }
 
-                let path = self.out_directory.join(format!("{:0>5}.png", page_num));
+    // A base that can't be converted leaves the jpeg to be written as it is
+    let Ok(base) = doc
+        .dereference(base)
+        .map_err(PDFConError::from)
+        .and_then(|(_, b)| resolve_color_space(doc, Some(resources), b, 8))
+    else {
+        return Ok(None);
+    };
+    // The lookup is a string or a stream of base color samples
+    let lookup = match doc.dereference(lookup)?.1 {
+        Object::String(bytes, _) => bytes.clone(),
+        Object::Stream(stream) => stream
+            .decompressed_content()
+            .unwrap_or_else(|_| stream.content.clone()),
+        other => {
+            return Err(PDFConError::UnsupportedColorSpace(format!(
+                "Indexed with a {} lookup",
+                other.enum_variant()
+            )));
+        }
+    };
+    Ok(Some((base, lookup)))
+}
 
-                pdf_image::encode_and_save_png(
-                    &stream.content,
-                    width,
-                    height,
-                    &color_enum,
-                    &path,
-                    self.optimize,
-                )?
+// Arguments of an --exec template. Whitespace separates them unless it's quoted. Single quotes
+// keep everything up to the next one as it is, double quotes let a backslash escape a double
+// quote or a backslash
+fn split_command(template: &str) -> Result<Vec<String>, PDFConError> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(current.take()),
+            '\'' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(unterminated(template)),
+                    }
+                }
+            }
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => arg.push(c),
+                            Some(c) => arg.extend(['\\', c]),
+                            None => return Err(unterminated(template)),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(unterminated(template)),
+                    }
+                }
             }
+            c => current.get_or_insert_with(String::new).push(c),
         }
+    }
+    args.extend(current);
+    Ok(args)
+}
 
-        Ok(())
+fn unterminated(template: &str) -> PDFConError {
+    PDFConError::ExecError(format!("{} has a quote that isn't closed", template))
+}
+
+// Subdirectory an image goes in with --group-by-type. 1 bit samples are never written so there's
+// no bilevel group
+fn type_group(color: Option<&PDFConColorSpace>) -> &'static str {
+    match color {
+        Some(PDFConColorSpace::L8) | Some(PDFConColorSpace::L16) => "gray",
+        _ => "color",
     }
+}
 
-    fn find_xobject_images_in_page(
-        &self,
-        doc: &Document,
-        page_num: u32,
-        page_dict: &Dictionary,
-        total_pages: usize,
-    ) -> Result<(), PDFConError> {
-        debug!("Getting resources and xobjects");
-        let resources_dict = page_dict.get(b"Resources")?.as_dict()?;
-        let x_obj_dict = resources_dict.get(b"XObject")?.as_dict()?;
-        for (_name, x_ref) in x_obj_dict.iter() {
-            self.process_xobject(&doc, page_num, total_pages, &x_ref)?;
+fn declared_dimension(doc: &Document, dict: &Dictionary, key: &[u8]) -> Option<i64> {
+    get_resolved(doc, dict, key).ok()?.as_i64().ok()
+}
+
+fn declared_pixels(doc: &Document, dict: &Dictionary) -> i64 {
+    let width = declared_dimension(doc, dict, b"Width").unwrap_or_default();
+    let height = declared_dimension(doc, dict, b"Height").unwrap_or_default();
+    width.saturating_mul(height)
+}
+
+// Alternates lists other versions of an image, like a print resolution one behind a screen
+// proxy. The one with the most pixels wins and the image itself is kept on a tie. OPI only names
+// files outside the pdf so there's nothing there to follow
+fn highres_alternate<'a>(
+    doc: &'a Document,
+    id: ObjectId,
+    stream: &'a Stream,
+) -> (ObjectId, &'a Stream) {
+    if stream.dict.has(b"OPI") {
+        debug!(
+            "Image {} {} R has OPI comments. The images they name aren't in the pdf",
+            id.0, id.1
+        );
+    }
+    let Ok(alternates) = get_resolved(doc, &stream.dict, b"Alternates")
+        .and_then(|a| a.as_array().map_err(PDFConError::from))
+    else {
+        return (id, stream);
+    };
+    let mut best = (id, stream);
+    for alternate in alternates {
+        let Some((alternate_id, alternate)) = doc
+            .dereference(alternate)
+            .ok()
+            .and_then(|(_, a)| a.as_dict().ok()?.get(b"Image").ok()?.as_reference().ok())
+            .and_then(|i| Some((i, doc.get_object(i).ok()?.as_stream().ok()?)))
+        else {
+            continue;
+        };
+        if declared_pixels(doc, &alternate.dict) > declared_pixels(doc, &best.1.dict) {
+            best = (alternate_id, alternate);
         }
-        Ok(())
     }
+    if best.0 != id {
+        debug!(
+            "Using alternate {} {} R in place of image {} {} R",
+            best.0.0, best.0.1, id.0, id.1
+        );
+    }
+    best
+}
 
-    fn extract_images(&self, doc: &Document) -> Result<(), PDFConError> {
-        let pages = doc.get_pages().into_par_iter().collect::<Vec<_>>();
-        let total_pages = pages.len();
+// Some writers leave Subtype out of image dictionaries. Only an image has a size and a sample
+// depth so those are enough to tell
+fn looks_like_image(dict: &Dictionary) -> bool {
+    [b"Width".as_slice(), b"Height", b"BitsPerComponent"]
+        .iter()
+        .all(|key| dict.has(key))
+}
 
-        // Initialize the progress bar
-        let pb = bar("Processing Images", total_pages as u64, tick_speed());
+// Rotate is inherited so a page without one takes it from the closest Pages node above it.
+// Clockwise degrees from 0 to 270
+fn page_rotation(doc: &Document, page_dict: &Dictionary) -> u32 {
+    // Deeper than any real page tree. Stops a Parent loop from going on forever
+    const MAX_TREE_DEPTH: usize = 64;
 
-        let results: Vec<Result<(), PDFConError>> = pages
-            .par_iter()
-            .progress_with(pb.clone())
-            .map(|(page_num, page_id)| {
-                let pos = pb.position();
-                let total = pb.length().unwrap();
+    let mut node = page_dict;
+    for _ in 0..MAX_TREE_DEPTH {
+        if let Ok(rotate) = get_resolved(doc, node, b"Rotate") {
+            return match rotate.as_float() {
+                Ok(degrees) if degrees % 90.0 == 0.0 => (degrees as i64).rem_euclid(360) as u32,
+                _ => {
+                    warn!("Ignoring a page rotation that isn't a multiple of 90 degrees");
+                    0
+                }
+            };
+        }
+        let Ok(parent) = get_resolved(doc, node, b"Parent") else {
+            break;
+        };
+        match parent.as_dict() {
+            Ok(parent) => node = parent,
+            Err(_) => break,
+        }
+    }
+    0
+}
 
-                // Update bars end cap based on current progress
-                update_end_cap(&pb, pos, total);
+// Say how much of a damaged document --tolerant got back and where the damage is
+fn report_recovery(source: &str, recovery: &repair::Recovery) {
+    if !recovery.unreadable.is_empty() {
+        warn!(
+            "{}: {} of {} objects couldn't be read and were left out",
+            source,
+            recovery.unreadable.len(),
+            recovery.found
+        );
+        for (id, offset) in &recovery.unreadable {
+            warn!("Object {} at byte {} is damaged", id, offset);
+        }
+    }
+    if recovery.reattached_pages > 0 {
+        warn!(
+            "{}: {} pages had fallen out of the page tree and were put back after the others",
+            source, recovery.reattached_pages
+        );
+    }
+}
 
-                debug!("Getting page dict");
-                let page_dict = doc.get_object(*page_id)?.as_dict()?;
-                self.find_xobject_images_in_page(&doc, *page_num, &page_dict, total_pages)?;
-                Ok(())
-            })
-            .collect();
+fn load_repaired(buffer: &[u8], filter: FilterFunc) -> Option<Document> {
+    let rebuilt = repair::rebuild_xref(buffer)?;
+    Reader {
+        buffer: &rebuilt,
+        document: Document::new(),
+    }
+    .read(Some(filter))
+    .ok()
+    .filter(|document| document.catalog().is_ok())
+}
 
-        // Finish bar and display message
-        close_bar(pb, " ● Processing Complete! ");
+// Jpegs turned with --apply-page-rotation only carry an Exif orientation. Apply it so the pixels
+// of a tiff page or sprite come out the way a viewer shows the jpeg
+fn open_oriented(path: &Path) -> Result<image::DynamicImage, PDFConError> {
+    let mut decoder = image::ImageReader::open(path)?.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = image::DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
 
-        // Log any errors and return a general error
-        let mut error_encountered = false;
-        for result in results {
-            match result {
-                Ok(()) => {}
-                Err(e) => {
-                    error_encountered = true;
-                    error!("Failed to extract image from page: {{{}}}", e.to_string())
+// Samples turned clockwise by rotation degrees along with their new width and height. alpha is
+// set when interleave_alpha added a channel
+fn rotate_samples<'a>(
+    content: &'a [u8],
+    width: u32,
+    height: u32,
+    color: &PDFConColorSpace,
+    alpha: bool,
+    rotation: u32,
+) -> (Cow<'a, [u8]>, u32, u32) {
+    if rotation == 0 {
+        return (Cow::Borrowed(content), width, height);
+    }
+    let bytes_per_pixel = color.data_len(1, 1) + alpha as usize;
+    let (rotated, width, height) =
+        pdf_image::rotate(content, width, height, bytes_per_pixel, rotation);
+    (Cow::Owned(rotated), width, height)
+}
+
+// The samples with --trim applied. Left as they are when there's no border to take off
+fn trim_samples<'a>(
+    content: &'a [u8],
+    width: u32,
+    height: u32,
+    color: &PDFConColorSpace,
+    alpha: bool,
+    fuzz: Option<u8>,
+) -> (Cow<'a, [u8]>, u32, u32) {
+    let sample_bytes = match color {
+        PDFConColorSpace::L16
+        | PDFConColorSpace::RGB16
+        | PDFConColorSpace::Lab { bits: 16, .. } => 2,
+        _ => 1,
+    };
+    let bytes_per_pixel = color.data_len(1, 1) + alpha as usize;
+    let trimmed = fuzz
+        .and_then(|f| pdf_image::trim(content, width, height, bytes_per_pixel, sample_bytes, f));
+    match trimmed {
+        Some((trimmed, trimmed_width, trimmed_height)) => {
+            debug!(
+                "Trimmed {}x{} down to {}x{}",
+                width, height, trimmed_width, trimmed_height
+            );
+            (Cow::Owned(trimmed), trimmed_width, trimmed_height)
+        }
+        None => (Cow::Borrowed(content), width, height),
+    }
+}
+
+// Filter names in the order they appear in the stream dictionary. None when the stream isn't
+// filtered at all. The entry and each name in it can be indirect like any other value
+fn stream_filters<'a>(
+    doc: &'a Document,
+    dict: &'a Dictionary,
+) -> Result<Option<Vec<&'a [u8]>>, PDFConError> {
+    let filters = match get_resolved(doc, dict, b"Filter") {
+        Ok(f) => {
+            if let Ok(first) = f.as_name() {
+                Some(vec![first])
+            } else if let Ok(second) = f.as_str() {
+                Some(vec![second])
+            } else {
+                let mut vec = Vec::new();
+                for filter in f.as_array()? {
+                    vec.push(doc.dereference(filter)?.1.as_name()?);
                 }
+                Some(vec)
             }
         }
-        if error_encountered {
-            return Err(PDFConError::UnpackError);
+        Err(_) => None,
+    };
+    Ok(filters)
+}
+
+// Filter names the way a pdf writes them, like [/FlateDecode /DCTDecode]
+fn chain_name(filters: &[&[u8]]) -> String {
+    let names: Vec<String> = filters
+        .iter()
+        .map(|f| format!("/{}", String::from_utf8_lossy(f)))
+        .collect();
+    format!("[{}]", names.join(" "))
+}
+
+// Filters are applied in reverse order from how they appear so
+// we're going to reverse this and apply the filters as the appear.
+// DCTDecode means this is a jpeg so we'll treat it as a jpeg. If DCT isn't present and only FlateDecode is
+// present then that means we're likely dealing with a png and we'll treat it as a png.
+// Used for images and their soft masks alike so both understand the same filters
+// The stream is only borrowed until a filter produces new data so a plain jpeg is never copied
+fn decode_filters<'a>(
+    doc: &Document,
+    dict: &Dictionary,
+    content: &'a [u8],
+    filter_list: Vec<&[u8]>,
+) -> Result<(Cow<'a, [u8]>, bool), PDFConError> {
+    let chain = chain_name(&filter_list);
+    let mut seen = HashSet::new();
+    if filter_list.iter().any(|f| !seen.insert(*f)) {
+        warn!(
+            "Filter chain {} lists the same filter more than once",
+            chain
+        );
+    }
+
+    let mut is_jpeg = false;
+    let mut content = Cow::Borrowed(content);
+    let mut applied = HashSet::new();
+    for (index, filter) in filter_list.into_iter().enumerate().rev() {
+        // A repeated filter is sometimes real but more often a writer listing one it applied once.
+        // Decoding that a second time fails or comes out empty and either way the data is garbage
+        let again = !applied.insert(filter);
+        if filter == b"DCTDecode" {
+            is_jpeg = true;
+        } else if filter == b"FlateDecode" {
+            content = match pdf_image::decompress(&content) {
+                Err(e) if again => {
+                    return Err(PDFConError::FilterChainError(format!(
+                        "{} decodes FlateDecode again but the data was only compressed once: {{{}}}",
+                        chain, e
+                    )));
+                }
+                Ok(data) if again && data.is_empty() => {
+                    return Err(PDFConError::FilterChainError(format!(
+                        "{} decodes FlateDecode again and the second pass gave nothing",
+                        chain
+                    )));
+                }
+                result => Cow::Owned(result?),
+            };
+            if let Some(parms) = decode_parms(doc, dict, index) {
+                content = Cow::Owned(unpredict(doc, parms, &content)?);
+            }
         }
-        Ok(())
     }
+    Ok((content, is_jpeg))
 }
 
-impl Run for Unpack {
-    fn run(&self) -> Result<(), PDFConError> {
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(self.threads)
-            .build_global()?;
+// DecodeParms of the filter at index. A lone dictionary goes with a lone filter
+fn decode_parms<'a>(
+    doc: &'a Document,
+    dict: &'a Dictionary,
+    index: usize,
+) -> Option<&'a Dictionary> {
+    let parms = get_resolved(doc, dict, b"DecodeParms").ok()?;
+    match parms.as_array() {
+        Ok(parms) => doc.dereference(parms.get(index)?).ok()?.1.as_dict().ok(),
+        Err(_) => parms.as_dict().ok(),
+    }
+}
+
+fn unpredict(doc: &Document, parms: &Dictionary, content: &[u8]) -> Result<Vec<u8>, PDFConError> {
+    let number = |key: &[u8], default: i64| {
+        get_resolved(doc, parms, key)
+            .and_then(|n| n.as_i64().map_err(PDFConError::from))
+            .unwrap_or(default)
+            .max(1)
+    };
+    let predictor = number(b"Predictor", 1);
+    if predictor == 1 {
+        return Ok(content.to_vec());
+    }
+    pdf_image::unpredict(
+        content,
+        predictor,
+        pdf_image::PredictorLayout {
+            colors: number(b"Colors", 1) as usize,
+            bits: number(b"BitsPerComponent", 8) as usize,
+            columns: number(b"Columns", 1) as usize,
+        },
+    )
+}
+
+// Soft mask of an image as one 8 bit alpha value per pixel. Masks at another size or depth are
+// left alone and the image keeps its unmasked colors
+fn soft_mask<'a>(
+    doc: &'a Document,
+    dict: &'a Dictionary,
+    width: u32,
+    height: u32,
+) -> Option<Cow<'a, [u8]>> {
+    let mask = get_resolved(doc, dict, b"SMask").ok()?.as_stream().ok()?;
+    let dimension = |key: &[u8]| declared_dimension(doc, &mask.dict, key);
+    let bits = get_resolved(doc, &mask.dict, b"BitsPerComponent")
+        .ok()
+        .and_then(|b| b.as_i64().ok());
+    if dimension(b"Width") != Some(width as i64)
+        || dimension(b"Height") != Some(height as i64)
+        || bits != Some(8)
+    {
+        debug!("Ignoring a soft mask that doesn't match its image");
+        return None;
+    }
 
-        std::fs::create_dir_all(&self.out_directory)?;
+    // Same filter chain as the image itself. A jpeg mask still has to come out as one gray sample
+    // per pixel
+    let decoded = match stream_filters(doc, &mask.dict) {
+        Ok(None) => Ok(Cow::Borrowed(mask.content.as_slice())),
+        Ok(Some(filters)) => {
+            decode_filters(doc, &mask.dict, &mask.content, filters).and_then(|(alpha, is_jpeg)| {
+                if !is_jpeg {
+                    return Ok(alpha);
+                }
+                let jpeg = image::load_from_memory_with_format(&alpha, image::ImageFormat::Jpeg)?;
+                Ok(Cow::Owned(jpeg.into_luma8().into_raw()))
+            })
+        }
+        Err(e) => Err(e),
+    };
+    let alpha = match decoded {
+        Ok(alpha) => alpha,
+        Err(e) => {
+            debug!("Ignoring a soft mask that failed to decode: {{{}}}", e);
+            return None;
+        }
+    };
+    (alpha.len() >= width as usize * height as usize).then_some(alpha)
+}
+
+// The Matte of a soft mask as 8 bit samples. The image's colors were blended against it
+fn soft_mask_matte(doc: &Document, dict: &Dictionary) -> Option<Vec<u8>> {
+    let mask = get_resolved(doc, dict, b"SMask").ok()?.as_stream().ok()?;
+    let values = get_resolved(doc, &mask.dict, b"Matte")
+        .ok()?
+        .as_array()
+        .ok()?;
+    let mut matte = Vec::new();
+    for value in values {
+        let value = doc.dereference(value).ok()?.1.as_float().ok()?;
+        matte.push((value.clamp(0.0, 1.0) * 255.0).round() as u8);
+    }
+    Some(matte)
+}
 
-        // Add spinner to show program is doing something
-        let spnr = spinner("Parsing PDF", tick_speed());
+impl Unpack {
+    // Zero padding for page names. Defaults to just enough digits for the highest number, which
+    // --page-offset pushes up
+    fn padding_width(&self, total_pages: usize) -> usize {
+        let highest = total_pages + self.page_offset as usize;
+        let needed = (highest.max(1).ilog10() + 1) as usize;
+        match self.pad {
+            Some(pad) if pad < needed => {
+                warn!(
+                    "A pad of {} is too small for numbers up to {}. Using {} instead",
+                    pad, highest, needed
+                );
+                needed
+            }
+            Some(pad) => pad,
+            None => needed,
+        }
+    }
 
-        let document = Document::load_filtered(&self.in_file, filter_func)?;
+    // The decoded samples have to fill the declared image exactly before they go to the encoder.
+    // --salvage cuts off or zero fills the difference, otherwise the page fails
+    fn fit_pixel_data<'a>(
+        &self,
+        content: &'a [u8],
+        width: u32,
+        height: u32,
+        color_enum: &PDFConColorSpace,
+        ref_id: ObjectId,
+        page_num: u32,
+    ) -> Result<Cow<'a, [u8]>, PDFConError> {
+        let expected = color_enum.data_len(width, height);
+        if content.len() == expected {
+            return Ok(Cow::Borrowed(content));
+        }
+        let mismatch = format!(
+            "image {} {} R is {}x{} and needs {} bytes but the stream decoded to {} bytes",
+            ref_id.0,
+            ref_id.1,
+            width,
+            height,
+            expected,
+            content.len()
+        );
+        if !self.salvage {
+            return Err(PDFConError::PixelDataError(mismatch));
+        }
+        warn!(
+            "Writing damaged image on page {}: {}. The rest is {}",
+            page_num,
+            mismatch,
+            if content.len() > expected {
+                "cut off"
+            } else {
+                "filled with black"
+            }
+        );
+        let mut fitted = content.to_vec();
+        fitted.resize(expected, 0);
+        Ok(Cow::Owned(fitted))
+    }
 
-        // Finish bar and display message
-        close_bar(spnr, " ● Parsing Complete! ");
+    // The samples with the soft mask applied. --alpha keep adds it as an alpha channel and
+    // --alpha flatten blends onto --bg-color. None when the image has no usable soft mask or
+    // isn't 8 bit gray or rgb
+    fn apply_mask(
+        &self,
+        doc: &Document,
+        dict: &Dictionary,
+        content: &[u8],
+        width: u32,
+        height: u32,
+        color: &PDFConColorSpace,
+    ) -> Option<Vec<u8>> {
+        let alpha = soft_mask(doc, dict, width, height)?;
+        if self.native {
+            warn!("Not applying a soft mask. --format native leaves the pixels as they are");
+            return None;
+        }
+        let unpremultiplied = soft_mask_matte(doc, dict)
+            .and_then(|matte| pdf_image::unpremultiply(content, color, &alpha, &matte));
+        let content = unpremultiplied.as_deref().unwrap_or(content);
+        if self.keep_alpha {
+            pdf_image::interleave_alpha(content, color, &alpha)
+        } else {
+            pdf_image::composite_background(content, color, &alpha, self.bg_color)
+        }
+    }
 
-        self.extract_images(&document)?;
+    // Pngs can't hold everything a pdf can. Converting is the only way to get those out at all,
+    // --format native included, but the colors are approximated. Say which images so they can be
+    // corrected by hand and count them for the summary. --strict-colorspace fails the image
+    // instead. Lab is the only conversion made. CMYK jpegs are written as they're embedded, CMYK
+    // samples and DeviceN can't be written at all and there's no manifest to flag them in
+    fn check_conversion(
+        &self,
+        ctx: &PageContext,
+        color: &PDFConColorSpace,
+        ref_id: ObjectId,
+        page_num: u32,
+    ) -> Result<(), PDFConError> {
+        if !matches!(color, PDFConColorSpace::Lab { .. }) {
+            return Ok(());
+        }
+        if self.strict_colorspace {
+            return Err(PDFConError::UnsupportedColorSpace(format!(
+                "Lab of image {} {} R can only be approximated in a png",
+                ref_id.0, ref_id.1
+            )));
+        }
+        warn!(
+            "Converting image {} {} R on page {} from Lab to sRGB since png has no Lab. The colors are approximate",
+            ref_id.0, ref_id.1, page_num
+        );
+        ctx.lossy.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
 
+    // Outputs that combine every image into one file have to decode and re-encode all of them
+    fn check_native(&self, target: &OutputTarget) -> Result<(), PDFConError> {
+        if !self.native {
+            return Ok(());
+        }
+        let combined = match (&self.sprite_sheet, target) {
+            (Some(sheet), _) => Some(sheet),
+            (None, OutputTarget::Tiff(path)) => Some(path),
+            _ => None,
+        };
+        if let Some(path) = combined {
+            return Err(PDFConError::OutputFormatError(format!(
+                "--format native never re-encodes but {} needs every image converted",
+                path.to_string_lossy()
+            )));
+        }
+        if self.composite {
+            return Err(PDFConError::OutputFormatError(String::from(
+                "--format native never re-encodes but --composite draws the images of a page onto a new one",
+            )));
+        }
+        if self.optimize {
+            warn!("--format native keeps jpegs as they are. --optimize only applies to pngs");
+        }
         Ok(())
     }
+
+    fn output_path(&self, number: u32, padding_width: usize, extension: &str) -> PathBuf {
+        self.out_directory.join(format!(
+            "{}{:0width$}.{}",
+            self.prefix
+                .as_ref()
+                .map(|p| format!("{}_", p))
+                .unwrap_or_default(),
+            number,
+            extension,
+            width = padding_width
+        ))
+    }
+
+    // Page numbering stays the same inside each subdirectory. The subdirectory is only made once
+    // an image is written to it
+    fn grouped_path(&self, path: PathBuf, group: &str) -> PathBuf {
+        if !self.group_by_type {
+            return path;
+        }
+        self.out_directory
+            .join(group)
+            .join(path.file_name().unwrap_or_default())
+    }
+
+    // Outputs are named after the page by default. --reverse numbers from the last page instead.
+    // --page-offset is added on top either way
+    fn output_number(&self, ctx: &PageContext, page_num: u32) -> u32 {
+        let number = if self.reverse {
+            ctx.total_pages + 1 - page_num
+        } else {
+            page_num
+        };
+        number + self.page_offset
+    }
+
+    // Take one of the --max-images slots. Slots are counted per page so what a page writes never
+    // depends on how far the other workers got. The cap itself is applied in page order afterwards
+    fn claim_image(&self, ctx: &PageContext, page_num: u32) -> bool {
+        let Some(max) = self.max_images else {
+            return true;
+        };
+        let mut claimed = ctx.claimed.lock().unwrap_or_else(|e| e.into_inner());
+        let count = claimed.entry(page_num).or_default();
+        if *count >= max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    fn load_filter(&self) -> FilterFunc {
+        match self.only_types {
+            Some(_) => strip_func,
+            None => filter_func,
+        }
+    }
+
+    // Drops what --only-types doesn't list from a document loaded with load_filter
+    fn drop_unlisted_types(&self, doc: &mut Document) {
+        if let Some(types) = &self.only_types {
+            doc.objects.retain(|_, object| {
+                keeps_type(object.type_name().unwrap_or_default(), Some(types))
+            });
+        }
+    }
+
+    // A jpeg under an Indexed color space only holds palette indexes. Written as it is it would
+    // show them as gray, so it's decoded and looked up instead. --format native passes it
+    // through with a warning
+    fn indexed_jpeg(
+        &self,
+        doc: &Document,
+        resources: &Dictionary,
+        dict: &Dictionary,
+        content: &[u8],
+        ref_id: ObjectId,
+        page_num: u32,
+    ) -> Result<Option<(Vec<u8>, ImageInfo)>, PDFConError> {
+        let Some((base, palette)) = indexed_color_space(doc, resources, dict)? else {
+            return Ok(None);
+        };
+        if self.native {
+            warn!(
+                "Writing indexed jpeg {} {} R on page {} as it is. --format native doesn't apply its palette",
+                ref_id.0, ref_id.1, page_num
+            );
+            return Ok(None);
+        }
+        let (indexes, width, height) = pdf_image::decode_gray_jpeg(content)?;
+        let samples = pdf_image::apply_palette(&indexes, &palette, base.data_len(1, 1));
+        Ok(Some((samples, (width, height, base))))
+    }
+
+    // The page's rotation with --apply-page-rotation. Images are left as they are otherwise
+    fn applied_rotation(&self, doc: &Document, page_dict: &Dictionary) -> u32 {
+        if self.apply_page_rotation {
+            page_rotation(doc, page_dict)
+        } else {
+            0
+        }
+    }
+
+    fn png_options<'a>(
+        &self,
+        provenance: Option<&'a pdf_image::Provenance>,
+        masked: bool,
+    ) -> pdf_image::PngOptions<'a> {
+        pdf_image::PngOptions {
+            optimize: self.optimize,
+            compression: self.png_compression,
+            provenance,
+            alpha: masked && self.keep_alpha,
+            quantize: self.quantize,
+            eight_bit: self.eight_bit,
+            dither: self.dither,
+        }
+    }
+
+    // Page numbers in the provenance are the page in the source pdf, not the output name
+    fn provenance(
+        &self,
+        ctx: &PageContext,
+        page_num: u32,
+        object: (u32, u16),
+    ) -> Option<pdf_image::Provenance> {
+        self.embed_provenance.then(|| pdf_image::Provenance {
+            source: ctx.source.to_string(),
+            page: ctx.source_page(page_num),
+            object,
+        })
+    }
+
+    // The images --top picks, one set per document. Only the declared sizes are read so nothing is
+    // decoded twice. Ties go to the earlier document and the lower object id
+    fn top_images(&self, docs: &[Document]) -> Option<Vec<BTreeSet<ObjectId>>> {
+        let top = self.top?;
+        let mut sizes = Vec::new();
+        for (index, doc) in docs.iter().enumerate() {
+            for id in info::page_images(doc) {
+                let Ok(stream) = doc.get_object(id).and_then(Object::as_stream) else {
+                    continue;
+                };
+                let dimension = |key: &[u8]| {
+                    declared_dimension(doc, &stream.dict, key).map_or(0, |d| d.max(0) as u64)
+                };
+                let pixels = dimension(b"Width").saturating_mul(dimension(b"Height"));
+                sizes.push((std::cmp::Reverse(pixels), index, id));
+            }
+        }
+        sizes.sort();
+
+        let mut selected = vec![BTreeSet::new(); docs.len()];
+        for (_, index, id) in sizes.into_iter().take(top) {
+            selected[index].insert(id);
+        }
+        Some(selected)
+    }
+
+    // --aspect and --min-megapixels only need the declared size so they run before any decoding
+    fn wanted_size(&self, width: i64, height: i64) -> bool {
+        let aspect = width as f64 / height as f64;
+        self.aspect
+            .is_none_or(|(min, max)| (min..=max).contains(&aspect))
+            && self
+                .min_pixels
+                .is_none_or(|min| (width as u64).saturating_mul(height as u64) >= min)
+    }
+
+    // Once this page and the ones before it hold max images nothing more from it can make the cut
+    fn images_exhausted(&self, ctx: &PageContext, page_num: u32) -> bool {
+        self.max_images.is_some_and(|max| {
+            let claimed = ctx.claimed.lock().unwrap_or_else(|e| e.into_inner());
+            claimed.range(..=page_num).map(|(_, c)| c).sum::<usize>() >= max
+        })
+    }
+
+    // Keep the first max images in page order. Workers can write a few past the cap while earlier
+    // pages are still running so those get removed again
+    fn apply_max_images(&self, written: Vec<PathBuf>) -> Result<Vec<PathBuf>, PDFConError> {
+        let Some(max) = self.max_images.filter(|max| written.len() > *max) else {
+            return Ok(written);
+        };
+        let (kept, extra) = written.split_at(max);
+        let extra: HashSet<&PathBuf> = extra.iter().filter(|p| !kept.contains(p)).collect();
+        for path in extra {
+            self.remove_output(path)?;
+        }
+        Ok(kept.to_vec())
+    }
+
+    // Only with --skip-similar since a jpeg has to be decoded for it. Hashed before any mask,
+    // trim or rotation. An image that can't be hashed is always kept
+    fn record_hash(
+        &self,
+        ctx: &PageContext,
+        path: &Path,
+        image: impl FnOnce() -> Result<image::DynamicImage, PDFConError>,
+    ) {
+        if self.skip_similar.is_none() {
+            return;
+        }
+        match profile::time(Phase::Decode, image) {
+            Ok(image) => {
+                let hash = pdf_image::perceptual_hash(&image);
+                let mut hashes = ctx.hashes.lock().unwrap_or_else(|e| e.into_inner());
+                hashes.insert(path.to_path_buf(), hash);
+            }
+            Err(e) => debug!(
+                "Not comparing {} with the image before it: {{{}}}",
+                path.to_string_lossy(),
+                e
+            ),
+        }
+    }
+
+    // --dump-dict. Named like the image so the two stay together. Pages are the page in the
+    // source pdf like with --embed-provenance
+    fn write_dictionary(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        page_num: u32,
+        id: ObjectId,
+        path: &Path,
+    ) -> Result<(), PDFConError> {
+        if !self.dump_dict || !matches!(ctx.sink, Sink::Files) {
+            return Ok(());
+        }
+        let stream = doc.get_object(id)?.as_stream()?;
+        let filters = stream_filters(doc, &stream.dict)?.unwrap_or_default();
+        if let Some(json) = dump::image_json(doc, id, ctx.source_page(page_num), &filters) {
+            io_limit::write(&path.with_extension("json"), json.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Outputs dropped after the fact take their --dump-dict file along
+    fn remove_output(&self, path: &Path) -> Result<(), PDFConError> {
+        std::fs::remove_file(path)?;
+        let dictionary = path.with_extension("json");
+        if self.dump_dict && dictionary.is_file() {
+            std::fs::remove_file(dictionary)?;
+        }
+        Ok(())
+    }
+
+    // --auto-deskew-rotate. What the pixels say wins over the page's Rotate since it's how the
+    // scan actually lies. Pages it can't tell about keep the rotation they had
+    fn oriented(
+        &self,
+        rotation: u32,
+        path: &Path,
+        image: impl FnOnce() -> Result<image::DynamicImage, PDFConError>,
+    ) -> u32 {
+        if !self.auto_orient {
+            return rotation;
+        }
+        match profile::time(Phase::Decode, image).map(|i| pdf_image::detect_orientation(&i)) {
+            Ok(Some(detected)) => {
+                if detected != rotation {
+                    debug!(
+                        "Turning {} by {} degrees to stand it upright",
+                        path.to_string_lossy(),
+                        detected
+                    );
+                }
+                detected
+            }
+            Ok(None) => rotation,
+            Err(e) => {
+                debug!(
+                    "Can't tell which way up {} is: {{{}}}",
+                    path.to_string_lossy(),
+                    e
+                );
+                rotation
+            }
+        }
+    }
+
+    // Drop images that look like the last one kept. Goes by page order so which one stays never
+    // depends on which worker finished first
+    fn apply_skip_similar(
+        &self,
+        written: Vec<PathBuf>,
+        hashes: HashMap<PathBuf, u64>,
+    ) -> Result<Vec<PathBuf>, PDFConError> {
+        let Some(threshold) = self.skip_similar else {
+            return Ok(written);
+        };
+        let mut kept: Vec<PathBuf> = Vec::with_capacity(written.len());
+        let mut previous: Option<u64> = None;
+        let mut skipped = 0;
+        for path in written {
+            // Pages with several images repeat a name. It's the same file so it stays
+            if kept.last() == Some(&path) {
+                continue;
+            }
+            let hash = hashes.get(&path).copied();
+            if let (Some(hash), Some(previous)) = (hash, previous)
+                && (hash ^ previous).count_ones() <= threshold
+            {
+                debug!(
+                    "Skipping {} since it's {} bits from the image before it",
+                    path.to_string_lossy(),
+                    (hash ^ previous).count_ones()
+                );
+                self.remove_output(&path)?;
+                skipped += 1;
+                continue;
+            }
+            previous = hash;
+            kept.push(path);
+        }
+        if skipped > 0 {
+            warn!(
+                "Skipped {} images that looked like the one before them",
+                skipped
+            );
+        }
+        Ok(kept)
+    }
+
+    fn process_xobject(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        page_num: u32,
+        resources: &Dictionary,
+        rotation: u32,
+        reference: &Object,
+    ) -> Result<Option<PathBuf>, PDFConError> {
+        debug!("Getting xobject information");
+        let ref_id = reference.as_reference()?;
+
+        debug!("Extracting stream");
+        let stream = match doc.get_object(ref_id) {
+            Ok(object) => object.as_stream()?,
+            Err(lopdf::Error::ObjectNotFound(_)) => {
+                // Broken or filtered out on load. Either way there's nothing left to extract
+                warn!(
+                    "Skipping xobject {} {} R on page {}: the object it points at is missing",
+                    ref_id.0, ref_id.1, page_num
+                );
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        debug!("Extracting subtype");
+        let subtype = match get_resolved(doc, &stream.dict, b"Subtype") {
+            Ok(subtype) => subtype.as_name()?,
+            Err(_) if looks_like_image(&stream.dict) => {
+                debug!(
+                    "Xobject {} {} R on page {} has no Subtype. Treating it as an image",
+                    ref_id.0, ref_id.1, page_num
+                );
+                b"Image".as_slice()
+            }
+            Err(_) => return Ok(None),
+        };
+
+        debug!("Checking image");
+        if subtype != b"Image" {
+            // Not an image. No need to continue
+            return Ok(None);
+        }
+        if ctx.selected.is_some_and(|s| !s.contains(&ref_id)) {
+            debug!(
+                "Skipping image {} {} R on page {}: it isn't one of the largest",
+                ref_id.0, ref_id.1, page_num
+            );
+            return Ok(None);
+        }
+        let (ref_id, stream) = if self.prefer_highres {
+            highres_alternate(doc, ref_id, stream)
+        } else {
+            (ref_id, stream)
+        };
+
+        // The stream exactly as it is in the pdf, before any of the decoding below can go wrong
+        if self.dump_raw {
+            io_limit::write(
+                &self
+                    .out_directory
+                    .join(format!("{}_{}.bin", ref_id.0, ref_id.1)),
+                &stream.content,
+            )?;
+        }
+
+        debug!("Checking dimensions");
+        let declared_width = declared_dimension(doc, &stream.dict, b"Width");
+        let declared_height = declared_dimension(doc, &stream.dict, b"Height");
+        if declared_width.is_some_and(|w| w <= 0) || declared_height.is_some_and(|h| h <= 0) {
+            warn!(
+                "Skipping image {} {} R on page {}: it has no pixels",
+                ref_id.0, ref_id.1, page_num
+            );
+            debug!(
+                "Declared size is {}x{}",
+                declared_width.unwrap_or_default(),
+                declared_height.unwrap_or_default()
+            );
+            return Ok(None);
+        }
+
+        if let (Some(width), Some(height)) = (declared_width, declared_height)
+            && !self.wanted_size(width, height)
+        {
+            debug!(
+                "Skipping image {} {} R on page {}: {}x{} is filtered out",
+                ref_id.0, ref_id.1, page_num, width, height
+            );
+            return Ok(None);
+        }
+
+        // Decoded pixels are the big allocation. Assume up to four components since the color
+        // space isn't known yet. Only an estimate: copies made later for masks, trimming, turning
+        // and encoding aren't counted
+        let bits = get_resolved(doc, &stream.dict, b"BitsPerComponent")
+            .and_then(|b| b.as_i64().map_err(PDFConError::from))
+            .unwrap_or(8);
+        let _reserved = memory::reserve(
+            (declared_width.unwrap_or_default() as usize)
+                .saturating_mul(declared_height.unwrap_or_default() as usize)
+                .saturating_mul((4 * bits.clamp(1, 16) as usize).div_ceil(8))
+                .saturating_add(stream.content.len()),
+            self.limit_memory,
+        )?;
+
+        debug!("Grabbing filter");
+        let filters = stream_filters(doc, &stream.dict)?;
+
+        match filters {
+            Some(filter_list) => {
+                // If no filter is present then that means some pdf builder sharted out raw pixel data into the
+                // document. They shouldn't do this ( ImageMagick ) but we probably aught to handle this it.
+                let (content, is_jpeg) = profile::time(Phase::Decode, || {
+                    decode_filters(doc, &stream.dict, &stream.content, filter_list)
+                })?;
+                let indexed = if is_jpeg {
+                    profile::time(Phase::Decode, || {
+                        self.indexed_jpeg(doc, resources, &stream.dict, &content, ref_id, page_num)
+                    })?
+                } else {
+                    None
+                };
+                let is_jpeg = is_jpeg && indexed.is_none();
+                let (content, decoded_info) = match indexed {
+                    Some((samples, info)) => (Cow::Owned(samples), Some(info)),
+                    None => (content, None),
+                };
+
+                let path = self.output_path(
+                    self.output_number(ctx, page_num),
+                    ctx.padding_width,
+                    if is_jpeg { "jpg" } else { "png" },
+                );
+                let provenance = self.provenance(ctx, page_num, ref_id);
+
+                let path = if is_jpeg {
+                    if !self.claim_image(ctx, page_num) {
+                        return Ok(None);
+                    }
+                    // A jpeg's dictionary can still say what color space it's in
+                    let color = image_info(doc, resources, &stream.dict).ok().map(|i| i.2);
+                    let path = self.grouped_path(path, type_group(color.as_ref()));
+                    self.record_hash(ctx, &path, || {
+                        Ok(image::load_from_memory_with_format(
+                            &content,
+                            image::ImageFormat::Jpeg,
+                        )?)
+                    });
+                    let rotation = self.oriented(rotation, &path, || {
+                        Ok(image::load_from_memory_with_format(
+                            &content,
+                            image::ImageFormat::Jpeg,
+                        )?)
+                    });
+                    let data = profile::time(Phase::Encode, || {
+                        pdf_image::finish_jpeg(
+                            &content,
+                            &path,
+                            self.optimize && !self.native,
+                            self.salvage,
+                            provenance.as_ref(),
+                            rotation,
+                            self.strip_thumbnails,
+                        )
+                    })?;
+                    ctx.sink.emit(
+                        &path,
+                        ExtractedImage {
+                            page: page_num,
+                            object: ref_id,
+                            format: "jpg",
+                            data,
+                        },
+                    )?;
+                    self.write_dictionary(doc, ctx, page_num, ref_id, &path)?;
+                    path
+                } else {
+                    let (width, height, color_enum) = match decoded_info {
+                        Some(info) => info,
+                        None => image_info(doc, resources, &stream.dict)?,
+                    };
+                    let content = self.fit_pixel_data(
+                        &content,
+                        width,
+                        height,
+                        &color_enum,
+                        ref_id,
+                        page_num,
+                    )?;
+                    if !self.claim_image(ctx, page_num) {
+                        return Ok(None);
+                    }
+                    let masked =
+                        self.apply_mask(doc, &stream.dict, &content, width, height, &color_enum);
+                    let path = self.grouped_path(path, type_group(Some(&color_enum)));
+                    self.record_hash(ctx, &path, || {
+                        pdf_image::samples_to_image(&content, width, height, &color_enum, None)
+                    });
+                    let rotation = self.oriented(rotation, &path, || {
+                        pdf_image::samples_to_image(&content, width, height, &color_enum, None)
+                    });
+                    self.check_conversion(ctx, &color_enum, ref_id, page_num)?;
+                    let options = self.png_options(provenance.as_ref(), masked.is_some());
+                    let (content, width, height) = trim_samples(
+                        masked.as_deref().unwrap_or(&content),
+                        width,
+                        height,
+                        &color_enum,
+                        options.alpha,
+                        self.trim,
+                    );
+                    let (content, width, height) = rotate_samples(
+                        &content,
+                        width,
+                        height,
+                        &color_enum,
+                        options.alpha,
+                        rotation,
+                    );
+
+                    let data = profile::time(Phase::Encode, || {
+                        pdf_image::encode_png(&content, width, height, &color_enum, options)
+                    })?;
+                    ctx.sink.emit(
+                        &path,
+                        ExtractedImage {
+                            page: page_num,
+                            object: ref_id,
+                            format: "png",
+                            data,
+                        },
+                    )?;
+                    self.write_dictionary(doc, ctx, page_num, ref_id, &path)?;
+                    path
+                };
+
+                Ok(Some(path))
+            }
+            None => {
+                // This is a raw pixel buffer. We can encode this in any format we'd like
+                // Treat it like its a png
+                debug!("Raw pixel buffer");
+                let (width, height, color_enum) = image_info(doc, resources, &stream.dict)?;
+                let content = self.fit_pixel_data(
+                    &stream.content,
+                    width,
+                    height,
+                    &color_enum,
+                    ref_id,
+                    page_num,
+                )?;
+                if !self.claim_image(ctx, page_num) {
+                    return Ok(None);
+                }
+
+                let path =
+                    self.output_path(self.output_number(ctx, page_num), ctx.padding_width, "png");
+                let path = self.grouped_path(path, type_group(Some(&color_enum)));
+                self.record_hash(ctx, &path, || {
+                    pdf_image::samples_to_image(&content, width, height, &color_enum, None)
+                });
+                let rotation = self.oriented(rotation, &path, || {
+                    pdf_image::samples_to_image(&content, width, height, &color_enum, None)
+                });
+                let provenance = self.provenance(ctx, page_num, ref_id);
+                let masked =
+                    self.apply_mask(doc, &stream.dict, &content, width, height, &color_enum);
+                self.check_conversion(ctx, &color_enum, ref_id, page_num)?;
+                let options = self.png_options(provenance.as_ref(), masked.is_some());
+                let (content, width, height) = trim_samples(
+                    masked.as_deref().unwrap_or(&content),
+                    width,
+                    height,
+                    &color_enum,
+                    options.alpha,
+                    self.trim,
+                );
+                let (content, width, height) = rotate_samples(
+                    &content,
+                    width,
+                    height,
+                    &color_enum,
+                    options.alpha,
+                    rotation,
+                );
+
+                let data = profile::time(Phase::Encode, || {
+                    pdf_image::encode_png(&content, width, height, &color_enum, options)
+                })?;
+                ctx.sink.emit(
+                    &path,
+                    ExtractedImage {
+                        page: page_num,
+                        object: ref_id,
+                        format: "png",
+                        data,
+                    },
+                )?;
+                self.write_dictionary(doc, ctx, page_num, ref_id, &path)?;
+
+                Ok(Some(path))
+            }
+        }
+    }
+
+    fn process_xobject_dict(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        page_num: u32,
+        resources: &Dictionary,
+        rotation: u32,
+        written: &mut Vec<(Vec<u8>, PathBuf)>,
+    ) -> Result<(), PDFConError> {
+        let x_obj_dict = match get_resolved(doc, resources, b"XObject") {
+            Ok(x) => x.as_dict()?,
+            Err(_) => return Ok(()),
+        };
+        for (name, x_ref) in x_obj_dict.iter() {
+            if self.images_exhausted(ctx, page_num) {
+                break;
+            }
+            if let Some(path) =
+                self.process_xobject(doc, ctx, page_num, resources, rotation, x_ref)?
+            {
+                written.push((name.clone(), path));
+            }
+        }
+        Ok(())
+    }
+
+    // Tiling patterns carry their own resources. Scans sometimes end up as an image tiled through
+    // a pattern instead of being drawn directly so look at those too
+    fn process_patterns(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        page_num: u32,
+        pattern_dict: &Dictionary,
+        rotation: u32,
+        written: &mut Vec<(Vec<u8>, PathBuf)>,
+    ) -> Result<(), PDFConError> {
+        for (name, pattern_ref) in pattern_dict.iter() {
+            let (_, pattern) = doc.dereference(pattern_ref)?;
+            let Ok(pattern) = pattern.as_stream() else {
+                // Shading patterns are plain dictionaries and never hold images
+                continue;
+            };
+            if !get_resolved(doc, &pattern.dict, b"PatternType")
+                .and_then(|t| t.as_i64().map_err(PDFConError::from))
+                .is_ok_and(|t| t == 1)
+            {
+                continue;
+            }
+            let Ok(resources) = get_resolved(doc, &pattern.dict, b"Resources")
+                .and_then(|r| r.as_dict().map_err(PDFConError::from))
+            else {
+                continue;
+            };
+            debug!(
+                "Checking images in pattern {}",
+                String::from_utf8_lossy(name)
+            );
+            self.process_xobject_dict(doc, ctx, page_num, resources, rotation, written)?;
+        }
+        Ok(())
+    }
+
+    fn find_xobject_images_in_page(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        page_num: u32,
+        page_dict: &Dictionary,
+    ) -> Result<Vec<(Vec<u8>, PathBuf)>, PDFConError> {
+        debug!("Getting resources and xobjects");
+        let resources_dict = page_dict.get(b"Resources")?.as_dict()?;
+        let rotation = self.applied_rotation(doc, page_dict);
+        let mut written = Vec::new();
+        // A page without either has no images
+        self.process_xobject_dict(doc, ctx, page_num, resources_dict, rotation, &mut written)?;
+        if let Ok(p) = get_resolved(doc, resources_dict, b"Pattern") {
+            self.process_patterns(doc, ctx, page_num, p.as_dict()?, rotation, &mut written)?;
+        }
+        Ok(written)
+    }
+
+    // --composite. The images a page draws itself are decoded into memory first since the images
+    // of a page all share its name. They're put on one canvas through the matrices the content
+    // stream draws them with and only that is written for them. Images the page doesn't draw
+    // directly, like ones in patterns, are written as usual. Pages with fewer than two placed
+    // images are extracted as usual
+    fn composite_page(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        page_num: u32,
+        page_id: ObjectId,
+        page_dict: &Dictionary,
+    ) -> Result<Vec<(Vec<u8>, PathBuf)>, PDFConError> {
+        let resources = page_dict.get(b"Resources")?.as_dict()?;
+        let xobjects = get_resolved(doc, resources, b"XObject")
+            .and_then(|x| Ok(x.as_dict()?))
+            .ok();
+        // Placements go by the object a name points at so nothing else sharing the name can be
+        // mistaken for the image
+        let placements: Vec<(ObjectId, svg::Matrix)> = composite::image_placements(doc, page_id)?
+            .into_iter()
+            .filter_map(|(name, matrix)| {
+                let id = xobjects?.get(&name).ok()?.as_reference().ok()?;
+                let stream = doc.get_object(id).ok()?.as_stream().ok()?;
+                get_resolved(doc, &stream.dict, b"Subtype")
+                    .map_or_else(
+                        |_| looks_like_image(&stream.dict),
+                        |s| s.as_name().is_ok_and(|s| s == b"Image"),
+                    )
+                    .then_some((id, matrix))
+            })
+            .collect();
+        if placements.len() < 2 {
+            debug!(
+                "Page {} draws {} images directly. Extracting it as usual",
+                page_num,
+                placements.len()
+            );
+            return self.find_xobject_images_in_page(doc, ctx, page_num, page_dict);
+        }
+        let placed: BTreeSet<ObjectId> = placements.iter().map(|(id, _)| *id).collect();
+
+        let decoded = Mutex::new(Vec::new());
+        let layers_ctx = PageContext {
+            sink: Sink::Memory(&decoded),
+            ..*ctx
+        };
+        // Layers are drawn as they're stored. Turning, trimming and the rest apply to the whole
+        // page afterwards
+        let layers_unpack = Unpack {
+            apply_page_rotation: false,
+            auto_orient: false,
+            trim: None,
+            optimize: false,
+            quantize: None,
+            embed_provenance: false,
+            keep_alpha: true,
+            max_images: None,
+            skip_similar: None,
+            group_by_type: false,
+            ..self.clone()
+        };
+        for id in &placed {
+            let reference = Object::Reference(*id);
+            layers_unpack.process_xobject(doc, &layers_ctx, page_num, resources, 0, &reference)?;
+        }
+        let decoded = decoded
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .into_iter()
+            .map(|i| Ok((i.object, image::load_from_memory(&i.data)?)))
+            .collect::<Result<HashMap<_, _>, PDFConError>>()?;
+        let layers: Vec<_> = placements
+            .iter()
+            .filter_map(|(id, matrix)| Some((*matrix, decoded.get(id)?)))
+            .collect();
+
+        // Written before the composite so it keeps the page's name
+        let rotation = self.applied_rotation(doc, page_dict);
+        let mut written = Vec::new();
+        for (name, x_ref) in xobjects.iter().flat_map(|x| x.iter()) {
+            if x_ref.as_reference().is_ok_and(|id| placed.contains(&id)) {
+                continue;
+            }
+            if self.images_exhausted(ctx, page_num) {
+                break;
+            }
+            if let Some(path) =
+                self.process_xobject(doc, ctx, page_num, resources, rotation, x_ref)?
+            {
+                written.push((name.clone(), path));
+            }
+        }
+        if let Ok(p) = get_resolved(doc, resources, b"Pattern") {
+            self.process_patterns(doc, ctx, page_num, p.as_dict()?, rotation, &mut written)?;
+        }
+
+        let Some(canvas) = profile::time(Phase::Decode, || {
+            composite::composite(&layers, self.keep_alpha, self.limit_memory)
+        })?
+        else {
+            return Ok(written);
+        };
+        if !self.claim_image(ctx, page_num) {
+            return Ok(written);
+        }
+
+        let path = self.output_path(self.output_number(ctx, page_num), ctx.padding_width, "png");
+        let path = self.grouped_path(path, "color");
+        let canvas = image::DynamicImage::ImageRgba8(canvas);
+        self.record_hash(ctx, &path, || Ok(canvas.clone()));
+        let rotation = self.oriented(self.applied_rotation(doc, page_dict), &path, || {
+            Ok(canvas.clone())
+        });
+        let (width, height) = (canvas.width(), canvas.height());
+        let (content, alpha) = if self.keep_alpha {
+            (canvas.into_rgba8().into_raw(), true)
+        } else {
+            (canvas.into_rgb8().into_raw(), false)
+        };
+        let provenance = self.provenance(ctx, page_num, placements[0].0);
+        let options = pdf_image::PngOptions {
+            alpha,
+            ..self.png_options(provenance.as_ref(), false)
+        };
+        let color = PDFConColorSpace::RGB8;
+        let (content, width, height) =
+            trim_samples(&content, width, height, &color, alpha, self.trim);
+        let (content, width, height) =
+            rotate_samples(&content, width, height, &color, alpha, rotation);
+        let data = profile::time(Phase::Encode, || {
+            pdf_image::encode_png(&content, width, height, &color, options)
+        })?;
+        ctx.sink.emit(
+            &path,
+            ExtractedImage {
+                page: page_num,
+                object: placements[0].0,
+                format: "png",
+                data,
+            },
+        )?;
+        written.push((Vec::new(), path));
+        Ok(written)
+    }
+
+    // Images are linked by file name so the svg has to sit in the same directory as them
+    fn write_svg(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        page_num: u32,
+        page_id: ObjectId,
+        images: &[(Vec<u8>, PathBuf)],
+    ) -> Result<(), PDFConError> {
+        let hrefs: HashMap<Vec<u8>, String> = images
+            .iter()
+            .filter_map(|(name, path)| {
+                Some((
+                    name.clone(),
+                    path.strip_prefix(&self.out_directory)
+                        .ok()?
+                        .to_string_lossy()
+                        .to_string(),
+                ))
+            })
+            .collect();
+        if let Some(svg) = svg::page_svg(doc, page_id, &hrefs)? {
+            io_limit::write(
+                &self.output_path(self.output_number(ctx, page_num), ctx.padding_width, "svg"),
+                svg.as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Text goes next to the page's image under the same number. lopdf decodes it through the
+    // font encodings and ToUnicode maps. Fonts it can't decode only cost the text, not the page
+    fn write_text(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        page_num: u32,
+    ) -> Result<(), PDFConError> {
+        let text = match doc.extract_text(&[ctx.source_page(page_num)]) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("Failed to extract the text of page {}: {{{}}}", page_num, e);
+                return Ok(());
+            }
+        };
+        if text.trim().is_empty() {
+            return Ok(());
+        }
+        io_limit::write(
+            &self.output_path(self.output_number(ctx, page_num), ctx.padding_width, "txt"),
+            text.as_bytes(),
+        )?;
+        Ok(())
+    }
+
+    // --object. Pages are only searched for the one that draws the image so it's named after that
+    // page and named color spaces still resolve against its resources. Nothing is decoded but
+    // the image itself
+    fn extract_object(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+        id: ObjectId,
+    ) -> Result<Vec<(u32, PathBuf)>, PDFConError> {
+        let drawn_on = doc.get_pages().into_iter().find_map(|(page, page_id)| {
+            let page_dict = doc.get_dictionary(page_id).ok()?;
+            let resources = get_resolved(doc, page_dict, b"Resources")
+                .ok()?
+                .as_dict()
+                .ok()?;
+            let xobjects = get_resolved(doc, resources, b"XObject")
+                .ok()?
+                .as_dict()
+                .ok()?;
+            xobjects
+                .iter()
+                .any(|(_, x)| x.as_reference().is_ok_and(|x| x == id))
+                .then(|| (page, resources, self.applied_rotation(doc, page_dict)))
+        });
+        let empty = Dictionary::new();
+        let (page, resources, rotation) = drawn_on.unwrap_or_else(|| {
+            warn!(
+                "Object {} {} R isn't drawn directly on any page. Naming it after page 1",
+                id.0, id.1
+            );
+            (1, &empty, 0)
+        });
+
+        let page_num = ctx.page_number(page);
+        let reference = Object::Reference(id);
+        match self.process_xobject(doc, ctx, page_num, resources, rotation, &reference) {
+            Ok(Some(path)) => Ok(vec![(page_num, path)]),
+            Ok(None) => {
+                error!(
+                    "Object {} {} R isn't an image that can be written",
+                    id.0, id.1
+                );
+                Err(PDFConError::UnpackError)
+            }
+            Err(e) => {
+                error!("Failed to extract object {} {} R: {{{}}}", id.0, id.1, e);
+                Err(PDFConError::UnpackError)
+            }
+        }
+    }
+
+    // Rename outputs so they are numbered 1..n in page order. Outputs only ever move to a number
+    // at or below their page number so renaming in ascending order can't clobber a pending file.
+    // Each --group-by-type subdirectory is numbered on its own
+    fn renumber_contiguous(
+        &self,
+        written: &[PathBuf],
+        padding_width: usize,
+    ) -> Result<Vec<PathBuf>, PDFConError> {
+        let mut renamed = Vec::with_capacity(written.len());
+        let mut counts: HashMap<PathBuf, u32> = HashMap::new();
+        for path in written {
+            let directory = path.parent().unwrap_or(&self.out_directory).to_path_buf();
+            let number = counts.entry(directory.clone()).or_default();
+            *number += 1;
+            let target = directory.join(
+                self.output_path(
+                    *number + self.page_offset,
+                    padding_width,
+                    &path.extension().unwrap_or_default().to_string_lossy(),
+                )
+                .file_name()
+                .unwrap_or_default(),
+            );
+            if *path != target {
+                std::fs::rename(path, &target)?;
+            }
+            renamed.push(target);
+        }
+        Ok(renamed)
+    }
+
+    // Results come back in the order of the items. --threads 1 maps them right here on the
+    // calling thread since handing them to rayon would only add overhead with one worker
+    fn map_items<'a, T: Sync, R: Send>(
+        &self,
+        items: &'a [T],
+        pb: &ProgressBar,
+        f: impl Fn(&'a T) -> R + Sync + Send,
+    ) -> Vec<R> {
+        if self.threads == 1 {
+            return items.iter().inspect(|_| pb.inc(1)).map(f).collect();
+        }
+        items.par_iter().progress_with(pb.clone()).map(f).collect()
+    }
+
+    // Written paths with the page number they came from
+    fn extract_document(
+        &self,
+        doc: &Document,
+        ctx: &PageContext,
+    ) -> Result<Vec<(u32, PathBuf)>, PDFConError> {
+        let pages = doc.get_pages().into_iter().collect::<Vec<_>>();
+        let total_pages = pages.len();
+
+        // Initialize the progress bar
+        let pb = bar("Processing Images", total_pages as u64, tick_speed());
+        let lossy_before = ctx.lossy.load(Ordering::Relaxed);
+
+        // Errors are held on to until the bar is closed so they don't interleave with it.
+        // Collecting keeps page order no matter which worker finished first
+        let results: Vec<(u32, Result<Vec<PathBuf>, PDFConError>)> =
+            self.map_items(&pages, &pb, |(page_num, page_id)| {
+                let pos = pb.position();
+                let total = pb.length().unwrap();
+
+                // Update bars end cap based on current progress
+                update_end_cap(&pb, pos, total);
+
+                let page_num = ctx.page_number(*page_num);
+                if self.images_exhausted(ctx, page_num)
+                    || ctx.checkpoint.is_some_and(|c| c.is_done(page_num))
+                {
+                    return (page_num, Ok(Vec::new()));
+                }
+
+                debug!("Getting page dict");
+                let result: Result<Vec<PathBuf>, PDFConError> = doc
+                    .get_object(*page_id)
+                    .and_then(|o| o.as_dict())
+                    .map_err(PDFConError::from)
+                    .and_then(|page_dict| {
+                        let images = if self.composite {
+                            self.composite_page(doc, ctx, page_num, *page_id, page_dict)?
+                        } else {
+                            self.find_xobject_images_in_page(doc, ctx, page_num, page_dict)?
+                        };
+                        if self.svg {
+                            self.write_svg(doc, ctx, page_num, *page_id, &images)?;
+                        }
+                        if self.extract_text {
+                            self.write_text(doc, ctx, page_num)?;
+                        }
+                        Ok(images.into_iter().map(|(_, path)| path).collect())
+                    });
+                if let (Some(checkpoint), Ok(paths)) = (ctx.checkpoint, &result) {
+                    checkpoint.finish_page(page_num, paths.len());
+                }
+                (page_num, result)
+            });
+
+        // Finish bar and display message. Pages with several images can share a name so only
+        // distinct paths count
+        let extracted = results
+            .iter()
+            .filter_map(|(_, r)| r.as_ref().ok())
+            .flatten()
+            .collect::<HashSet<_>>()
+            .len();
+        let lossy = ctx.lossy.load(Ordering::Relaxed) - lossy_before;
+        let summary = if lossy == 0 {
+            format!(" ● Processing Complete! {} images ", extracted)
+        } else {
+            format!(
+                " ● Processing Complete! {} images, {} had their colors approximated ",
+                extracted, lossy
+            )
+        };
+        close_bar(pb, &summary);
+
+        // Log any errors and return a general error
+        let mut failed = 0;
+        let mut written = Vec::new();
+        for (page_num, result) in results {
+            match result {
+                Ok(paths) => written.extend(paths.into_iter().map(|p| (page_num, p))),
+                Err(e) => {
+                    failed += 1;
+                    error!("Failed to extract image from page {}: {{{}}}", page_num, e)
+                }
+            }
+        }
+        if failed > 0 && failed == total_pages {
+            error!(
+                "Every one of the {} pages of {} failed",
+                total_pages, ctx.source
+            );
+        }
+        if failed > 0 {
+            return Err(PDFConError::UnpackError);
+        }
+
+        Ok(written)
+    }
+
+    // --collate takes the odd pages from the first document and the even pages from the second,
+    // like a duplex document scanned one side at a time. Empty without --collate
+    fn collation(&self, docs: &[Document]) -> Result<Vec<Collated>, PDFConError> {
+        if !self.collate {
+            return Ok(Vec::new());
+        }
+        let [fronts, backs] = docs else {
+            return Err(PDFConError::CollateError(format!(
+                "it takes exactly two pdfs but {} were given",
+                docs.len()
+            )));
+        };
+        let (fronts, backs) = (
+            fronts.get_pages().len() as u32,
+            backs.get_pages().len() as u32,
+        );
+        // The first document can have one more page when the last page has no back
+        if backs > fronts || fronts > backs + 1 {
+            return Err(PDFConError::CollateError(format!(
+                "{} odd pages don't pair up with {} even pages",
+                fronts, backs
+            )));
+        }
+        Ok(vec![
+            Collated::Fronts,
+            Collated::Backs {
+                reversed: self.reverse_even.then_some(backs),
+            },
+        ])
+    }
+
+    // A run that writes nothing looks the same as one that worked. Say why, and fail with
+    // --strict. Pages that failed have already been reported by the time this is reached
+    fn nothing_extracted(&self, total_pages: usize) -> Result<(), PDFConError> {
+        let reason = if total_pages == 0 {
+            String::from("the pdf has no pages")
+        } else if self.object.is_some() || self.top.is_some() || self.only_types.is_some() {
+            format!("nothing selected on the {} pages is an image", total_pages)
+        } else {
+            format!("none of the {} pages have images", total_pages)
+        };
+        if self.strict {
+            return Err(PDFConError::NoImagesError(reason));
+        }
+        warn!("No images were extracted, {}", reason);
+        Ok(())
+    }
+
+    // Pages are numbered continuously across all the documents in the order they're given
+    pub(crate) fn extract_images(&self, docs: &[Document]) -> Result<Vec<PathBuf>, PDFConError> {
+        let total_pages = docs.iter().map(|d| d.get_pages().len()).sum();
+        let padding_width = self.padding_width(total_pages);
+
+        // Shared by every page of every document so --max-images caps the whole run
+        let claimed = Mutex::new(BTreeMap::new());
+        let hashes = Mutex::new(HashMap::new());
+        let lossy = AtomicUsize::new(0);
+        let checkpoint = self
+            .checkpoint
+            .map(|interval| Checkpoint::load(&self.out_directory, interval))
+            .transpose()?;
+        let resumed = checkpoint.as_ref().map_or(0, |c| c.resumed_pages());
+        if resumed > 0 {
+            warn!(
+                "Resuming from a checkpoint. Skipping {} pages that are already done",
+                resumed
+            );
+        }
+
+        let mut error_encountered = false;
+        let mut written = Vec::new();
+        let mut page_offset = 0;
+        let collated = self.collation(docs)?;
+        let top = self.top_images(docs);
+        for (index, doc) in docs.iter().enumerate() {
+            let source = self
+                .in_file
+                .get(index)
+                .and_then(|f| f.file_name())
+                .map(|f| f.to_string_lossy())
+                .unwrap_or_default();
+            let ctx = PageContext {
+                source: &source,
+                page_offset,
+                total_pages: total_pages as u32,
+                padding_width,
+                claimed: &claimed,
+                collated: collated.get(index).copied(),
+                lossy: &lossy,
+                checkpoint: checkpoint.as_ref(),
+                sink: Sink::Files,
+                selected: top.as_ref().and_then(|t| t.get(index)),
+                hashes: &hashes,
+            };
+            let extracted = match self.object {
+                Some(id) => self.extract_object(doc, &ctx, id),
+                None => self.extract_document(doc, &ctx),
+            };
+            match extracted {
+                Ok(paths) => written.extend(paths),
+                Err(_) => error_encountered = true,
+            }
+            page_offset += doc.get_pages().len() as u32;
+        }
+        if error_encountered {
+            // Keep the pages that did finish for the next run
+            if let Some(checkpoint) = &checkpoint {
+                checkpoint.save();
+            }
+            return Err(PDFConError::UnpackError);
+        }
+        if let Some(checkpoint) = &checkpoint {
+            checkpoint.remove()?;
+        }
+        // Pages a checkpoint skipped were written by the earlier run
+        if written.is_empty() && resumed == 0 {
+            self.nothing_extracted(total_pages)?;
+        }
+
+        // Each document comes back in page order. Collated documents still have to be merged.
+        // The sort is stable so images keep their order within a page
+        written.sort_by_key(|(page_num, _)| *page_num);
+        let written = written.into_iter().map(|(_, path)| path).collect();
+
+        // Still in page order here no matter which pages finished first
+        let written = self.apply_skip_similar(
+            written,
+            hashes.into_inner().unwrap_or_else(|e| e.into_inner()),
+        )?;
+        let mut written = self.apply_max_images(written)?;
+
+        // Keep the list in name order for renumbering
+        if self.reverse {
+            written.reverse();
+        }
+
+        // Pages with several images reuse the same name so only keep the one that survived
+        written.dedup();
+
+        if self.contiguous {
+            written = self.renumber_contiguous(&written, padding_width)?;
+        }
+
+        Ok(written)
+    }
+}
+
+// create_dir_all only says "File exists" or "Not a directory" when the path or one of its parents
+// is a file. Find which one up front so the error can name it
+pub(crate) fn check_directory(path: &Path) -> Result<(), PDFConError> {
+    let existing = path
+        .ancestors()
+        .find(|a| !a.as_os_str().is_empty() && a.exists());
+    match existing {
+        Some(existing) if !existing.is_dir() => Err(PDFConError::NotADirectoryError(
+            existing.to_string_lossy().to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl Unpack {
+    // Old outputs left in the directory would get mixed in with the new ones
+    fn check_clobber(&self, directory: &Path) -> Result<(), PDFConError> {
+        // Files next to a checkpoint are the ones the interrupted run wrote
+        if self.force || (self.checkpoint.is_some() && Checkpoint::exists(directory)) {
+            return Ok(());
+        }
+        // A directory that doesn't exist yet is as good as empty
+        let has_files = std::fs::read_dir(directory).is_ok_and(|mut e| e.next().is_some());
+        if has_files {
+            return Err(PDFConError::OutputNotEmptyError(
+                directory.to_string_lossy().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn load_documents(&self) -> Result<Vec<Document>, PDFConError> {
+        let mut documents = Vec::with_capacity(self.in_file.len());
+        for in_file in &self.in_file {
+            // Reading shows how many bytes are in so far. Parsing has no way to report how far
+            // along it is so it gets a spinner
+            let buffer = profile::time(Phase::Parse, || {
+                read_file(in_file, "Reading PDF", tick_speed())
+            })?;
+            let spnr = spinner("Parsing PDF", tick_speed());
+            spnr.set_message(in_file.to_string_lossy().to_string());
+
+            let loaded = profile::time(Phase::Parse, || {
+                Reader {
+                    buffer: &buffer,
+                    document: Document::new(),
+                }
+                .read(Some(self.load_filter()))
+            });
+            // A broken xref often still loads but without the objects it points at wrong. Load
+            // again from a rebuilt xref. None when even that doesn't give a usable document so
+            // the original failure is what gets reported
+            let broken = self.repair && !loaded.as_ref().is_ok_and(|d| d.catalog().is_ok());
+            let repaired = profile::time(Phase::Parse, || {
+                broken
+                    .then(|| load_repaired(&buffer, self.load_filter()))
+                    .flatten()
+            });
+
+            let was_repaired = repaired.is_some();
+            let mut document = match repaired {
+                Some(document) => document,
+                None => loaded?,
+            };
+            let recovery = if self.tolerant {
+                Some(repair::recover(&buffer, &mut document))
+            } else {
+                None
+            };
+            self.drop_unlisted_types(&mut document);
+
+            // Finish bar and display message. Say so when the source was damaged
+            let message = if recovery.as_ref().is_some_and(|r| r.is_damaged()) {
+                " ● Parsing Complete! The document was damaged and only partly recovered "
+            } else if was_repaired {
+                " ● Parsing Complete! The cross-reference table was damaged and had to be rebuilt "
+            } else {
+                " ● Parsing Complete! "
+            };
+            documents.push(document);
+            close_bar(spnr, message);
+            if let Some(recovery) = &recovery {
+                report_recovery(&in_file.to_string_lossy(), recovery);
+            }
+        }
+        Ok(documents)
+    }
+
+    // Attachments keep their own names and go next to the images. Nothing already in the
+    // directory is replaced
+    fn write_attachments(&self, documents: &[Document]) -> Result<(), PDFConError> {
+        for (index, doc) in documents.iter().enumerate() {
+            let embedded = attachments::embedded_files(doc).inspect_err(|e| {
+                error!(
+                    "Failed to read the attachments of {}: {{{}}}",
+                    self.in_file
+                        .get(index)
+                        .map(|f| f.to_string_lossy())
+                        .unwrap_or_default(),
+                    e
+                )
+            })?;
+            for attachment in &embedded {
+                attachments::write(&self.out_directory, attachment)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Profiles are named after a hash of their content so one that several documents share is
+    // only written once
+    fn write_icc_profiles(&self, documents: &[Document]) -> Result<(), PDFConError> {
+        let mut written = HashSet::new();
+        for doc in documents {
+            for profile in icc::color_profiles(doc) {
+                if written.insert(profile.name.clone()) {
+                    attachments::write(&self.out_directory, &profile)?;
+                }
+            }
+        }
+        debug!("Wrote {} ICC profiles", written.len());
+        Ok(())
+    }
+
+    // Decode every output again and check it comes out at the size its header promises. Catches
+    // truncated writes and encoder bugs that would otherwise only show up when someone opens it
+    fn verify_outputs(&self, written: &[PathBuf]) -> Result<(), PDFConError> {
+        let pb = bar("Verifying Images", written.len() as u64, tick_speed());
+
+        let results: Vec<(&PathBuf, Result<(), PDFConError>)> =
+            self.map_items(written, &pb, |path| {
+                let result = image::image_dimensions(path)
+                    .and_then(|expected| Ok((expected, image::open(path)?)))
+                    .map_err(PDFConError::from)
+                    .and_then(|((width, height), decoded)| {
+                        if (decoded.width(), decoded.height()) == (width, height) {
+                            Ok(())
+                        } else {
+                            Err(PDFConError::VerifyError(format!(
+                                "decoded to {}x{} instead of {}x{}",
+                                decoded.width(),
+                                decoded.height(),
+                                width,
+                                height
+                            )))
+                        }
+                    });
+                (path, result)
+            });
+
+        close_bar(pb, " ● Verifying Complete! ");
+
+        let mut failed = 0;
+        for (path, result) in results {
+            if let Err(e) = result {
+                failed += 1;
+                error!("Failed to verify {}: {{{}}}", path.to_string_lossy(), e);
+            }
+        }
+        if failed > 0 {
+            return Err(PDFConError::VerifyError(format!(
+                "{} of {} images are unreadable",
+                failed,
+                written.len()
+            )));
+        }
+
+        Ok(())
+    }
+
+    // The command is split into arguments and run directly, not through a shell, so paths with
+    // spaces in them stay one argument
+    fn exec_command(&self, template: &str, path: &Path) -> Result<(), PDFConError> {
+        let path = path.to_string_lossy();
+        let mut args = split_command(template)?
+            .into_iter()
+            .map(|arg| arg.replace("{path}", &path));
+        let Some(program) = args.next() else {
+            return Err(PDFConError::ExecError(String::from("the command is empty")));
+        };
+        let status = std::process::Command::new(&program)
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(PDFConError::ExecError(format!(
+                "{} exited with {}",
+                program, status
+            )));
+        }
+        Ok(())
+    }
+
+    // Commands run on the same pool as the extraction so --threads limits them too. A failed
+    // command is only fatal with --strict
+    fn run_exec(&self, written: &[PathBuf]) -> Result<(), PDFConError> {
+        let Some(template) = &self.exec else {
+            return Ok(());
+        };
+        let pb = bar("Running Commands", written.len() as u64, tick_speed());
+
+        let results: Vec<(&PathBuf, Result<(), PDFConError>)> =
+            self.map_items(written, &pb, |path| {
+                (path, self.exec_command(template, path))
+            });
+
+        let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+        let summary = if failed == 0 {
+            String::from(" ● Commands Complete! ")
+        } else {
+            format!(
+                " ● Commands Complete! {} of {} failed ",
+                failed,
+                written.len()
+            )
+        };
+        close_bar(pb, &summary);
+
+        for (path, result) in results {
+            if let Err(e) = result {
+                error!("Command failed for {}: {{{}}}", path.to_string_lossy(), e);
+            }
+        }
+        if failed > 0 && self.strict {
+            return Err(PDFConError::ExecError(format!(
+                "{} of {} commands failed",
+                failed,
+                written.len()
+            )));
+        }
+        Ok(())
+    }
+
+    // Extract into a scratch directory and hand the one image over to deliver. Anything other than
+    // exactly one image is an error since there's no sensible file to pick
+    fn extract_single_image<F>(
+        &self,
+        documents: &[Document],
+        staging: &Path,
+        target: &str,
+        deliver: F,
+    ) -> Result<(), PDFConError>
+    where
+        F: FnOnce(&Path) -> Result<(), PDFConError>,
+    {
+        std::fs::create_dir_all(staging)?;
+
+        let staged = Unpack {
+            out_directory: staging.to_path_buf(),
+            contiguous: false,
+            ..self.clone()
+        };
+        let result = staged
+            .extract_images(documents)
+            .and_then(|written| match written.as_slice() {
+                [image] => deliver(image),
+                images => Err(PDFConError::OutputFormatError(format!(
+                    "{} images were found but {} can only hold one. Use a directory or --max-images 1",
+                    images.len(),
+                    target
+                ))),
+            });
+
+        std::fs::remove_dir_all(staging)?;
+        result
+    }
+
+    // Extract into a scratch directory next to path and hand it and every image in it over to
+    // combine in page order. Used by the outputs that put all the images into one file
+    fn extract_combined<F>(
+        &self,
+        documents: &[Document],
+        path: &Path,
+        combine: F,
+    ) -> Result<(), PDFConError>
+    where
+        F: FnOnce(&Path, &[PathBuf]) -> Result<(), PDFConError>,
+    {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let staging = parent.join(format!(".pdfcon-{}", std::process::id()));
+        std::fs::create_dir_all(&staging)?;
+
+        let staged = Unpack {
+            out_directory: staging.clone(),
+            contiguous: false,
+            ..self.clone()
+        };
+        let result = staged.extract_images(documents).and_then(|written| {
+            if self.verify_output {
+                self.verify_outputs(&written)?;
+            }
+            combine(&staging, &written)
+        });
+
+        std::fs::remove_dir_all(&staging)?;
+        result
+    }
+
+    // Extraction still runs in parallel into a scratch directory. The pages are then appended one
+    // at a time in the order extract_images returns them, which is page order
+    fn write_tiff(&self, documents: &[Document], path: &Path) -> Result<(), PDFConError> {
+        // The pages have no alpha channel so masks are always blended onto the background
+        let flattened = Unpack {
+            keep_alpha: false,
+            ..self.clone()
+        };
+        flattened.extract_combined(documents, path, |_, written| {
+            let pb = bar("Writing TIFF", written.len() as u64, tick_speed());
+            let mut tiff =
+                MultipageTiff::new(std::io::BufWriter::new(std::fs::File::create(path)?))?;
+            for image in written {
+                tiff.add_page(&open_oriented(image)?)?;
+                pb.inc(1);
+            }
+            tiff.finish()?;
+            close_bar(pb, " ● Writing Complete! ");
+            Ok(())
+        })
+    }
+
+    // The images go in as they are, named by their path under the staging directory so the page
+    // numbers in the names run on from one part to the next. With cbz_chunk a new archive starts
+    // every N images and the parts are named book.part001.cbz and so on next to book.cbz
+    fn write_cbz(&self, documents: &[Document], path: &Path) -> Result<(), PDFConError> {
+        self.extract_combined(documents, path, |staging, written| {
+            let chunk = self.cbz_chunk.unwrap_or(written.len()).max(1);
+            let mut parts: Vec<&[PathBuf]> = written.chunks(chunk).collect();
+            // Without images there's still an archive, just an empty one, so the run never
+            // succeeds without leaving path behind
+            if parts.is_empty() {
+                parts.push(&[]);
+            }
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = path.extension().unwrap_or_default().to_string_lossy();
+
+            let pb = bar("Writing CBZ", written.len() as u64, tick_speed());
+            for (i, part) in parts.iter().enumerate() {
+                let target = if self.cbz_chunk.is_some() {
+                    path.with_file_name(format!("{}.part{:03}.{}", stem, i + 1, extension))
+                } else {
+                    path.to_path_buf()
+                };
+                let mut cbz = Cbz::new(std::io::BufWriter::new(std::fs::File::create(&target)?));
+                for image in part.iter() {
+                    let name = image
+                        .strip_prefix(staging)
+                        .unwrap_or(image)
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    cbz.add_file(&name, &std::fs::read(image)?)?;
+                    pb.inc(1);
+                }
+                cbz.finish()?;
+            }
+            close_bar(pb, " ● Writing Complete! ");
+            Ok(())
+        })
+    }
+
+    // Every image on one png with a json file next to it saying where each one is. Images are
+    // copied over as they are with no scaling
+    fn write_sprite_sheet(&self, documents: &[Document], path: &Path) -> Result<(), PDFConError> {
+        self.extract_combined(documents, path, |staging, written| {
+            let pb = bar("Reading Images", written.len() as u64, tick_speed());
+            let mut images = Vec::with_capacity(written.len());
+            for image in written {
+                let name = image
+                    .strip_prefix(staging)
+                    .unwrap_or(image)
+                    .to_string_lossy()
+                    .to_string();
+                images.push((name, open_oriented(image)?));
+                pb.inc(1);
+            }
+            close_bar(pb, " ● Reading Complete! ");
+
+            // Square-ish unless asked otherwise
+            let columns = self
+                .sprite_columns
+                .unwrap_or_else(|| (images.len() as f64).sqrt().ceil() as usize);
+            let (sheet, sprites) = sprite::build_sheet(&images, columns)?;
+            sheet.save_with_format(path, image::ImageFormat::Png)?;
+
+            let json = sprite::layout_json(
+                &path.file_name().unwrap_or_default().to_string_lossy(),
+                sheet.width(),
+                sheet.height(),
+                &sprites,
+            );
+            std::fs::write(path.with_extension("json"), json)?;
+            Ok(())
+        })
+    }
+}
+
+impl Run for Unpack {
+    fn run(&self) -> Result<(), PDFConError> {
+        // Every output lands under out_directory or the sprite sheet so switching those to
+        // extended length paths covers everything written on Windows. - is stdout, not a path
+        let out_directory = if self.out_directory == Path::new("-") {
+            self.out_directory.clone()
+        } else {
+            long_path::extended(&self.out_directory)
+        };
+        let sprite_sheet = self.sprite_sheet.as_deref().map(long_path::extended);
+        if out_directory != self.out_directory || sprite_sheet != self.sprite_sheet {
+            return Unpack {
+                out_directory,
+                sprite_sheet,
+                ..self.clone()
+            }
+            .run();
+        }
+
+        let target = OutputTarget::infer(&self.out_directory)?;
+        if let OutputTarget::Directory(directory) = &target {
+            check_directory(directory)?;
+        }
+        self.check_native(&target)?;
+        if self.cbz_chunk.is_some() && !matches!(target, OutputTarget::Archive(_)) {
+            return Err(PDFConError::OutputFormatError(format!(
+                "--cbz-chunk splits a .cbz but {} isn't one",
+                self.out_directory.to_string_lossy()
+            )));
+        }
+
+        // Tiffs, cbzs and sprite sheets hold every image in one file and stdout isn't a file at all
+        if self.exec.is_some()
+            && (self.sprite_sheet.is_some()
+                || matches!(
+                    target,
+                    OutputTarget::Stdout | OutputTarget::Tiff(_) | OutputTarget::Archive(_)
+                ))
+        {
+            return Err(PDFConError::OutputFormatError(format!(
+                "--exec runs on every written image but {} isn't written as separate images",
+                self.sprite_sheet
+                    .as_deref()
+                    .unwrap_or(&self.out_directory)
+                    .to_string_lossy()
+            )));
+        }
+
+        if let Some(nice) = self.nice {
+            priority::lower_priority(nice);
+        }
+        io_limit::set_limit(self.io_threads.unwrap_or(self.threads));
+        if self.profile {
+            profile::enable();
+        }
+        let result = self.install(|| self.write_target(target));
+        profile::report();
+        result
+    }
+}
+
+impl Unpack {
+    // The global pool can only be built once. A pool of our own lets run be called again and
+    // leaves a host that already uses rayon alone. It's built even for one thread since lopdf
+    // parses with rayon and would otherwise spin up the global pool with a thread per core
+    pub(crate) fn install<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, PDFConError> + Send,
+    ) -> Result<T, PDFConError>
+    where
+        T: Send,
+    {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()?
+            .install(f)
+    }
+
+    // Flags that write their own files into the output directory. Every target but a directory
+    // stages the images in one that's removed afterwards so those files would be lost
+    fn sidecar_flags(&self) -> Vec<&'static str> {
+        [
+            (self.svg, "--svg"),
+            (self.dump_dict, "--dump-dict"),
+            (self.dump_raw, "--dump-raw"),
+            (self.extract_text, "--extract-text"),
+            (self.icc_profiles, "--icc-profiles"),
+            (self.attachments, "--attachments"),
+        ]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .collect()
+    }
+
+    fn write_target(&self, target: OutputTarget) -> Result<(), PDFConError> {
+        if let Some(sheet) = &self.sprite_sheet {
+            if sheet
+                .extension()
+                .is_none_or(|e| !e.eq_ignore_ascii_case("png"))
+            {
+                return Err(PDFConError::OutputFormatError(format!(
+                    "sprite sheets are pngs. {} has to end in .png",
+                    sheet.to_string_lossy()
+                )));
+            }
+            let documents = self.load_documents()?;
+            return self.write_sprite_sheet(&documents, sheet);
+        }
+
+        let sidecars = self.sidecar_flags();
+        match target {
+            OutputTarget::Directory(directory) => {
+                self.check_clobber(&directory)?;
+                std::fs::create_dir_all(directory)?;
+                let documents = self.load_documents()?;
+                let written = self.extract_images(&documents)?;
+                if self.verify_output {
+                    self.verify_outputs(&written)?;
+                }
+                self.run_exec(&written)?;
+                if self.attachments {
+                    self.write_attachments(&documents)?;
+                }
+                if self.icc_profiles {
+                    self.write_icc_profiles(&documents)?;
+                }
+            }
+            OutputTarget::Image(..)
+            | OutputTarget::Stdout
+            | OutputTarget::Tiff(_)
+            | OutputTarget::Archive(_)
+                if !sidecars.is_empty() =>
+            {
+                return Err(PDFConError::OutputFormatError(format!(
+                    "{} write files next to the images. {} has to be a directory",
+                    sidecars.join(", "),
+                    self.out_directory.to_string_lossy()
+                )));
+            }
+            OutputTarget::Image(path, extension) => {
+                // Stage next to the target so the final rename stays on one filesystem
+                let parent = match path.parent() {
+                    Some(p) if !p.as_os_str().is_empty() => p,
+                    _ => Path::new("."),
+                };
+                let staging = parent.join(format!(".pdfcon-{}", std::process::id()));
+                let documents = self.load_documents()?;
+                self.extract_single_image(
+                    &documents,
+                    &staging,
+                    &path.to_string_lossy(),
+                    |image| {
+                        if image.extension().is_none_or(|e| e != extension) {
+                            return Err(PDFConError::OutputFormatError(format!(
+                                "the image is a {} but {} asks for a {}",
+                                image.extension().unwrap_or_default().to_string_lossy(),
+                                path.to_string_lossy(),
+                                extension
+                            )));
+                        }
+                        std::fs::rename(image, &path)?;
+                        Ok(())
+                    },
+                )?;
+                if self.verify_output {
+                    self.verify_outputs(std::slice::from_ref(&path))?;
+                }
+                self.run_exec(&[path])?;
+            }
+            OutputTarget::Stdout => {
+                let staging =
+                    std::env::temp_dir().join(format!("pdfcon-stdout-{}", std::process::id()));
+                let documents = self.load_documents()?;
+                self.extract_single_image(&documents, &staging, "stdout", |image| {
+                    if self.verify_output {
+                        self.verify_outputs(&[image.to_path_buf()])?;
+                    }
+                    let mut stdout = std::io::stdout().lock();
+                    std::io::copy(&mut std::fs::File::open(image)?, &mut stdout)?;
+                    stdout.flush()?;
+                    Ok(())
+                })?;
+            }
+            OutputTarget::Tiff(path) => {
+                let documents = self.load_documents()?;
+                self.write_tiff(&documents, &path)?;
+            }
+            OutputTarget::Archive(path) => {
+                let documents = self.load_documents()?;
+                self.write_cbz(&documents, &path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Extract every image of a pdf that's already in memory without touching the disk. Options only
+// files make sense for, like svg, dump_raw, group_by_type, checkpoint and skip_similar, are
+// ignored along with the paths. Images come back in page order and every image on a page is kept
+pub fn extract_images_from_bytes(
+    data: &[u8],
+    opts: &Unpack,
+) -> Result<Vec<ExtractedImage>, PDFConError> {
+    let unpack = Unpack {
+        svg: false,
+        extract_text: false,
+        dump_raw: false,
+        dump_dict: false,
+        group_by_type: false,
+        checkpoint: None,
+        skip_similar: None,
+        collate: false,
+        ..opts.clone()
+    };
+
+    let loaded = Reader {
+        buffer: data,
+        document: Document::new(),
+    }
+    .read(Some(unpack.load_filter()));
+    let broken = unpack.repair && !loaded.as_ref().is_ok_and(|d| d.catalog().is_ok());
+    let mut doc = match broken
+        .then(|| load_repaired(data, unpack.load_filter()))
+        .flatten()
+    {
+        Some(document) => document,
+        None => loaded?,
+    };
+    if unpack.tolerant {
+        report_recovery("pdf", &repair::recover(data, &mut doc));
+    }
+    unpack.drop_unlisted_types(&mut doc);
+
+    let total_pages = doc.get_pages().len();
+    let claimed = Mutex::new(BTreeMap::new());
+    let hashes = Mutex::new(HashMap::new());
+    let lossy = AtomicUsize::new(0);
+    let images = Mutex::new(Vec::new());
+    let top = unpack.top_images(std::slice::from_ref(&doc));
+    let ctx = PageContext {
+        source: "",
+        page_offset: 0,
+        total_pages: total_pages as u32,
+        padding_width: unpack.padding_width(total_pages),
+        claimed: &claimed,
+        collated: None,
+        lossy: &lossy,
+        checkpoint: None,
+        sink: Sink::Memory(&images),
+        selected: top.as_ref().and_then(|t| t.first()),
+        hashes: &hashes,
+    };
+    unpack.install(|| match unpack.object {
+        Some(id) => unpack.extract_object(&doc, &ctx, id),
+        None => unpack.extract_document(&doc, &ctx),
+    })?;
+
+    // Workers finish in any order. The sort is stable so a page keeps its images in order
+    let mut images = images.into_inner().unwrap_or_else(|e| e.into_inner());
+    images.sort_by_key(|image| image.page);
+    if let Some(max) = unpack.max_images {
+        images.truncate(max);
+    }
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    fn options(threads: usize) -> Unpack {
+        Unpack {
+            threads,
+            ..Default::default()
+        }
+    }
+
+    const PIXELS: [u8; 12] = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+
+    // One page drawing a 2x2 rgb image. The catalog, page tree and page sit in an object stream
+    // that only a cross reference stream points into
+    fn object_stream_pdf() -> Vec<u8> {
+        let compressed: [&[u8]; 3] = [
+            b"<< /Type /Catalog /Pages 2 0 R >>",
+            b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>",
+            b"<< /Type /Page /Parent 2 0 R /MediaBox [0 0 2 2] \
+              /Resources << /XObject << /Im0 4 0 R >> >> /Contents 5 0 R >>",
+        ];
+        let mut header = String::new();
+        let mut body = Vec::new();
+        for (index, object) in compressed.iter().enumerate() {
+            header.push_str(&format!("{} {} ", index + 1, body.len()));
+            body.extend_from_slice(object);
+            body.push(b' ');
+        }
+        let mut object_stream = header.clone().into_bytes();
+        object_stream.extend(body);
+
+        let content = b"q 2 0 0 2 0 0 cm /Im0 Do Q";
+        let mut pdf = b"%PDF-1.5\n".to_vec();
+        let mut offsets = Vec::new();
+        let mut object = |pdf: &mut Vec<u8>, dict: String, data: &[u8]| {
+            offsets.push(pdf.len());
+            pdf.extend(format!("{} 0 obj\n{}\nstream\n", offsets.len() + 3, dict).into_bytes());
+            pdf.extend_from_slice(data);
+            pdf.extend(b"\nendstream\nendobj\n");
+        };
+        object(
+            &mut pdf,
+            format!(
+                "<< /Type /XObject /Subtype /Image /Width 2 /Height 2 /ColorSpace /DeviceRGB \
+                 /BitsPerComponent 8 /Length {} >>",
+                PIXELS.len()
+            ),
+            &PIXELS,
+        );
+        object(
+            &mut pdf,
+            format!("<< /Length {} >>", content.len()),
+            content,
+        );
+        object(
+            &mut pdf,
+            format!(
+                "<< /Type /ObjStm /N 3 /First {} /Length {} >>",
+                header.len(),
+                object_stream.len()
+            ),
+            &object_stream,
+        );
+
+        // Type 2 entries point into object 6, the object stream
+        let xref_at = pdf.len();
+        let mut entries = vec![(0u8, 0u32, 65535u16)];
+        entries.extend((0..3).map(|index| (2, 6, index)));
+        entries.extend(offsets.iter().map(|&offset| (1, offset as u32, 0)));
+        entries.push((1, xref_at as u32, 0));
+        let xref: Vec<u8> = entries
+            .iter()
+            .flat_map(|(kind, field, index)| {
+                [*kind]
+                    .into_iter()
+                    .chain(field.to_be_bytes())
+                    .chain(index.to_be_bytes())
+            })
+            .collect();
+        pdf.extend(
+            format!(
+                "7 0 obj\n<< /Type /XRef /Size 8 /W [1 4 2] /Root 1 0 R /Length {} >>\nstream\n",
+                xref.len()
+            )
+            .into_bytes(),
+        );
+        pdf.extend(xref);
+        pdf.extend(format!("\nendstream\nendobj\nstartxref\n{}\n%%EOF\n", xref_at).into_bytes());
+        pdf
+    }
+
+    // Pages each drawing a few 4x4 gray images of their own shade
+    fn many_images_pdf(pages: u8, per_page: u8) -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let mut kids = Vec::new();
+        for page in 0..pages {
+            let mut xobjects = Dictionary::new();
+            for index in 0..per_page {
+                let image = Stream::new(
+                    dictionary! {
+                        "Type" => "XObject",
+                        "Subtype" => "Image",
+                        "Width" => 4,
+                        "Height" => 4,
+                        "ColorSpace" => "DeviceGray",
+                        "BitsPerComponent" => 8,
+                    },
+                    vec![page * per_page + index; 16],
+                );
+                xobjects.set(format!("Im{}", index), doc.add_object(image));
+            }
+            let content = doc.add_object(Stream::new(Dictionary::new(), Vec::new()));
+            kids.push(Object::Reference(doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "Resources" => dictionary! { "XObject" => xobjects },
+                "Contents" => content,
+            })));
+        }
+        let count = kids.len() as i64;
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => count,
+            }),
+        );
+        let catalog = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog);
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn output_is_the_same_for_any_thread_count() {
+        let pdf = many_images_pdf(12, 3);
+        let run = |threads: usize| {
+            let opts = Unpack {
+                max_images: Some(20),
+                ..options(threads)
+            };
+            extract_images_from_bytes(&pdf, &opts)
+                .unwrap()
+                .into_iter()
+                .map(|i| (i.page, i.object, i.format, i.data))
+                .collect::<Vec<_>>()
+        };
+        let serial = run(1);
+        assert_eq!(serial.len(), 20);
+        for threads in [2, 4, 8] {
+            assert_eq!(run(threads), serial);
+        }
+    }
+
+    #[test]
+    fn exec_templates_split_on_unquoted_whitespace() {
+        assert_eq!(
+            split_command(r#"convert {path} -resize "50%" 'my dir/{path}' a"b c"d"#).unwrap(),
+            [
+                "convert",
+                "{path}",
+                "-resize",
+                "50%",
+                "my dir/{path}",
+                "ab cd"
+            ]
+        );
+        assert_eq!(
+            split_command(r#"echo "say \"hi\"" '' "#).unwrap(),
+            ["echo", r#"say "hi""#, ""]
+        );
+        assert!(split_command("echo 'open").is_err());
+    }
+
+    #[test]
+    fn images_extract_from_object_streams() {
+        let images = extract_images_from_bytes(&object_stream_pdf(), &options(1)).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].page, 1);
+        assert_eq!(images[0].object, (4, 0));
+        let decoded = image::load_from_memory(&images[0].data).unwrap().to_rgb8();
+        assert_eq!(decoded.into_raw(), PIXELS);
+    }
 }